@@ -0,0 +1,41 @@
+/* Inserts a stream of new keys, reading each one back right after it is inserted.
+ *
+ * Every read of a new key misses the read-only map, so this is the workload where promotions of
+ * the dirty map are most frequent, and where each promotion is followed by copying the read-only
+ * map back into a new dirty map on the next insert.
+ *
+ * Run with `cargo bench --bench monotonic`.
+ */
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use syncmap::map::Map;
+
+const SIZES: [u64; 3] = [10_000, 50_000, 200_000];
+
+fn task_insert_then_get(size: u64) -> Map<u64, u64> {
+    let map = Map::new();
+    let guard = map.guard();
+    for i in 0..size {
+        map.insert(i, i + 7, &guard);
+        assert_eq!(map.get(&i, &guard), Some(&(i + 7)));
+        assert_eq!(map.get(&(i / 2), &guard), Some(&(i / 2 + 7)));
+    }
+    drop(guard);
+    map
+}
+
+fn insert_then_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("monotonic_insert_then_get");
+    group.sample_size(10);
+    for size in SIZES {
+        group.throughput(Throughput::Elements(size));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| task_insert_then_get(size));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert_then_get);
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+/* Compares `retain` with `par_retain` for an expensive predicate.
+ *
+ * The predicate hashes every value a number of times to stand in for real eviction checks
+ * (TTLs, lookups in external state), which is where evaluating it in parallel pays off.
+ *
+ * Run with `cargo bench --bench retain --features rayon`.
+ */
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use syncmap::map::Map;
+
+const SIZES: [u64; 2] = [10_000, 100_000];
+const ROUNDS: u64 = 64;
+
+fn expensive(k: &u64, v: &u64) -> bool {
+    let mut h = DefaultHasher::new();
+    for round in 0..ROUNDS {
+        (k, v, round).hash(&mut h);
+    }
+    h.finish() & 1 == 0
+}
+
+fn populated(size: u64) -> Map<u64, u64> {
+    let map = Map::new();
+    let guard = map.guard();
+    for i in 0..size {
+        map.insert(i, i + 7, &guard);
+    }
+    drop(guard);
+    map
+}
+
+fn retain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("retain");
+    for size in SIZES {
+        group.throughput(Throughput::Elements(size));
+        group.bench_with_input(BenchmarkId::new("serial", size), &size, |b, &size| {
+            b.iter_batched(
+                || populated(size),
+                |map| {
+                    let guard = map.guard();
+                    map.retain(expensive, &guard)
+                },
+                BatchSize::LargeInput,
+            );
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", size), &size, |b, &size| {
+            b.iter_batched(
+                || populated(size),
+                |map| {
+                    let guard = map.guard();
+                    map.par_retain(expensive, &guard)
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, retain);
+criterion_main!(benches);
@@ -16,7 +16,6 @@
 
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 
-use rayon;
 use rayon::prelude::*;
 use std::sync::Arc;
 use syncmap::map::{Map};
@@ -35,9 +34,11 @@ fn task_insert_syncmap_u64_u64_guard_every_it() -> Map<u64, u64> {
     map
 }
 
+// not registered in `benches` below
+#[allow(dead_code)]
 fn insert_syncmap_u64_u64_guard_every_it(c: &mut Criterion) {
     let mut group = c.benchmark_group("insert_syncmap_u64_u64_guard_every_it");
-    group.throughput(Throughput::Elements(ITER as u64));
+    group.throughput(Throughput::Elements(ITER));
     let max = 2;
 
     for threads in 1..max {
@@ -57,8 +58,9 @@ fn insert_syncmap_u64_u64_guard_every_it(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(dead_code)]
 fn task_insert_syncmap_u64_u64_guard_once(threads: usize) -> Map<u64, u64> {
-    let mut map = Arc::new(Map::new());
+    let map = Arc::new(Map::new());
     let inc = ITER / (threads as u64);
 
     rayon::scope(|s| {
@@ -76,9 +78,11 @@ fn task_insert_syncmap_u64_u64_guard_once(threads: usize) -> Map<u64, u64> {
     Arc::try_unwrap(map).unwrap()
 }
 
+// not registered in `benches` below
+#[allow(dead_code)]
 fn insert_syncmap_u64_u64_guard_once(c: &mut Criterion) {
     let mut group = c.benchmark_group("insert_syncmap_u64_u64_guard_once");
-    group.throughput(Throughput::Elements(ITER as u64));
+    group.throughput(Throughput::Elements(ITER));
     let max = 2;
 
     for threads in 1..=max {
@@ -110,7 +114,7 @@ fn task_get_syncmap_u64_u64_guard_every_it(map: &Map<u64, u64>) {
 
 fn get_syncmap_u64_u64_guard_every_it(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_syncmap_u64_u64_guard_every_it");
-    group.throughput(Throughput::Elements(ITER as u64));
+    group.throughput(Throughput::Elements(ITER));
     let max = 6;
     for threads in 1..=max {
         let map = task_insert_syncmap_u64_u64_guard_every_it();
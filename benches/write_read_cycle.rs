@@ -0,0 +1,54 @@
+/* Alternates between writing a batch of new keys and reading them back, the workload that
+ * `MapBuilder::keep_dirty_allocation` targets.
+ *
+ * Every write phase starts by copying the read-only map into a dirty map, and every read phase
+ * ends with a promotion of that dirty map. By default the dirty map is dropped on promotion and
+ * allocated again by the next write phase; with `keep_dirty_allocation` it is emptied and reused.
+ *
+ * Run with `cargo bench --bench write_read_cycle`.
+ */
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use syncmap::map::Map;
+
+const CYCLES: u64 = 100;
+const SIZES: [u64; 3] = [100, 1_000, 10_000];
+
+fn task_write_read_cycles(map: Map<u64, u64>, batch: u64) -> Map<u64, u64> {
+    let guard = map.guard();
+    for cycle in 0..CYCLES {
+        let keys = cycle * batch..(cycle + 1) * batch;
+        for i in keys.clone() {
+            map.insert(i, i, &guard);
+        }
+        // reading the new keys back misses the read-only map until they are promoted
+        for _ in 0..2 {
+            for i in keys.clone() {
+                assert_eq!(map.get(&i, &guard), Some(&i));
+            }
+        }
+    }
+    drop(guard);
+    map
+}
+
+fn write_read_cycles(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_read_cycles");
+    group.sample_size(10);
+    for batch in SIZES {
+        group.throughput(Throughput::Elements(CYCLES * batch));
+        group.bench_with_input(BenchmarkId::new("drop_dirty", batch), &batch, |b, &batch| {
+            b.iter(|| task_write_read_cycles(Map::new(), batch));
+        });
+        group.bench_with_input(BenchmarkId::new("keep_dirty", batch), &batch, |b, &batch| {
+            b.iter(|| {
+                task_write_read_cycles(Map::builder().keep_dirty_allocation(true).build(), batch)
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write_read_cycles);
+criterion_main!(benches);
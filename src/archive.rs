@@ -0,0 +1,152 @@
+//! Zero-copy export of a map's contents with [`rkyv`].
+//!
+//! [`Map::to_archive_bytes`] archives the live key-value pairs of a map, and [`FrozenMap`] is a
+//! read-only view directly over such an archive, for example one that was written to a file and
+//! memory-mapped back in. Lookups through a `FrozenMap` read the archived bytes in place, without
+//! deserializing anything, and hand out the archived forms of keys and values.
+//!
+//! The archive is an `rkyv`-archived [`std::collections::HashMap`], so it doesn't carry any of
+//! the map's concurrency machinery, and can also be produced or consumed without this crate.
+//!
+//! # Examples
+//!
+//! ```
+//! use syncmap::archive::FrozenMap;
+//! use syncmap::map::Map;
+//!
+//! let map = Map::new();
+//! let guard = map.guard();
+//! map.insert(String::from("a"), 1u32, &guard);
+//! map.insert(String::from("b"), 2u32, &guard);
+//! let bytes = map.to_archive_bytes(&guard).unwrap();
+//!
+//! let frozen = FrozenMap::<String, u32>::from_bytes(&bytes).unwrap();
+//! assert_eq!(frozen.get("a"), Some(&1));
+//! assert_eq!(frozen.get("c"), None);
+//! assert_eq!(frozen.len(), 2);
+//! ```
+
+use std::borrow::Borrow;
+use std::collections::HashMap as StdHashMap;
+use std::hash::{BuildHasher, Hash};
+use rkyv::bytecheck::CheckBytes;
+use rkyv::collections::hash_map::ArchivedHashMap;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::validation::CheckTypeError;
+use rkyv::{AlignedVec, Archive, Archived, Fallible, Serialize};
+use seize::Guard;
+use crate::map::Map;
+
+/// The scratch space, in bytes, that [`Map::to_archive_bytes`] serializes with before falling
+/// back to the heap.
+pub const SCRATCH_SPACE: usize = 4096;
+
+/// The serializer used by [`Map::to_archive_bytes`].
+pub type MapSerializer = AllocSerializer<SCRATCH_SPACE>;
+
+/// A read-only view of a map archived with [`Map::to_archive_bytes`], borrowing the archive.
+///
+/// See the [module docs](self).
+pub struct FrozenMap<'a, K, V>
+    where
+        K: Archive,
+        V: Archive,
+{
+    map: &'a ArchivedHashMap<Archived<K>, Archived<V>>,
+}
+
+impl<K, V, S> Map<K, V, S>
+    where
+        K: Clone + Hash + Ord + Serialize<MapSerializer>,
+        Archived<K>: Hash + Eq,
+        V: Clone + Serialize<MapSerializer>,
+        S: BuildHasher,
+{
+    /// Archives the live key-value pairs of the map with `rkyv`.
+    ///
+    /// The pairs are those visited by [`iter`](Self::iter), copied into a
+    /// [`std::collections::HashMap`] which is then archived. Concurrent modifications may or may
+    /// not be reflected. The returned bytes can be viewed without deserializing through
+    /// [`FrozenMap`].
+    pub fn to_archive_bytes(
+        &self,
+        guard: &Guard<'_>,
+    ) -> Result<AlignedVec, <MapSerializer as Fallible>::Error> {
+        let entries: StdHashMap<K, V> = self
+            .iter(guard)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        rkyv::to_bytes::<_, SCRATCH_SPACE>(&entries)
+    }
+}
+
+impl<'a, K, V> FrozenMap<'a, K, V>
+    where
+        K: Archive + Hash + Eq,
+        Archived<K>: Hash + Eq,
+        V: Archive,
+{
+    /// Creates a view over `bytes`, after validating that they hold a well-formed archive.
+    ///
+    /// Validation walks the whole archive once, which is still much cheaper than deserializing
+    /// it, and is what makes it safe to use bytes from an untrusted or corrupted source.
+    pub fn from_bytes(
+        bytes: &'a [u8],
+    ) -> Result<Self, CheckTypeError<Archived<StdHashMap<K, V>>, DefaultValidator<'a>>>
+        where
+            Archived<StdHashMap<K, V>>: CheckBytes<DefaultValidator<'a>>,
+    {
+        let map = rkyv::check_archived_root::<StdHashMap<K, V>>(bytes)?;
+        Ok(Self { map })
+    }
+
+    /// Creates a view over `bytes` without validating them.
+    ///
+    /// # Safety
+    ///
+    /// `bytes` must hold an archive produced by [`Map::to_archive_bytes`] for the same `K` and
+    /// `V` (or any other `rkyv` archive of a `std::collections::HashMap<K, V>`), and be aligned
+    /// as it was when produced.
+    pub unsafe fn from_bytes_unchecked(bytes: &'a [u8]) -> Self {
+        Self {
+            map: rkyv::archived_root::<StdHashMap<K, V>>(bytes),
+        }
+    }
+
+    /// Returns the archived value corresponding to the key.
+    ///
+    /// The key may be any borrowed form of the *archived* key type, e.g. `&str` for archived
+    /// `String` keys.
+    pub fn get<Q>(&self, key: &Q) -> Option<&'a Archived<V>>
+        where
+            Archived<K>: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+    {
+        self.map.get(key)
+    }
+
+    /// Returns `true` if the archive contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where
+            Archived<K>: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+    {
+        self.map.contains_key(key)
+    }
+
+    /// Returns the number of entries in the archive.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the archive holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// An iterator visiting all archived key-value pairs in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a Archived<K>, &'a Archived<V>)> {
+        self.map.iter()
+    }
+}
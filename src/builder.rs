@@ -0,0 +1,265 @@
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicU64;
+use seize::Collector;
+use crate::map::{Map, MissHook, DEFAULT_COMPACTION_THRESHOLD};
+#[cfg(feature = "trace")]
+use crate::trace::{TraceEvent, TraceHook};
+
+/// A builder for configuring a [`Map`] before it is created.
+///
+/// # Examples
+///
+/// ```
+/// use syncmap::DefaultHashBuilder;
+/// use syncmap::map::Map;
+///
+/// let map: Map<u64, u64> = Map::builder()
+///     .hasher(DefaultHashBuilder::default())
+///     .build();
+/// let guard = map.guard();
+/// map.insert(1, 1, &guard);
+/// ```
+pub struct MapBuilder<K, V, S = crate::DefaultHashBuilder> {
+    hasher: S,
+    batch_size: Option<usize>,
+    compaction_threshold: f64,
+    track_insertion_order: bool,
+    keep_dirty_allocation: bool,
+    on_miss: Option<MissHook<K>>,
+    #[cfg(feature = "metrics")]
+    label: Option<String>,
+    #[cfg(feature = "trace")]
+    trace_hook: Option<TraceHook>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> MapBuilder<K, V, crate::DefaultHashBuilder> {
+    /// Creates a builder for a map with the default hasher and settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<K, V, S> Default for MapBuilder<K, V, S>
+    where
+        S: Default,
+{
+    fn default() -> Self {
+        Self {
+            hasher: S::default(),
+            batch_size: None,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            track_insertion_order: false,
+            keep_dirty_allocation: false,
+            on_miss: None,
+            #[cfg(feature = "metrics")]
+            label: None,
+            #[cfg(feature = "trace")]
+            trace_hook: None,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, S> MapBuilder<K, V, S> {
+    /// Sets the hasher used by the map.
+    ///
+    /// See [`Map::with_hasher`] for the caveats of supplying a hasher manually.
+    pub fn hasher<S2>(self, hasher: S2) -> MapBuilder<K, V, S2> {
+        MapBuilder {
+            hasher,
+            batch_size: self.batch_size,
+            compaction_threshold: self.compaction_threshold,
+            track_insertion_order: self.track_insertion_order,
+            keep_dirty_allocation: self.keep_dirty_allocation,
+            on_miss: self.on_miss,
+            #[cfg(feature = "metrics")]
+            label: self.label,
+            #[cfg(feature = "trace")]
+            trace_hook: self.trace_hook,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets how many retired allocations (replaced values, old tables, dropped entries) a thread
+    /// accumulates before it tries to reclaim them.
+    ///
+    /// Larger batches amortize the cost of reclamation and raise throughput for write-heavy
+    /// workloads, at the price of keeping more garbage alive for longer. Smaller batches
+    /// reclaim memory more eagerly, which suits memory-constrained services. If this is not set,
+    /// the default batch size of the `seize` collector is used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<u64, u64> = Map::builder().batch_size(8).build();
+    /// ```
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    /// Sets how many removed entries per live entry the read-only map may hold before
+    /// [`Map::maybe_compact`] rebuilds it. Defaults to `1.0`, i.e. compaction kicks in once the
+    /// removed entries outnumber the live ones.
+    ///
+    /// Lower values keep the map tighter at the cost of rebuilding it more often.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<u64, u64> = Map::builder().compaction_threshold(0.25).build();
+    /// ```
+    pub fn compaction_threshold(mut self, threshold: f64) -> Self {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// Sets whether the map records the order in which keys are inserted, which
+    /// [`Map::evict_oldest`] needs. Defaults to `false`.
+    ///
+    /// With tracking enabled, every insertion of a new key, or of a key that was removed, takes
+    /// one more atomic increment. Replacing the value of a key that is present doesn't change
+    /// its position in the order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<u64, u64> = Map::builder().track_insertion_order(true).build();
+    /// ```
+    pub fn track_insertion_order(mut self, track: bool) -> Self {
+        self.track_insertion_order = track;
+        self
+    }
+
+    /// Sets whether the dirty map keeps its allocation across promotions.
+    ///
+    /// By default, promoting the dirty map drops it, and the next write of a new key allocates a
+    /// fresh one. With this set, the promoted keys are moved out of the dirty map instead, and the
+    /// emptied map is kept to be refilled by the next write phase. Workloads that alternate
+    /// between writing and reading a stable set of keys then don't reallocate the dirty map for
+    /// every cycle, at the price of holding on to its memory while only reading.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<u64, u64> = Map::builder().keep_dirty_allocation(true).build();
+    /// let guard = map.guard();
+    /// map.insert(1, 1, &guard);
+    /// map.iter(&guard).count(); // promote
+    /// map.insert(2, 2, &guard);
+    /// assert_eq!(map.len(), 2);
+    /// ```
+    pub fn keep_dirty_allocation(mut self, keep: bool) -> Self {
+        self.keep_dirty_allocation = keep;
+        self
+    }
+
+    /// Sets a callback that is called with the key whenever
+    /// [`Map::get_or_insert_with`] is about to run its closure to fill in a missing value.
+    ///
+    /// Calls that find the key present, whether on the lock-free path or after waiting for a
+    /// concurrent fill of the same key, don't invoke the callback, so it counts genuine fills
+    /// only. It runs on the filling thread, right before the closure, without holding the map's
+    /// lock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    /// use syncmap::map::Map;
+    ///
+    /// let fills = Arc::new(AtomicUsize::new(0));
+    /// let counter = fills.clone();
+    /// let map: Map<u64, u64> = Map::builder()
+    ///     .on_miss(move |_key| {
+    ///         counter.fetch_add(1, Ordering::Relaxed);
+    ///     })
+    ///     .build();
+    /// let guard = map.guard();
+    /// map.get_or_insert_with(1, || 1, &guard);
+    /// map.get_or_insert_with(1, || 2, &guard);
+    /// assert_eq!(fills.load(Ordering::Relaxed), 1);
+    /// ```
+    pub fn on_miss<F>(mut self, on_miss: F) -> Self
+        where
+            F: Fn(&K) + Send + Sync + 'static,
+    {
+        self.on_miss = Some(Box::new(on_miss));
+        self
+    }
+
+    /// Sets the value of the `map` label attached to every metric this map emits, so that
+    /// several maps can be told apart. Defaults to `"default"`.
+    #[cfg(feature = "metrics")]
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Sets a hook that is called with a [`TraceEvent`] whenever the map enters or leaves a locked
+    /// slow path, or promotes its dirty map. See the [`trace`](crate::trace) module.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    /// use syncmap::map::Map;
+    /// use syncmap::trace::{Operation, TraceEvent};
+    ///
+    /// let slow_puts = Arc::new(AtomicUsize::new(0));
+    /// let counter = slow_puts.clone();
+    /// let map: Map<u64, u64> = Map::builder()
+    ///     .trace_hook(move |event| {
+    ///         if event == TraceEvent::LockAcquired(Operation::Put) {
+    ///             counter.fetch_add(1, Ordering::Relaxed);
+    ///         }
+    ///     })
+    ///     .build();
+    /// let guard = map.guard();
+    /// map.insert(1, 1, &guard);
+    /// assert_eq!(slow_puts.load(Ordering::Relaxed), 1);
+    /// ```
+    #[cfg(feature = "trace")]
+    pub fn trace_hook<F>(mut self, hook: F) -> Self
+        where
+            F: Fn(TraceEvent) + Send + Sync + 'static,
+    {
+        self.trace_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Creates the configured map.
+    pub fn build(self) -> Map<K, V, S> {
+        let collector = match self.batch_size {
+            Some(batch_size) => Collector::new().batch_size(batch_size),
+            None => Collector::new(),
+        };
+        let mut map = Map::with_collector(self.hasher, collector);
+        map.compaction_threshold = self.compaction_threshold;
+        if self.track_insertion_order {
+            map.next_seq = Some(AtomicU64::new(0));
+        }
+        map.keep_dirty_allocation = self.keep_dirty_allocation;
+        map.on_miss = self.on_miss;
+        #[cfg(feature = "metrics")]
+        if let Some(label) = self.label {
+            map.metric_keys = crate::map::MetricKeys::new(label);
+        }
+        #[cfg(feature = "trace")]
+        {
+            map.trace_hook = self.trace_hook;
+        }
+        map
+    }
+}
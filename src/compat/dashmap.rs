@@ -0,0 +1,294 @@
+//! A [`dashmap`](https://docs.rs/dashmap)-like interface.
+//!
+//! [`DashMap`] manages guards internally: every call pins its own guard, and references into
+//! the map ([`Ref`] and [`RefMulti`]) carry the guard that keeps them valid. Because values are
+//! never modified in place, there is no equivalent of `get_mut`, and methods that hand back
+//! owned values require `V: Clone`.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Deref;
+use std::rc::Rc;
+use crate::iter;
+use crate::map::Map;
+use crate::reclaim::Guard;
+
+/// A concurrent map with a `dashmap`-like API, backed by a [`Map`].
+///
+/// # Examples
+///
+/// ```
+/// use syncmap::compat::dashmap::DashMap;
+///
+/// let map = DashMap::new();
+/// assert_eq!(map.insert("a", 1), None);
+/// assert_eq!(map.insert("a", 2), Some(1));
+/// assert_eq!(*map.get("a").unwrap(), 2);
+/// assert_eq!(map.remove("a"), Some(("a", 2)));
+/// assert!(map.is_empty());
+/// ```
+pub struct DashMap<K, V, S = crate::DefaultHashBuilder> {
+    map: Map<K, V, S>,
+}
+
+impl<K, V> DashMap<K, V, crate::DefaultHashBuilder> {
+    /// Creates an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty map with room for at least `capacity` entries.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            map: Map::with_capacity(capacity),
+        }
+    }
+}
+
+impl<K, V, S> DashMap<K, V, S> {
+    /// Creates an empty map which will use `hasher` to hash keys.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self {
+            map: Map::with_hasher(hasher),
+        }
+    }
+
+    /// Returns the underlying [`Map`].
+    pub fn as_map(&self) -> &Map<K, V, S> {
+        &self.map
+    }
+
+    /// Consumes the wrapper and returns the underlying [`Map`].
+    pub fn into_map(self) -> Map<K, V, S> {
+        self.map
+    }
+}
+
+impl<K, V, S> Default for DashMap<K, V, S>
+    where
+        S: Default,
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> From<Map<K, V, S>> for DashMap<K, V, S> {
+    fn from(map: Map<K, V, S>) -> Self {
+        Self { map }
+    }
+}
+
+impl<K, V, S> fmt::Debug for DashMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DashMap").finish_non_exhaustive()
+    }
+}
+
+impl<K, V, S> DashMap<K, V, S>
+    where
+        K: Clone + Hash + Ord,
+        S: BuildHasher,
+{
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the map contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns `true` if the map contains a value for `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.map.contains_key(key, &self.map.guard())
+    }
+
+    /// Returns a reference to the entry for `key`, if there is one.
+    ///
+    /// The returned [`Ref`] holds a guard, which keeps the value alive (and holds back the
+    /// reclamation of the map's garbage) until it is dropped.
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V>>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let guard = self.map.guard();
+        // safety: the references stay valid for as long as `guard` is alive, and `Ref` keeps
+        // it alive for as long as it hands them out.
+        let guard_ref = unsafe { &*(&guard as *const Guard<'_>) };
+        let (key, value) = self.map.get_key_value(key, guard_ref)?;
+        Some(Ref {
+            key,
+            value,
+            _guard: guard,
+        })
+    }
+
+    /// Returns an iterator over the entries of the map, in arbitrary order.
+    ///
+    /// Every [`RefMulti`] shares the iterator's guard, which stays pinned until the iterator
+    /// and all the references it produced have been dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::compat::dashmap::DashMap;
+    ///
+    /// let map = DashMap::new();
+    /// map.insert(1, 10);
+    /// map.insert(2, 20);
+    /// let mut pairs: Vec<_> = map.iter().map(|r| (*r.key(), *r.value())).collect();
+    /// pairs.sort();
+    /// assert_eq!(pairs, vec![(1, 10), (2, 20)]);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let guard = Rc::new(self.map.guard());
+        // safety: the `Rc` keeps the guard at a stable address for as long as `Iter` or any
+        // `RefMulti` holds on to it, and `inner` is dropped before `guard`.
+        let guard_ref = unsafe { &*Rc::as_ptr(&guard) };
+        Iter {
+            inner: self.map.iter(guard_ref),
+            guard,
+        }
+    }
+}
+
+impl<K, V, S> DashMap<K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send + Clone,
+        S: BuildHasher,
+{
+    /// Inserts a key-value pair, and returns the value that was previously stored for the key.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let guard = self.map.guard();
+        self.map.insert_returning_old(key, value, &guard).cloned()
+    }
+
+    /// Removes `key` from the map, and returns the stored key and value if it was present.
+    pub fn remove<Q>(&self, key: &Q) -> Option<(K, V)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let guard = self.map.guard();
+        let (stored, value) = self.map.remove_with(key, K::clone, &guard)?;
+        Some((stored, value.clone()))
+    }
+
+    /// Retains only the entries for which `f` returns `true`.
+    pub fn retain<F>(&self, mut f: F)
+        where
+            F: FnMut(&K, &V) -> bool,
+    {
+        self.map.retain(|k, v| f(k, v), &self.map.guard());
+    }
+
+    /// Removes all entries from the map.
+    pub fn clear(&self) {
+        self.map.clear();
+    }
+}
+
+/// A reference to an entry of a [`DashMap`], as returned by [`DashMap::get`].
+pub struct Ref<'a, K, V> {
+    key: &'a K,
+    value: &'a V,
+    _guard: Guard<'a>,
+}
+
+impl<K, V> Ref<'_, K, V> {
+    /// Returns the entry's key.
+    pub fn key(&self) -> &K {
+        self.key
+    }
+
+    /// Returns the entry's value.
+    pub fn value(&self) -> &V {
+        self.value
+    }
+
+    /// Returns the entry's key and value.
+    pub fn pair(&self) -> (&K, &V) {
+        (self.key, self.value)
+    }
+}
+
+impl<K, V> Deref for Ref<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for Ref<'_, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ref").field("key", self.key).field("value", self.value).finish()
+    }
+}
+
+/// An iterator over the entries of a [`DashMap`], as returned by [`DashMap::iter`].
+pub struct Iter<'a, K, V> {
+    // borrows from `guard`, so it must be declared (and dropped) first
+    inner: iter::Iter<'a, K, V>,
+    guard: Rc<Guard<'a>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = RefMulti<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.inner.next()?;
+        Some(RefMulti {
+            key,
+            value,
+            _guard: self.guard.clone(),
+        })
+    }
+}
+
+/// A reference to an entry of a [`DashMap`], as yielded by [`DashMap::iter`].
+pub struct RefMulti<'a, K, V> {
+    key: &'a K,
+    value: &'a V,
+    _guard: Rc<Guard<'a>>,
+}
+
+impl<K, V> RefMulti<'_, K, V> {
+    /// Returns the entry's key.
+    pub fn key(&self) -> &K {
+        self.key
+    }
+
+    /// Returns the entry's value.
+    pub fn value(&self) -> &V {
+        self.value
+    }
+
+    /// Returns the entry's key and value.
+    pub fn pair(&self) -> (&K, &V) {
+        (self.key, self.value)
+    }
+}
+
+impl<K, V> Deref for RefMulti<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for RefMulti<'_, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefMulti").field("key", self.key).field("value", self.value).finish()
+    }
+}
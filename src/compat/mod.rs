@@ -0,0 +1,7 @@
+//! Wrappers that mimic the API of other concurrent maps on top of [`Map`](crate::map::Map).
+//!
+//! These make it easier to try `syncmap` in code that was written against another crate, and
+//! to A/B test the two with a minimal diff. New code should use [`Map`](crate::map::Map)
+//! directly, which avoids pinning a guard for every single operation.
+
+pub mod dashmap;
@@ -0,0 +1,46 @@
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Primitive integer types, as used by the counter operations of [`Map`](crate::map::Map) such
+/// as [`fetch_add_saturating`](crate::map::Map::fetch_add_saturating).
+///
+/// This trait is sealed and implemented for all primitive integers.
+pub trait Counter: Copy + Default + sealed::Sealed {
+    #[doc(hidden)]
+    fn saturating_add(self, rhs: Self) -> Self;
+    #[doc(hidden)]
+    fn saturating_sub(self, rhs: Self) -> Self;
+    #[doc(hidden)]
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    #[doc(hidden)]
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+}
+
+macro_rules! impl_counter {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+
+            impl Counter for $t {
+                fn saturating_add(self, rhs: Self) -> Self {
+                    <$t>::saturating_add(self, rhs)
+                }
+
+                fn saturating_sub(self, rhs: Self) -> Self {
+                    <$t>::saturating_sub(self, rhs)
+                }
+
+                fn checked_add(self, rhs: Self) -> Option<Self> {
+                    <$t>::checked_add(self, rhs)
+                }
+
+                fn checked_sub(self, rhs: Self) -> Option<Self> {
+                    <$t>::checked_sub(self, rhs)
+                }
+            }
+        )*
+    };
+}
+
+impl_counter!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
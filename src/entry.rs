@@ -1,13 +1,62 @@
-use std::marker::PhantomData;
 use std::ptr;
-use std::sync::atomic::Ordering;
-use seize::{AtomicPtr, Guard};
-use crate::reclaim::{Atomic, Shared};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+use seize::{Collector, Guard};
+use crate::reclaim::{Atomic, Linked, RetireShared, Shared};
+
+/// A pointer to an entry, as stored in the read-only and dirty maps.
+///
+/// Entries are allocated through the map's collector so that they can be retired once they are
+/// no longer reachable from either map.
+pub(crate) type EntryPtr<V> = *mut Linked<Entry<V>>;
+
+/// The value pointer of an entry that has been removed and then left out of the dirty map.
+///
+/// The pointer is never dereferenced, it only has to be distinct from every real value. A
+/// dangling but well-aligned pointer is: it points into the first page of the address space,
+/// where no allocation ever lives.
+fn expunged<'g, V>() -> Shared<'g, V> {
+    Shared::from(NonNull::<Linked<V>>::dangling().as_ptr())
+}
+
+/// The low bit set on the value pointer of an entry whose tables have been dropped from the map.
+///
+/// The value stays readable by threads still holding the old tables, but the entry counts as
+/// expunged for anything that would change it, so those changes go through the lock and land in
+/// the current tables. Values are allocated with a header of pointers, so the bit is always free.
+const RETIRED: usize = 1;
+
+fn is_retired<V>(p: Shared<'_, V>) -> bool {
+    // safety: the pointer is only inspected, never dereferenced.
+    unsafe { p.as_ptr() as usize & RETIRED != 0 }
+}
+
+/// Returns `p` with the [`RETIRED`] bit set if `retired` is `true`, and cleared otherwise.
+fn with_retired<V>(p: Shared<'_, V>, retired: bool) -> Shared<'_, V> {
+    // safety: see `is_retired`.
+    let addr = unsafe { p.as_ptr() as usize } & !RETIRED;
+    Shared::from((addr | if retired { RETIRED } else { 0 }) as *mut Linked<V>)
+}
+
+/// What an entry currently holds, as returned by [`Entry::state`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum EntryState<'g, V> {
+    /// The entry holds a value.
+    Live(&'g V),
+    /// The value has been removed. The entry is still in the dirty map, if there is one, so a
+    /// new value can be stored without taking the lock.
+    Empty,
+    /// The value has been removed and the entry was left out of the dirty map when it was built.
+    /// A new value can only be stored under the lock, after adding the entry back to the dirty
+    /// map.
+    Expunged,
+}
 
-#[derive(Clone)]
 pub struct Entry<V> {
     pub(crate) p: Atomic<V>,
-    pub(crate) expunged: Atomic<V>,
+    /// When the entry last went from empty to holding a value, if the map tracks insertion
+    /// order. See [`MapBuilder::track_insertion_order`](crate::MapBuilder::track_insertion_order).
+    pub(crate) seq: AtomicU64,
 }
 
 
@@ -17,80 +66,313 @@ impl<V> Entry<V>
     pub(crate) fn new(e: Shared<V>) -> Self {
         Self {
             p: Atomic::from(e),
-            expunged: Atomic::null(),
+            seq: AtomicU64::new(0),
         }
     }
-    pub fn remove<'g>(&'g self, guard: &'g Guard<'_>) -> Option<&'g V> {
-        let item = self.p.load(Ordering::SeqCst, guard);
-        if item.is_null() /*TODO || self.p == self.EXPUNGED*/ {
-            return None;
+
+    /// Returns the entry's current state.
+    ///
+    /// The value of a retired entry is still reported as live; see [`RETIRED`].
+    pub(crate) fn state<'g>(&'g self, guard: &'g Guard<'_>) -> EntryState<'g, V> {
+        let p = self.p.load(Ordering::SeqCst, guard);
+        Self::state_of(with_retired(p, false))
+    }
+
+    /// Returns the state `p` puts the entry in, as seen by anything that would change it. A
+    /// retired entry counts as expunged.
+    fn state_of<'g>(p: Shared<'g, V>) -> EntryState<'g, V> {
+        if p.is_null() {
+            EntryState::Empty
+        } else if p == expunged() || is_retired(p) {
+            EntryState::Expunged
+        } else {
+            // safety: real value pointers are only retired after being unlinked from the entry,
+            // and `p` was loaded under a guard that is still held.
+            EntryState::Live(unsafe { p.deref() })
         }
-        if let Ok(v) = self.p.compare_exchange(item, Shared::null(), Ordering::AcqRel, Ordering::Acquire, guard) {
-            if let Some(v) = unsafe { item.as_ref() } {
-                let v = &**v;
+    }
 
-                return Some(v);
+    /// Removes the entry's value and returns it.
+    ///
+    /// The removed value is retired through `guard`, so it is only freed once every guard that
+    /// might still be reading it (including this one) has been dropped. The returned reference
+    /// therefore borrows `guard` and can't outlive it.
+    pub fn remove<'g>(&'g self, guard: &'g Guard<'_>) -> Option<&'g V> {
+        loop {
+            let item = self.p.load(Ordering::SeqCst, guard);
+            let value = match Self::state_of(item) {
+                EntryState::Live(value) => value,
+                EntryState::Empty | EntryState::Expunged => return None,
+            };
+            if self.p.compare_exchange(item, Shared::null(), Ordering::AcqRel, Ordering::Acquire, guard).is_ok() {
+                // safety: we just unlinked the value, so no new references to it can be created.
+                // Threads that already loaded it hold a guard, and the collector will not free it
+                // before those guards (and ours) are dropped.
+                unsafe { guard.retire_shared(item) };
+                return Some(value);
             }
         }
-
-        return None;
     }
+
+    /// Returns the entry's value, or `None` if it has been removed.
     pub fn load<'g>(&'g self, guard: &'g Guard<'_>) -> Option<&'g V> {
-        let item = self.p.load(Ordering::SeqCst, guard);
-        if item.is_null() /*TODO || self.p == self.EXPUNGED*/ {
-            return None;
+        match self.state(guard) {
+            EntryState::Live(value) => Some(value),
+            EntryState::Empty | EntryState::Expunged => None,
         }
-        if let Some(v) = unsafe { item.as_ref() } {
-            let v = &**v;
+    }
 
-            return Some(v);
+    /// Stores `value` unless the entry has been expunged, and returns the value it replaced.
+    ///
+    /// Fails, leaving the entry untouched, if the entry is expunged; the value must then be
+    /// stored under the map's lock. Like a removed value, the replaced value is retired through
+    /// `guard`.
+    pub(crate) fn try_store<'g>(&'g self, value: Shared<V>, guard: &'g Guard<'_>) -> Result<Option<&'g V>, ()> {
+        loop {
+            let load = self.p.load(Ordering::SeqCst, guard);
+            let old = match Self::state_of(load) {
+                EntryState::Live(old) => Some(old),
+                EntryState::Empty => None,
+                EntryState::Expunged => return Err(()),
+            };
+            if self.p.compare_exchange(load, value, Ordering::AcqRel, Ordering::Acquire, guard).is_ok() {
+                if old.is_some() {
+                    // safety: see `remove`.
+                    unsafe { guard.retire_shared(load) };
+                }
+                return Ok(old);
+            }
         }
-        return None;
     }
 
-    //todo check later
-    pub(crate) fn try_store<'g>(&'g self, value: Shared<V>, guard: &'g Guard<'_>) -> bool {
+    /// Stores `value` if the entry is empty, and otherwise returns the value it holds.
+    ///
+    /// Unlike [`try_store`](Self::try_store), this never replaces a value, so a value stored
+    /// concurrently without the lock wins over `value`. The entry must be known not to be
+    /// expunged, which requires holding the map's lock; `value` is left unpublished on failure.
+    pub(crate) fn try_store_if_empty<'g>(&'g self, value: Shared<'g, V>, guard: &'g Guard<'_>) -> Result<(), &'g V> {
         loop {
-            let load = self.p.load(Ordering::SeqCst, guard);
+            match self.p.compare_exchange(Shared::null(), value, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(_) => return Ok(()),
+                Err(e) => match Self::state_of(e.current) {
+                    EntryState::Live(current) => return Err(current),
+                    // the value was removed again in between
+                    EntryState::Empty => continue,
+                    EntryState::Expunged => unreachable!("entry expunged while holding the lock"),
+                },
+            }
+        }
+    }
 
-            if load == self.expunged.load(Ordering::SeqCst, guard) {
-                return false;
+    /// Swaps `value` into the entry if it currently holds a value, returning the previous one.
+    ///
+    /// Returns `None`, leaving the entry untouched, if the entry has been deleted or expunged.
+    /// The previous value is retired through `guard`.
+    pub(crate) fn try_replace<'g>(&'g self, value: Shared<'g, V>, guard: &'g Guard<'_>) -> Option<&'g V> {
+        loop {
+            let current = self.p.load(Ordering::SeqCst, guard);
+            let old = match Self::state_of(current) {
+                EntryState::Live(old) => old,
+                EntryState::Empty | EntryState::Expunged => return None,
+            };
+            if self.p.compare_exchange(current, value, Ordering::AcqRel, Ordering::Acquire, guard).is_ok() {
+                // safety: see `remove`.
+                unsafe { guard.retire_shared(current) };
+                return Some(old);
             }
-            if self.p.compare_exchange(load, value, Ordering::AcqRel, Ordering::Acquire, guard).is_ok() {
-                return true;
+        }
+    }
+
+    /// Swaps `value` into the entry if it currently holds exactly `current`, compared by address,
+    /// and returns the replaced value.
+    ///
+    /// Fails, leaving the entry untouched, if the entry holds a different value or none at all.
+    /// The replaced value is retired through `guard`.
+    pub(crate) fn compare_and_swap_ptr<'g>(&'g self, current: &V, value: Shared<'g, V>, guard: &'g Guard<'_>) -> Result<&'g V, ()> {
+        let loaded = self.p.load(Ordering::SeqCst, guard);
+        match Self::state_of(loaded) {
+            EntryState::Live(old) if ptr::eq(old, current) => self.p
+                .compare_exchange(loaded, value, Ordering::AcqRel, Ordering::Acquire, guard)
+                .map(|_| {
+                    // safety: see `remove`.
+                    unsafe { guard.retire_shared(loaded) };
+                    old
+                })
+                .map_err(|_| ()),
+            _ => Err(()),
+        }
+    }
+
+    /// Removes the entry's value if it is exactly `current`, compared by address.
+    ///
+    /// Returns whether the value was removed. Like [`remove`](Self::remove), the removed value is
+    /// retired through `guard`.
+    pub(crate) fn remove_if_ptr<'g>(&'g self, current: &V, guard: &'g Guard<'_>) -> bool {
+        let loaded = self.p.load(Ordering::SeqCst, guard);
+        match Self::state_of(loaded) {
+            EntryState::Live(value) if ptr::eq(value, current) => {
+                if self.p.compare_exchange(loaded, Shared::null(), Ordering::AcqRel, Ordering::Acquire, guard).is_err() {
+                    return false;
+                }
+                // safety: see `remove`.
+                unsafe { guard.retire_shared(loaded) };
+                true
             }
+            _ => false,
         }
     }
 
+    /// Marks an expunged entry as empty again, so that it can be added back to the dirty map.
+    ///
+    /// Returns `true` if the entry was expunged. Must be called while holding the map's lock.
     pub fn unexpunge_locked<'g>(&'g self, guard: &'g Guard<'_>) -> bool {
-        let exp = self.expunged.load(Ordering::SeqCst, guard);
-        self.expunged.compare_exchange(exp, Shared::null(), Ordering::AcqRel, Ordering::Acquire, guard).is_ok()
+        self.p.compare_exchange(expunged(), Shared::null(), Ordering::AcqRel, Ordering::Acquire, guard).is_ok()
     }
 
-    pub fn try_unexpunge_locked<'g>(&'g self, guard: &'g Guard<'_>) -> bool {
+    /// Expunges the entry if it is empty, and returns whether it is expunged.
+    ///
+    /// Used while building a dirty map, which leaves out expunged entries. Must be called while
+    /// holding the map's lock.
+    pub fn try_expunge_locked<'g>(&'g self, guard: &'g Guard<'_>) -> bool {
         let mut p = self.p.load(Ordering::SeqCst, guard);
         while p.is_null() {
-            if self.p.compare_exchange(p,self.expunged.load(Ordering::SeqCst,guard),Ordering::AcqRel,Ordering::Acquire,guard).is_ok() {
+            if self.p.compare_exchange(p, expunged(), Ordering::AcqRel, Ordering::Acquire, guard).is_ok() {
                 return true;
             }
             p = self.p.load(Ordering::SeqCst, guard);
         }
-        return p == self.expunged.load(Ordering::SeqCst,guard)
+        p == expunged()
     }
 
+    /// Retires the entry, and returns whether it held a value.
+    ///
+    /// Used when the tables holding the entry are dropped from the map. A value is marked
+    /// [`RETIRED`] rather than removed, so that threads still reading the old tables keep seeing
+    /// it, and is dropped along with the entry; an empty entry is expunged. Either way, a thread
+    /// still holding one of the old tables can no longer store into or remove from the entry
+    /// without the lock. Must be called while holding the map's lock.
+    pub(crate) fn retire_locked(&self, guard: &Guard<'_>) -> bool {
+        let mut p = self.p.load(Ordering::SeqCst, guard);
+        loop {
+            let retired = match Self::state_of(p) {
+                EntryState::Live(_) => with_retired(p, true),
+                EntryState::Empty => expunged(),
+                EntryState::Expunged => return false,
+            };
+            match self.p.compare_exchange(p, retired, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(_) => return !p.is_null(),
+                // a lock-free store or removal got in first
+                Err(e) => p = e.current,
+            }
+        }
+    }
 
-    pub(crate) fn store_locked<'g>(&'g self, value: Shared<V>, guard: &'g Guard<'_>) {
-
-        self.p.swap(value, Ordering::SeqCst,guard);
+    /// Unconditionally stores `value`, and returns the value it replaced, which is retired
+    /// through `guard`.
+    ///
+    /// The entry must be known not to be expunged, which requires holding the map's lock.
+    pub(crate) fn store_locked<'g>(&'g self, value: Shared<V>, guard: &'g Guard<'_>) -> Option<&'g V> {
+        let old = self.p.swap(value, Ordering::SeqCst, guard);
+        match Self::state_of(old) {
+            EntryState::Live(value) => {
+                // safety: see `remove`.
+                unsafe { guard.retire_shared(old) };
+                Some(value)
+            }
+            EntryState::Empty | EntryState::Expunged => None,
+        }
     }
 }
 
 
+/// Drops the entry's value along with it.
+///
+/// Entries are only dropped once no map references them and no guard can still be reading them,
+/// so nobody can observe the value anymore either. Values that were removed or replaced earlier
+/// have already been retired on their own.
 impl<V> Drop for Entry<V> {
     fn drop(&mut self) {
-        unsafe {
-            //let _ = Box::into_raw(self.p.load())
+        // safety: see above; nothing else can be accessing the entry.
+        let guard = unsafe { Guard::unprotected() };
+        let p = with_retired(self.p.load(Ordering::SeqCst, &guard), false);
+        if let EntryState::Live(_) = Self::state_of(p) {
+            // safety: the value is only referenced by this entry.
+            drop(unsafe { p.into_box() });
         }
     }
 }
 
+
+/// A handle to a single entry of a [`Map`](crate::map::Map), as returned by
+/// [`Map::get_or_insert_entry`](crate::map::Map::get_or_insert_entry).
+///
+/// The handle operates on the entry directly, so repeated loads and updates skip the hash
+/// lookup. It stays tied to the entry it was created for: once the key is removed from the map,
+/// [`load`](Self::load) returns `None` and updates through the handle fail.
+///
+/// Whether a later insert of the key is visible through the handle depends on where the entry
+/// was. A removed key that is in the read-only map keeps its emptied entry there until the map
+/// is compacted (which also happens on its own, once removed entries outweigh live ones; see
+/// [`MapBuilder::compaction_threshold`](crate::MapBuilder::compaction_threshold)), and
+/// inserting the key again stores the new value in that same entry, so the
+/// handle sees it and can update it again. A key that was only in the dirty map, or whose entry
+/// was compacted away, gets a new entry when it is inserted again, which the handle never sees.
+pub struct EntryHandle<'g, V> {
+    entry: &'g Entry<V>,
+    collector: &'g Collector,
+    guard: &'g Guard<'g>,
+}
+
+impl<'g, V> EntryHandle<'g, V> {
+    pub(crate) fn new(entry: &'g Entry<V>, collector: &'g Collector, guard: &'g Guard<'g>) -> Self {
+        Self {
+            entry,
+            collector,
+            guard,
+        }
+    }
+
+    /// Returns the entry's current value, or `None` if it has been removed.
+    pub fn load(&self) -> Option<&'g V> {
+        self.entry.load(self.guard)
+    }
+
+    /// Stores `value` in the entry and returns the value it replaced.
+    ///
+    /// Like an [`insert`](crate::map::Map::insert) of a key that is already present, this is a
+    /// single atomic swap and does not take the map's lock. An entry that has been removed can
+    /// only be brought back by the map itself (under its lock), so in that case nothing is
+    /// stored and `value` is handed back as an error.
+    pub fn store(&self, value: V) -> Result<&'g V, V> {
+        let new = Shared::boxed(value, self.collector);
+        match self.entry.try_replace(new, self.guard) {
+            Some(old) => Ok(old),
+            // safety: the new value was never published, so nobody else can be referencing it.
+            None => Err(Linked::into_inner(*unsafe { new.into_box() })),
+        }
+    }
+
+    /// Stores `new` in the entry if its current value is `current`, and returns the replaced
+    /// value.
+    ///
+    /// `current` is compared by identity, not by value: it must be a reference previously
+    /// returned by [`load`](Self::load) (or any other read of this entry). If the entry has been
+    /// updated or removed since, nothing is stored and `new` is handed back as an error.
+    pub fn compare_and_swap(&self, current: &V, new: V) -> Result<&'g V, V> {
+        let new = Shared::boxed(new, self.collector);
+        self.entry.compare_and_swap_ptr(current, new, self.guard).map_err(|()| {
+            // safety: the new value was never published, so nobody else can be referencing it.
+            Linked::into_inner(*unsafe { new.into_box() })
+        })
+    }
+}
+
+/// Two handles are equal if they refer to the same entry, that is, to the same key of the same
+/// map.
+impl<V> PartialEq for EntryHandle<'_, V> {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self.entry, other.entry)
+    }
+}
+
+impl<V> Eq for EntryHandle<'_, V> {}
@@ -0,0 +1,149 @@
+//! An immutable, guard-free snapshot of a [`Map`] for build-then-serve workloads.
+//!
+//! Once all writes are done, [`Map::freeze_into_arc`] moves the live key-value pairs of a map
+//! into a [`FrozenMap`], which is a plain hash table behind an [`Arc`](std::sync::Arc). With no
+//! writers left there is nothing to reclaim, so lookups take no guard and never touch the lock,
+//! and cost the same as on a `HashMap`.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::thread;
+//! use syncmap::map::Map;
+//!
+//! let map = Map::new();
+//! let guard = map.guard();
+//! map.insert("a", 1, &guard);
+//! map.insert("b", 2, &guard);
+//! drop(guard);
+//!
+//! let frozen = map.freeze_into_arc();
+//! let reader = {
+//!     let frozen = frozen.clone();
+//!     thread::spawn(move || frozen.get(&"a").copied())
+//! };
+//! assert_eq!(reader.join().unwrap(), Some(1));
+//! assert_eq!(frozen.get(&"b"), Some(&2));
+//! assert_eq!(frozen.len(), 2);
+//! ```
+//!
+//! [`Map`]: crate::map::Map
+//! [`Map::freeze_into_arc`]: crate::map::Map::freeze_into_arc
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Index;
+use hashbrown::HashMap;
+
+/// The live contents of a [`Map`](crate::map::Map) after it was frozen. See the
+/// [module docs](self).
+pub struct FrozenMap<K, V, S = crate::DefaultHashBuilder> {
+    map: HashMap<K, V, S>,
+}
+
+impl<K, V, S> FrozenMap<K, V, S> {
+    pub(crate) fn new(map: HashMap<K, V, S>) -> Self {
+        Self { map }
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the map contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> hashbrown::hash_map::Iter<'_, K, V> {
+        self.map.iter()
+    }
+
+    /// An iterator visiting all keys in arbitrary order.
+    pub fn keys(&self) -> hashbrown::hash_map::Keys<'_, K, V> {
+        self.map.keys()
+    }
+
+    /// An iterator visiting all values in arbitrary order.
+    pub fn values(&self) -> hashbrown::hash_map::Values<'_, K, V> {
+        self.map.values()
+    }
+
+    /// Returns the map's hasher, which is the one the map was frozen with.
+    pub fn hasher(&self) -> &S {
+        self.map.hasher()
+    }
+}
+
+impl<K, V, S> FrozenMap<K, V, S>
+    where
+        K: Hash + Eq,
+        S: BuildHasher,
+{
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+    {
+        self.map.get(key)
+    }
+
+    /// Returns the key-value pair corresponding to the supplied key.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+    {
+        self.map.get_key_value(key)
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+    {
+        self.map.contains_key(key)
+    }
+}
+
+impl<K, V, S, Q> Index<&Q> for FrozenMap<K, V, S>
+    where
+        K: Hash + Eq + Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        S: BuildHasher,
+{
+    type Output = V;
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is not present in the map.
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a FrozenMap<K, V, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = hashbrown::hash_map::Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K, V, S> fmt::Debug for FrozenMap<K, V, S>
+    where
+        K: fmt::Debug,
+        V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.map.iter()).finish()
+    }
+}
@@ -0,0 +1,88 @@
+use hashbrown::hash_map;
+use seize::Guard;
+use crate::entry::EntryPtr;
+use crate::map::Map;
+
+/// An iterator over a map's entries.
+///
+/// See [`Map::iter`](crate::map::Map::iter) for details.
+pub struct Iter<'g, K, V> {
+    pub(crate) inner: Option<hash_map::Iter<'g, K, EntryPtr<V>>>,
+    pub(crate) guard: &'g Guard<'g>,
+}
+
+impl<'g, K, V> Iterator for Iter<'g, K, V> {
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let inner = self.inner.as_mut()?;
+        for (key, entry) in inner {
+            // safety: entries are owned by the map, and the map outlives 'g.
+            if let Some(value) = unsafe { &**entry }.load(self.guard) {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over a map's keys.
+///
+/// See [`Map::keys`](crate::map::Map::keys) for details.
+pub struct Keys<'g, K, V> {
+    pub(crate) iter: Iter<'g, K, V>,
+}
+
+impl<'g, K, V> Iterator for Keys<'g, K, V> {
+    type Item = &'g K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(k, _)| k)
+    }
+}
+
+/// An iterator over a map's values.
+///
+/// See [`Map::values`](crate::map::Map::values) for details.
+pub struct Values<'g, K, V> {
+    pub(crate) iter: Iter<'g, K, V>,
+}
+
+impl<'g, K, V> Iterator for Values<'g, K, V> {
+    type Item = &'g V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(_, v)| v)
+    }
+}
+
+/// An iterator that removes the entries matching a predicate, and yields them.
+///
+/// See [`Map::drain_filter`](crate::map::Map::drain_filter) for details.
+pub struct DrainFilter<'g, K, V, S, F> {
+    pub(crate) map: &'g Map<K, V, S>,
+    pub(crate) inner: Option<hash_map::Iter<'g, K, EntryPtr<V>>>,
+    pub(crate) pred: F,
+    pub(crate) guard: &'g Guard<'g>,
+}
+
+impl<'g, K, V, S, F> Iterator for DrainFilter<'g, K, V, S, F>
+    where
+        F: FnMut(&K, &V) -> bool,
+{
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let inner = self.inner.as_mut()?;
+        for (key, entry) in inner {
+            // safety: entries are owned by the map, and the map outlives 'g.
+            let entry = unsafe { &**entry };
+            if let Some(value) = entry.load(self.guard) {
+                if (self.pred)(key, value) && self.map.remove_read_entry_if(entry, value, self.guard) {
+                    return Some((key, value));
+                }
+            }
+        }
+        None
+    }
+}
@@ -1,6 +1,26 @@
-mod reclaim;
+pub mod reclaim;
+#[cfg(feature = "rkyv")]
+pub mod archive;
+mod builder;
+pub mod compat;
+mod counter;
 mod entry;
+pub mod frozen;
+pub mod iter;
 pub mod map;
+#[cfg(feature = "trace")]
+pub mod trace;
+mod map_ref;
+mod map_entry;
+pub mod sharded;
+pub mod simple;
+
+pub use builder::MapBuilder;
+pub use counter::Counter;
+pub use entry::EntryHandle;
+pub use map_ref::{HashMapRef, Ref, RefMut};
+pub use map_entry::{MapEntry, OccupiedEntry, VacantEntry};
+pub use hashbrown::TryReserveError;
 
 
 
@@ -1,14 +1,76 @@
+use std::any::Any;
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::ops::Deref;
-use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Add, Deref, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, TryLockError, Weak};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+use hashbrown::{HashMap, TryReserveError};
 use seize::{Collector, Guard};
-use crate::entry::Entry;
-use crate::reclaim::{Atomic, Shared};
+use crate::builder::MapBuilder;
+use crate::counter::Counter;
+use crate::frozen::FrozenMap;
+use crate::entry::{Entry, EntryHandle, EntryPtr, EntryState};
+use crate::iter::{DrainFilter, Iter, Keys, Values};
+use crate::reclaim::{Atomic, Linked, RetireShared, Shared};
+#[cfg(feature = "trace")]
+use crate::trace::{Operation, TraceEvent, TraceHook};
+
+/// How many times [`Map::init_table`] busy-waits on a concurrent initialization before it
+/// falls back to yielding the thread.
+///
+/// Initialization is short, so a losing thread usually sees it finish within a few spins, which
+/// is much cheaper than a trip through the scheduler.
+const INIT_SPIN_LIMIT: usize = 16;
+
+/// Once a promotion has happened, the dirty map has to grow to this many times the size it was
+/// promoted at before the next promotion happens after the usual number of misses.
+const PROMOTION_GROWTH: usize = 2;
+
+/// Until the dirty map has grown by [`PROMOTION_GROWTH`], promotion takes this many times the
+/// usual number of misses, so that maps that stop growing still get promoted eventually.
+const PROMOTION_BACKOFF: usize = 4;
+
+/// A callback for keys whose value [`Map::get_or_insert_with`] is about to compute. See
+/// [`MapBuilder::on_miss`].
+pub(crate) type MissHook<K> = Box<dyn Fn(&K) + Send + Sync>;
+
+/// An error cached by [`Map::get_or_try_insert_with_negative_cache`], with the time it expires
+/// at. The error's type is only known to the caller, so it is stored type-erased.
+type CachedFailure = (Instant, Box<dyn Any + Send + Sync>);
+
+/// The keys of the metrics a map emits, built once from its label so that emitting a metric
+/// doesn't allocate. See [`MapBuilder::label`].
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub(crate) struct MetricKeys {
+    misses: metrics::Key,
+    len: metrics::Key,
+    promotions: metrics::Key,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricKeys {
+    pub(crate) fn new(label: impl Into<metrics::SharedString>) -> Self {
+        let label = metrics::Label::new("map", label);
+        let key = |name: &'static str| metrics::Key::from_parts(name, vec![label.clone()]);
+        Self {
+            misses: key("syncmap.misses"),
+            len: key("syncmap.len"),
+            promotions: key("syncmap.promotions"),
+        }
+    }
+}
+
+/// The default for [`MapBuilder::compaction_threshold`].
+pub(crate) const DEFAULT_COMPACTION_THRESHOLD: f64 = 1.0;
+
+/// The most entries the `Debug` output of a [`Map`] prints before summarizing the rest.
+pub const DEBUG_ENTRY_LIMIT: usize = 128;
 
 macro_rules! load_factor {
     ($n: expr) => {
@@ -25,24 +87,143 @@ macro_rules! load_factor {
 /// multiple thread read, write, and overwrite entries for disjoint sets of keys. In these two cases,
 /// use of a Map may significantly reduce lock contention compared to a Rust HashMap paired with a
 /// separate Mutex
+///
+/// A `Map<K, V, S>` is `Send` and `Sync` whenever `K`, `V` and `S` are all `Send + Sync`, so it
+/// can be shared across threads behind an `Arc` without any additional wrapping.
 pub struct Map<K, V, S = crate::DefaultHashBuilder> {
     read: Atomic<ReadOnly<K, V>>,
-    dirty: Atomic<HashMap<K, *mut Entry<V>>>,
+    dirty: Atomic<HashMap<K, EntryPtr<V>>>,
     misses: AtomicUsize,
     flag_ctl: AtomicIsize,
     build_hasher: S,
     collector: Collector,
     lock: Mutex<()>,
+    /// Keys whose value is currently being computed by [`Map::get_or_insert_with`], with the
+    /// thread computing it.
+    in_flight: Mutex<HashMap<K, ThreadId>>,
+    /// Signalled whenever a key is taken out of `in_flight`.
+    in_flight_done: Condvar,
+    /// The thread holding `lock` through a [`MapEntry`](crate::MapEntry), if any. Only written
+    /// by that thread while it holds the lock.
+    entry_owner: Mutex<Option<ThreadId>>,
+    /// Whether `entry_owner` is set, so that taking the lock only checks it while it is.
+    entry_held: AtomicBool,
+    /// Errors recently returned by the closures of
+    /// [`Map::get_or_try_insert_with_negative_cache`], with the time they expire at.
+    failures: Mutex<HashMap<K, CachedFailure>>,
+    /// The number of entries that currently hold a value, in either map.
+    ///
+    /// A value is counted after it is stored and uncounted after it is removed, so a removal that
+    /// races the store can briefly take the count below zero. It wraps around and is read back
+    /// through [`Map::len`], which clamps it.
+    live: AtomicUsize,
+    /// Values removed from read-only map entries since it was last compacted, which leaves the
+    /// emptied entries behind as tombstones.
+    tombstones: AtomicUsize,
+    /// Signalled, with `lock` held, whenever `live` drops to zero.
+    emptied: Condvar,
+    /// The length of the dirty map when it was last promoted.
+    last_promoted_len: AtomicUsize,
+    /// Lookups answered from the read-only map without taking the lock.
+    read_hits: AtomicU64,
+    /// Lookups that found their value only after taking the lock.
+    dirty_hits: AtomicU64,
+    /// Lookups that found no value.
+    get_misses: AtomicU64,
+    /// The next insertion sequence number, if the map tracks insertion order.
+    pub(crate) next_seq: Option<AtomicU64>,
+    /// Removed entries per live entry in the read-only map above which `maybe_compact` compacts.
+    pub(crate) compaction_threshold: f64,
+    /// Whether promotions empty the dirty map in place rather than dropping it.
+    pub(crate) keep_dirty_allocation: bool,
+    pub(crate) on_miss: Option<MissHook<K>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metric_keys: MetricKeys,
+    #[cfg(feature = "trace")]
+    pub(crate) trace_hook: Option<TraceHook>,
 }
 
+// Safety: the read and dirty tables hold raw pointers to entries, which makes the auto-derived
+// impls unreliable. Those pointers are only ever created by the map itself and point to entries
+// owned by the map. The tables themselves are swapped in and out through `Atomic`s and the dirty
+// table is only mutated while holding `self.lock`; the value inside each entry is only changed
+// through atomic operations. Keys and values may be accessed (and dropped) from any thread that
+// holds a reference to the map, hence the `Send + Sync` requirement on both.
+unsafe impl<K, V, S> Send for Map<K, V, S>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        S: Send + Sync,
+{}
+
+// Safety: see the `Send` impl above. Shared access only hands out `&K` and `&V` and all
+// mutation of shared state goes through atomics or happens under `self.lock`.
+unsafe impl<K, V, S> Sync for Map<K, V, S>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        S: Send + Sync,
+{}
+
+/// Prints at most [`DEBUG_ENTRY_LIMIT`] entries, followed by how many more there are, so that
+/// printing a large map can't produce a huge log line.
+///
+/// Printing never blocks: keys that are only in the dirty map are left out (and counted among
+/// the rest) if the lock is held elsewhere, for example by the thread that is printing the map.
 impl<K, V, S> fmt::Debug for Map<K, V, S>
     where
-        K: Debug,
+        K: Debug + Hash + Eq,
         V: Debug,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let guard = self.collector.enter();
-        f.debug_map().finish()
+        let read = self.read.load(Ordering::SeqCst, &guard);
+        let r = match unsafe { read.as_ref() } {
+            Some(r) => r,
+            None => return f.write_str("{}"),
+        };
+        let lock = if r.amended {
+            match self.lock.try_lock() {
+                Ok(lock) => Some(lock),
+                Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => None,
+            }
+        } else {
+            None
+        };
+        let dirty = match lock {
+            Some(_) => unsafe { self.dirty.load(Ordering::SeqCst, &guard).as_ref() },
+            None => None,
+        };
+
+        let dirty_only = dirty
+            .into_iter()
+            .flat_map(|d| d.iter())
+            .filter(|(k, _)| !r.m.contains_key(*k));
+        let entries = r.m.iter()
+            .chain(dirty_only)
+            .filter_map(|(k, e)| unsafe { e.as_ref().unwrap() }.load(&guard).map(|v| (k, v)));
+
+        f.write_str("{")?;
+        let mut printed = 0;
+        for (k, v) in entries.take(DEBUG_ENTRY_LIMIT) {
+            if printed > 0 {
+                f.write_str(", ")?;
+            }
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+            printed += 1;
+        }
+        let more = self.live_len().saturating_sub(printed);
+        if more > 0 {
+            if printed > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "... ({} more)", more)?;
+        }
+        drop(lock);
+        f.write_str("}")
     }
 }
 
@@ -52,25 +233,213 @@ impl<K, V, S> Clone for Map<K, V, S>
         V: Sync + Send + Clone,
         S: BuildHasher + Clone,
 {
+    /// Returns a new map holding clones of every live entry, with the same hasher and settings.
     fn clone(&self) -> Map<K, V, S> {
         let mut cloned_map = Map::with_hasher(self.build_hasher.clone());
-
+        cloned_map.compaction_threshold = self.compaction_threshold;
+        cloned_map.next_seq = self.next_seq.as_ref().map(|_| AtomicU64::new(0));
+        cloned_map.keep_dirty_allocation = self.keep_dirty_allocation;
+        #[cfg(feature = "metrics")]
         {
-            cloned_map.dirty = self.dirty.clone();
-            cloned_map.read = self.read.clone();
-            cloned_map.misses = AtomicUsize::new(self.misses.load(Ordering::SeqCst));
-            cloned_map.flag_ctl = AtomicIsize::new(self.flag_ctl.load(Ordering::SeqCst));
-
-            // let dirty = self.dirty.load(Ordering::SeqCst, &guard);
-            // if !dirty.is_null() {
-            //     for (key, value) in unsafe { dirty.deref() }.deref() {
-            //         let value = unsafe { (value.as_ref().unwrap()).p.load(Ordering::SeqCst, &guard).deref().deref() };
-            //         cloned_map.insert(key.clone(), value.clone(), &cloned_guard)
-            //     }
-            // }
+            cloned_map.metric_keys = self.metric_keys.clone();
         }
+
+        let guard = self.guard();
+        let entries = self.iter(&guard).map(|(k, v)| (k.clone(), v.clone()));
+        let cloned_guard = cloned_map.guard();
+        cloned_map.insert_batch(entries, &cloned_guard);
+        drop(cloned_guard);
         cloned_map
     }
+
+    /// Makes this map a copy of `source`, reusing this map's read-only table.
+    ///
+    /// The table is emptied in place, keeping its capacity, and refilled with clones of every
+    /// live entry of `source`, which are then all readable without the lock. Refreshing a
+    /// standby copy of a map this way repeatedly doesn't reallocate the table each time, as
+    /// long as it is large enough.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let primary = Map::new();
+    /// let mut standby = Map::new();
+    /// primary.insert(1, "a", &primary.guard());
+    /// standby.insert(2, "b", &standby.guard());
+    /// standby.clone_from(&primary);
+    /// let guard = standby.guard();
+    /// assert_eq!(standby.get(&1, &guard), Some(&"a"));
+    /// assert_eq!(standby.get(&2, &guard), None);
+    /// ```
+    fn clone_from(&mut self, source: &Self) {
+        self.build_hasher = source.build_hasher.clone();
+        self.compaction_threshold = source.compaction_threshold;
+        self.next_seq = source.next_seq.as_ref().map(|_| AtomicU64::new(0));
+        self.keep_dirty_allocation = source.keep_dirty_allocation;
+        #[cfg(feature = "metrics")]
+        {
+            self.metric_keys = source.metric_keys.clone();
+        }
+
+        let guard = self.guard();
+        let read = self.init_table(&guard);
+        let lock = self.lock();
+        let old_dirty = self.dirty.swap(Shared::null(), Ordering::SeqCst, &guard);
+        // safety: we have exclusive access to the map, so no other thread can be looking at the
+        // read-only table while it is modified in place.
+        let r = unsafe { read.as_ptr().as_mut() }.unwrap();
+        let mut entries: HashSet<_> = r.m.drain().map(|(_, e)| e).collect();
+        if let Some(dirty) = unsafe { old_dirty.as_ref() } {
+            entries.extend(dirty.values().copied());
+        }
+        // safety: the entries are no longer reachable from either table, and every one of them is
+        // retired once.
+        unsafe {
+            for e in entries {
+                guard.retire(e, seize::reclaim::boxed::<Entry<V>>);
+            }
+            guard.retire_shared(old_dirty);
+        }
+
+        let source_guard = source.guard();
+        for (key, value) in source.iter(&source_guard) {
+            let entry = Entry::new(Shared::boxed(value.clone(), &self.collector));
+            self.stamp(&entry);
+            r.m.insert(key.clone(), self.collector.link_boxed(entry));
+        }
+        r.amended = false;
+        let len = r.m.len();
+        self.misses.store(0, Ordering::SeqCst);
+        self.live.store(len, Ordering::SeqCst);
+        self.tombstones.store(0, Ordering::SeqCst);
+        self.last_promoted_len.store(len, Ordering::SeqCst);
+        drop(lock);
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for &Map<K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Inserts all pairs from the iterator, as with [`Map::insert_batch`].
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        let guard = self.guard();
+        self.insert_batch(iter, &guard);
+    }
+}
+
+/// Collects the pairs into a map, the last value winning for keys that repeat.
+///
+/// The pairs are published as the read-only map in one step, as with [`Map::from_std`]. Use the
+/// `TryFrom<Vec<(K, V)>>` impl instead to reject repeated keys.
+///
+/// # Examples
+///
+/// ```
+/// use syncmap::map::Map;
+///
+/// let map: Map<_, _> = vec![(1, "a"), (2, "b"), (1, "c")].into_iter().collect();
+/// assert_eq!(map.get(&1, &map.guard()), Some(&"c"));
+/// ```
+impl<K, V, S> FromIterator<(K, V)> for Map<K, V, S>
+    where
+        K: Clone + Hash + Ord,
+        S: BuildHasher + Default,
+{
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        Self::from_std(iter.into_iter().collect::<std::collections::HashMap<_, _>>())
+    }
+}
+
+/// Builds a map from pairs with unique keys, failing on the first key that repeats.
+///
+/// This is the strict counterpart of the `FromIterator` impl, for inputs such as configuration
+/// where a repeated key points at a mistake rather than an update.
+///
+/// # Examples
+///
+/// ```
+/// use syncmap::map::{DuplicateKey, Map};
+///
+/// let map = Map::<_, _>::try_from(vec![(1, "a"), (2, "b")]).unwrap();
+/// assert_eq!(map.len(), 2);
+/// let err = Map::<_, _>::try_from(vec![(1, "a"), (2, "b"), (1, "c")]).unwrap_err();
+/// assert_eq!(err, DuplicateKey(1));
+/// ```
+impl<K, V, S> TryFrom<Vec<(K, V)>> for Map<K, V, S>
+    where
+        K: Clone + Hash + Ord,
+        S: BuildHasher + Default,
+{
+    type Error = DuplicateKey<K>;
+
+    fn try_from(pairs: Vec<(K, V)>) -> Result<Self, Self::Error> {
+        let mut map = std::collections::HashMap::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            if map.contains_key(&key) {
+                return Err(DuplicateKey(key));
+            }
+            map.insert(key, value);
+        }
+        Ok(Self::from_std(map))
+    }
+}
+
+/// Compares the live entries of the map against a [`std::collections::HashMap`].
+///
+/// Any keys that are only in the dirty map are promoted first, as with [`Map::iter`]. The
+/// comparison is not atomic with respect to concurrent modification of the map.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use syncmap::map::Map;
+///
+/// let map = Map::new();
+/// let guard = map.guard();
+/// map.insert(1, "a", &guard);
+/// map.insert(2, "b", &guard);
+/// map.remove(&2, &guard);
+///
+/// let expected: HashMap<_, _> = [(1, "a")].into_iter().collect();
+/// assert!(map == expected);
+/// assert!(expected == map);
+/// ```
+impl<K, V, S, H> PartialEq<std::collections::HashMap<K, V, H>> for Map<K, V, S>
+    where
+        K: Clone + Hash + Ord,
+        V: PartialEq,
+        S: BuildHasher,
+        H: BuildHasher,
+{
+    fn eq(&self, other: &std::collections::HashMap<K, V, H>) -> bool {
+        let guard = self.guard();
+        let mut len = 0;
+        for (key, value) in self.iter(&guard) {
+            if other.get(key) != Some(value) {
+                return false;
+            }
+            len += 1;
+        }
+        len == other.len()
+    }
+}
+
+impl<K, V, S, H> PartialEq<Map<K, V, S>> for std::collections::HashMap<K, V, H>
+    where
+        K: Clone + Hash + Ord,
+        V: PartialEq,
+        S: BuildHasher,
+        H: BuildHasher,
+{
+    fn eq(&self, other: &Map<K, V, S>) -> bool {
+        other == self
+    }
 }
 
 impl<K, V> Map<K, V, crate::DefaultHashBuilder> {
@@ -89,6 +458,35 @@ impl<K, V> Map<K, V, crate::DefaultHashBuilder> {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Creates an empty `HashMap` with the specified capacity.
+    ///
+    /// The map will be able to hold at least `capacity` elements without reallocating. If
+    /// `capacity` is 0, the map will not allocate until it is first inserted into.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<&str, i32> = Map::with_capacity(10);
+    /// ```
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, crate::DefaultHashBuilder::default())
+    }
+
+    /// Creates a [`MapBuilder`] for configuring a new map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<&str, i32> = Map::builder().build();
+    /// ```
+    pub fn builder() -> MapBuilder<K, V> {
+        MapBuilder::new()
+    }
 }
 
 impl<K, V, S> Default for Map<K, V, S>
@@ -100,36 +498,39 @@ impl<K, V, S> Default for Map<K, V, S>
     }
 }
 
+/// Drops every entry along with its value, then the tables themselves.
+///
+/// Entries that are in both the read-only and the dirty map are only dropped once. Garbage that
+/// was retired earlier is freed when the collector is dropped right after.
 impl<K, V, S> Drop for Map<K, V, S> {
     fn drop(&mut self) {
+        // safety: we have mut access to self, so no guard of this map can still be active.
         let guard = unsafe { Guard::unprotected() };
 
-        // let read = self.read.swap(Shared::null(), Ordering::SeqCst, &guard);
-        // assert!(
-        //     !read.is_null(),
-        //     "self.moved is initialized together with the table"
-        // );
-        //
-        // // safety: we have mut access to self, so no-one else will drop this value under us.
-        // let read = unsafe { read.into_box() };
-        // drop(read);
-         let read = self.read.swap(Shared::null(), Ordering::SeqCst, &guard);
-        if !read.is_null() {
-            let read = unsafe { read.into_box() };
-            drop(read);
-        }
-        let moved = self.dirty.swap(Shared::null(), Ordering::SeqCst, &guard);
-        if moved.is_null() {
-            return;
+        let read = self.read.swap(Shared::null(), Ordering::SeqCst, &guard);
+        let dirty = self.dirty.swap(Shared::null(), Ordering::SeqCst, &guard);
+        let mut entries = HashSet::new();
+        if let Some(read) = unsafe { read.as_ref() } {
+            entries.extend(read.m.values().copied());
+        }
+        if let Some(dirty) = unsafe { dirty.as_ref() } {
+            entries.extend(dirty.values().copied());
+        }
+        for e in entries {
+            // safety: entries are allocated through the collector, are only referenced by the two
+            // tables, and are not retired while they are still in either of them.
+            drop(unsafe { Box::from_raw(e) });
         }
-        assert!(
-            !moved.is_null(),
-            "self.moved is initialized together with the table"
-        );
 
-        // safety: we have mut access to self, so no-one else will drop this value under us.
-        let moved = unsafe { moved.into_box() };
-        drop(moved);
+        // safety: as above, nobody else can be accessing the tables.
+        unsafe {
+            if !read.is_null() {
+                drop(read.into_box());
+            }
+            if !dirty.is_null() {
+                drop(dirty.into_box());
+            }
+        }
     }
 }
 
@@ -155,14 +556,67 @@ impl<K, V, S> Map<K, V, S> {
     /// map.insert(1, 2,&guard);
     /// ```
     pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_collector(hash_builder, Collector::new())
+    }
+
+    /// Creates an empty map with the specified `capacity`, using `hash_builder` to hash the keys.
+    ///
+    /// The map will be sized to accommodate `capacity` elements without reallocating. The tables
+    /// are still allocated lazily, on the first insert. If `capacity` is 0, the call will not
+    /// allocate, and is equivalent to [`Map::with_hasher`].
+    ///
+    /// Warning: `hash_builder` is normally randomly generated, and is designed to allow the map
+    /// to be resistant to attacks that cause many collisions and very poor performance.
+    /// Setting it manually using this function can expose a DoS attack vector.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::DefaultHashBuilder;
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::with_capacity_and_hasher(10, DefaultHashBuilder::default());
+    /// map.insert(1, 2, &map.guard());
+    /// ```
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        let map = Self::with_hasher(hash_builder);
+        if capacity > 0 {
+            // `init_table` reads a positive `flag_ctl` as the capacity to allocate.
+            map.flag_ctl.store(capacity.min(isize::MAX as usize) as isize, Ordering::SeqCst);
+        }
+        map
+    }
+
+    /// Creates an empty map that uses `hash_builder` and reclaims memory through `collector`.
+    pub(crate) fn with_collector(hash_builder: S, collector: Collector) -> Self {
         Self {
             read: Atomic::null(),
             dirty: Atomic::null(),
             misses: AtomicUsize::new(0),
             flag_ctl: AtomicIsize::new(0),
             build_hasher: hash_builder,
-            collector: Collector::new(),
+            collector,
             lock: Mutex::new(()),
+            in_flight: Mutex::new(HashMap::new()),
+            in_flight_done: Condvar::new(),
+            entry_owner: Mutex::new(None),
+            entry_held: AtomicBool::new(false),
+            failures: Mutex::new(HashMap::new()),
+            live: AtomicUsize::new(0),
+            tombstones: AtomicUsize::new(0),
+            emptied: Condvar::new(),
+            last_promoted_len: AtomicUsize::new(0),
+            read_hits: AtomicU64::new(0),
+            dirty_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+            next_seq: None,
+            on_miss: None,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            keep_dirty_allocation: false,
+            #[cfg(feature = "metrics")]
+            metric_keys: MetricKeys::new("default"),
+            #[cfg(feature = "trace")]
+            trace_hook: None,
         }
     }
 
@@ -170,19 +624,221 @@ impl<K, V, S> Map<K, V, S> {
     ///
     /// Keep in mind that for as long as you hold onto this `Guard`, you are preventing the
     /// collection of garbage generated by the map.
+    ///
+    /// The returned guard borrows the map, and every reference the map hands out is bounded by
+    /// both the guard and the map. A value reference therefore can't outlive the map it came
+    /// from:
+    ///
+    /// ```compile_fail
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// let value = map.get(&1, &guard);
+    /// drop(guard);
+    /// drop(map);
+    /// assert_eq!(value, Some(&"a"));
+    /// ```
+    ///
+    /// Nor can it outlive the guard it was loaded with:
+    ///
+    /// ```compile_fail
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// let value = map.get(&1, &guard);
+    /// drop(guard);
+    /// assert_eq!(value, Some(&"a"));
+    /// ```
     pub fn guard(&self) -> Guard<'_> {
         self.collector.enter()
     }
 
+    /// Returns `true` if a thread panicked while holding the map's internal lock.
+    ///
+    /// This is purely informational. The lock only serializes writers and protects no data of
+    /// its own, and the tables it guards are only ever changed by swapping in complete new ones
+    /// or by single inserts and removals, so a panic (for example in a closure passed to
+    /// [`with_many_mut`](Self::with_many_mut)) can't leave them half-updated. The map ignores
+    /// the poisoning and keeps working.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::panic::{self, AssertUnwindSafe};
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+    ///     map.with_many_mut(&[1], |_| panic!("boom"), &guard);
+    /// }));
+    /// assert!(map.is_poisoned());
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.get(&1, &guard), Some(&"a"));
+    /// ```
+    pub fn is_poisoned(&self) -> bool {
+        self.lock.is_poisoned()
+    }
+
+    /// Acquires the lock that serializes access to the dirty map, ignoring poisoning.
+    ///
+    /// See [`is_poisoned`](Self::is_poisoned) for why that is fine.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current thread already holds the lock through a
+    /// [`MapEntry`](crate::MapEntry), since waiting for itself would never return.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, ()> {
+        if self.entry_held.load(Ordering::SeqCst) {
+            let owner = *self.entry_owner.lock().unwrap_or_else(PoisonError::into_inner);
+            if owner == Some(std::thread::current().id()) {
+                panic!("re-entrant use of a map: a MapEntry on this thread holds its lock");
+            }
+        }
+        self.lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Acquires the lock on behalf of a [`MapEntry`](crate::MapEntry), which keeps it until the
+    /// entry is dropped. Using the map in a way that takes the lock again from the same thread
+    /// panics meanwhile, rather than deadlocking.
+    pub(crate) fn lock_for_entry(&self) -> EntryLock<'_> {
+        let lock = self.lock();
+        *self.entry_owner.lock().unwrap_or_else(PoisonError::into_inner) = Some(std::thread::current().id());
+        self.entry_held.store(true, Ordering::SeqCst);
+        EntryLock { owner: &self.entry_owner, held: &self.entry_held, _lock: lock }
+    }
+
+    /// The number of live entries, as returned by [`len`](Self::len).
+    fn live_len(&self) -> usize {
+        // the count only wraps around while a removal is briefly ahead of its store
+        (self.live.load(Ordering::SeqCst) as isize).max(0) as usize
+    }
+
+    /// Records that an empty entry now holds a value. `locked` says whether the caller already
+    /// holds `self.lock`.
+    fn count_stored(&self, locked: bool) {
+        // a removal that got counted first leaves the count at -1, and this empties the map
+        if self.live.fetch_add(1, Ordering::SeqCst) == usize::MAX {
+            let lock = if locked { None } else { Some(self.lock()) };
+            self.emptied.notify_all();
+            drop(lock);
+        }
+    }
+
+    /// Records that a live entry was removed, and wakes up [`wait_empty`](Self::wait_empty) if it
+    /// was the last one. `locked` says whether the caller already holds `self.lock`.
+    fn count_removed(&self, locked: bool) {
+        // a removal can be counted before the store it races, see `live`
+        let previous = self.live.fetch_sub(1, Ordering::SeqCst);
+        if previous == 1 {
+            // Notify while holding the lock, so that a waiter can't check the count and then
+            // start waiting in between.
+            let lock = if locked { None } else { Some(self.lock()) };
+            self.emptied.notify_all();
+            drop(lock);
+        }
+    }
+
+    /// Records that `entry` just went from empty to holding a value, if the map tracks insertion
+    /// order.
+    fn stamp(&self, entry: &Entry<V>) {
+        if let Some(next_seq) = &self.next_seq {
+            entry.seq.store(next_seq.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+        }
+    }
+
+    /// Records that an emptied entry of the read-only map holds a value again.
+    fn refill_tombstone(&self) {
+        let _ = self.tombstones
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| Some(n.saturating_sub(1)));
+    }
+
+    /// Removes `value` from `entry`, an entry of the read-only map, if it is still the entry's
+    /// value, and returns whether it was removed.
+    pub(crate) fn remove_read_entry_if(&self, entry: &Entry<V>, value: &V, guard: &Guard<'_>) -> bool {
+        if !entry.remove_if_ptr(value, guard) {
+            return false;
+        }
+        self.count_removed(false);
+        self.tombstones.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Blocks until the map has no live entries, or until `timeout` has passed.
+    ///
+    /// Returns `true` if the map became empty, and `false` if the timeout ran out first. With a
+    /// timeout of `None`, this waits for as long as it takes. The map counts its live entries as
+    /// they are inserted and removed, so waiting doesn't involve polling [`len`](Self::len).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use std::thread;
+    /// use std::time::Duration;
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Arc::new(Map::new());
+    /// map.insert(1, "a", &map.guard());
+    /// assert!(!map.wait_empty(Some(Duration::from_millis(10))));
+    ///
+    /// let worker = {
+    ///     let map = map.clone();
+    ///     thread::spawn(move || {
+    ///         map.remove(&1, &map.guard());
+    ///     })
+    /// };
+    /// assert!(map.wait_empty(None));
+    /// worker.join().unwrap();
+    /// ```
+    pub fn wait_empty(&self, timeout: Option<Duration>) -> bool {
+        let lock = self.lock();
+        let not_empty = |_: &mut ()| self.live.load(Ordering::SeqCst) != 0;
+        match timeout {
+            None => {
+                drop(self.emptied.wait_while(lock, not_empty).unwrap_or_else(PoisonError::into_inner));
+                true
+            }
+            Some(timeout) => {
+                let (lock, result) = self.emptied
+                    .wait_timeout_while(lock, timeout, not_empty)
+                    .unwrap_or_else(PoisonError::into_inner);
+                drop(lock);
+                !result.timed_out()
+            }
+        }
+    }
+
+    /// Reports `event` to the trace hook, if there is one.
+    #[cfg(feature = "trace")]
+    #[inline]
+    fn trace(&self, event: TraceEvent) {
+        if let Some(hook) = &self.trace_hook {
+            hook(event);
+        }
+    }
+
     #[inline]
-    fn check_guard(&self, guard: &Guard<'_>) {
-        // guard.collector() may be `None` if it is unprotected
+    pub(crate) fn check_guard(&self, guard: &Guard<'_>) {
+        // guard.collector() is `None` if it is unprotected. Unprotected guards are only meant for
+        // `Drop`, where nothing else can access the map; any other operation needs protection
+        // from concurrent reclamation.
+        debug_assert!(
+            guard.collector().is_some(),
+            "map operations need a protected guard, pin one with `map.guard()` instead of using \
+             `Guard::unprotected()`"
+        );
         if let Some(c) = guard.collector() {
             assert!(Collector::ptr_eq(c, &self.collector));
         }
     }
 
     fn init_table<'g>(&'g self, guard: &'g Guard<'_>) -> Shared<'g, ReadOnly<K, V>> {
+        let mut spins = 0;
         loop {
             let table = self.read.load(Ordering::SeqCst, guard);
             // safety: we loaded the ReadOnly while the thread was marked as active.
@@ -193,8 +849,14 @@ impl<K, V, S> Map<K, V, S> {
             //try allocate ReadOnly
             let mut flag = self.flag_ctl.load(Ordering::SeqCst);
             if flag < 0 {
-                //lost tje init race; just spin
-                std::thread::yield_now();
+                // lost the init race; the winner only allocates two empty tables, so spin briefly
+                // before giving up our time slice.
+                if spins < INIT_SPIN_LIMIT {
+                    spins += 1;
+                    std::hint::spin_loop();
+                } else {
+                    std::thread::yield_now();
+                }
                 continue;
             }
 
@@ -209,7 +871,14 @@ impl<K, V, S> Map<K, V, S> {
                     };
                     table = Shared::boxed(ReadOnly::new(), &self.collector);
                     self.read.store(table, Ordering::SeqCst);
-                    let m = Shared::boxed(HashMap::new(), &self.collector);
+                    // new keys are added to the dirty map, so that is where a requested capacity
+                    // goes; the empty read-only map is replaced by the first insert anyway.
+                    let m = if flag > 0 {
+                        HashMap::with_capacity(n)
+                    } else {
+                        HashMap::new()
+                    };
+                    let m = Shared::boxed(m, &self.collector);
                     self.dirty.store(m, Ordering::SeqCst);
                     flag = load_factor!(n as isize)
                 }
@@ -225,263 +894,218 @@ impl<K, V, S> Map<K, V, S>
         K: Clone + Hash + Ord,
         S: BuildHasher,
 {
-    #[inline]
-    fn hash<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
-        let mut h = self.build_hasher.build_hasher();
-        key.hash(&mut h);
-        h.finish()
-    }
-
-    /// Returns the number of entries in the map.
+    /// Creates a map holding the entries of a [`std::collections::HashMap`].
+    ///
+    /// This is the bridge from a single-threaded build phase to concurrent use: the entries are
+    /// moved into a table sized for them and published as the read-only map in one step, as if
+    /// they had been inserted and promoted, without a guard or the lock per entry. Reads of the
+    /// loaded keys never take the lock. [`snapshot`](Self::snapshot) goes the other way.
     ///
     /// # Examples
     ///
     /// ```
+    /// use std::collections::HashMap;
     /// use syncmap::map::Map;
     ///
-    /// let map = Map::new();
+    /// let mut dataset = HashMap::new();
+    /// for i in 0..3 {
+    ///     dataset.insert(i, i * 10);
+    /// }
+    /// let map: Map<_, _> = Map::from_std(dataset);
     /// let guard = map.guard();
-    /// map.insert(1, "a",&guard);
-    /// map.insert(2, "b",&guard);
-    /// assert!(map.len() == 2);
+    /// assert_eq!(map.get(&2, &guard), Some(&20));
+    /// assert_eq!(map.len_read(&guard), 3);
     /// ```
-    pub fn len(&self) -> usize {
-        let guard = self.guard();
-        let map = self.dirty.load(Ordering::SeqCst, &guard);
-        if map.is_null() {
-            return 0;
-        }
-        unsafe { map.deref() }.len()
+    pub fn from_std<H>(map: std::collections::HashMap<K, V, H>) -> Self
+        where
+            S: Default,
+    {
+        Self::from_std_with_hasher(map, S::default())
     }
 
-    /// Returns a reference to the value corresponding to the key.
+    /// Creates a map holding the entries of a [`std::collections::HashMap`], using
+    /// `hash_builder` to hash the keys.
     ///
-    /// The key may be any borrowed form of the map's key type, but
-    /// [`Hash`] and [`Ord`] on the borrowed form *must* match those for
-    /// the key type.
+    /// See [`from_std`](Self::from_std) for details.
     ///
-    /// [`Ord`]: std::cmp::Ord
-    /// [`Hash`]: std::hash::Hash
+    /// # Examples
     ///
-    /// To obtain a `Guard`, use [`HashMap::guard`].
+    /// ```
+    /// use std::collections::HashMap;
+    /// use syncmap::DefaultHashBuilder;
+    /// use syncmap::map::Map;
+    ///
+    /// let dataset: HashMap<_, _> = vec![(1, "a"), (2, "b")].into_iter().collect();
+    /// let map = Map::from_std_with_hasher(dataset, DefaultHashBuilder::default());
+    /// assert_eq!(map.get(&1, &map.guard()), Some(&"a"));
+    /// ```
+    pub fn from_std_with_hasher<H>(map: std::collections::HashMap<K, V, H>, hash_builder: S) -> Self {
+        let this = Self::with_hasher(hash_builder);
+        let len = map.len();
+        let mut m = HashMap::with_capacity(len);
+        for (key, value) in map {
+            let entry = Entry::new(Shared::boxed(value, &this.collector));
+            m.insert(key, this.collector.link_boxed(entry));
+        }
+        // Nobody else can see the map yet, so leave it in the state `init_table` followed by a
+        // promotion would: a complete read-only map and no dirty map.
+        let read = Shared::boxed(ReadOnly { m, amended: false }, &this.collector);
+        this.read.store(read, Ordering::SeqCst);
+        this.flag_ctl.store(load_factor!(len.clamp(1, isize::MAX as usize) as isize), Ordering::SeqCst);
+        this.live.store(len, Ordering::SeqCst);
+        this
+    }
+
+    /// Consumes the map and moves its live key-value pairs into an immutable [`FrozenMap`]
+    /// behind an [`Arc`].
+    ///
+    /// This is the end of a build-then-serve lifecycle: with no writers left, the frozen map
+    /// drops the read-only and dirty tables, the lock and the collector, and lookups through it
+    /// take no guard and cost what they would on a plain `HashMap`. Keys and values are moved,
+    /// not cloned. The frozen map hashes with a clone of this map's hasher. See the
+    /// [`frozen`](crate::frozen) module.
     ///
     /// # Examples
     ///
     /// ```
-    ///
     /// use syncmap::map::Map;
+    ///
     /// let map = Map::new();
     /// let guard = map.guard();
-    /// map.insert(1,"a",&guard);
-    /// assert_eq!(map.get(&1,&guard), Some(&"a"));
-    /// assert_eq!(map.get(&2,&guard), None);
+    /// map.insert(1, "a", &guard);
+    /// map.insert(2, "b", &guard);
+    /// map.remove(&1, &guard);
+    /// drop(guard);
+    ///
+    /// let frozen = map.freeze_into_arc();
+    /// assert_eq!(frozen.get(&1), None);
+    /// assert_eq!(frozen[&2], "b");
+    /// assert_eq!(frozen.len(), 1);
     /// ```
-    #[inline]
-    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g V>
+    pub fn freeze_into_arc(self) -> Arc<FrozenMap<K, V, S>>
         where
-            K: Borrow<Q>,
-            Q: ?Sized + Hash + Ord,
+            S: Clone,
     {
-        self.check_guard(guard);
+        // safety: we own the map, so no guard of it can still be active.
+        let guard = unsafe { Guard::unprotected() };
+        let read = self.read.swap(Shared::null(), Ordering::SeqCst, &guard);
+        let dirty = self.dirty.swap(Shared::null(), Ordering::SeqCst, &guard);
+        // safety: as above, nobody else can be accessing the tables, and taking them out of the
+        // map means its `Drop` won't free them again.
+        let read = (!read.is_null()).then(|| Linked::into_inner(*unsafe { read.into_box() }).m);
+        let dirty = (!dirty.is_null()).then(|| Linked::into_inner(*unsafe { dirty.into_box() }));
 
-        let read = self.read.load(Ordering::SeqCst, guard);
-        if read.is_null() {
-            return None;
-        }
-        let r = unsafe { read.deref() };
-        let mut e = r.m.get(key);
-        if e.is_none() && r.amended {
-            let lock = self.lock.lock();
-            let read = self.read.load(Ordering::SeqCst, guard);
-            let r = unsafe { read.deref() };
-            e = r.m.get(key);
-            if e.is_none() && r.amended {
-                let dirty = self.dirty.load(Ordering::SeqCst, guard);
-                if dirty.is_null() {
-                    drop(lock);
-                    return None;
-                }
-                e = unsafe { dirty.deref() }.get(key);
-                self.miss_locked(guard);
+        // The dirty map, if there is one, holds every live entry: the read-only entries left out
+        // of it were expunged. Otherwise the read-only map is complete.
+        let (table, rest) = match dirty {
+            Some(dirty) => (dirty, read),
+            None => (read.unwrap_or_default(), None),
+        };
+        let mut entries: HashSet<EntryPtr<V>> = rest.into_iter().flat_map(HashMap::into_values).collect();
+        let mut map = HashMap::with_capacity_and_hasher(table.len(), self.build_hasher.clone());
+        for (key, e) in table {
+            entries.insert(e);
+            // safety: entries are only freed below, once every table is done with them.
+            let entry = unsafe { &*e };
+            if let EntryState::Live(_) = entry.state(&guard) {
+                let value = entry.p.swap(Shared::null(), Ordering::SeqCst, &guard);
+                // safety: the value was only referenced by the entry, which no longer holds it.
+                map.insert(key, Linked::into_inner(*unsafe { value.into_box() }));
             }
-            drop(lock)
         }
-        if e.is_none() {
-            return None;
+        for e in entries {
+            // safety: every entry was referenced only by the tables we took, and values moved out
+            // above were unlinked first, so dropping the entry won't drop them again.
+            drop(unsafe { Box::from_raw(e) });
         }
-
-        /*  let v = unsafe { Box::from_raw(e.unwrap().as_mut().unwrap()) };
-          let p = v.p.load(Ordering::SeqCst, &guard);
-          if p.is_null() {
-              return None;
-          }
-          if let Some(p) = unsafe {p.as_ref()} {
-              let v = &**p;
-              return Some(v)
-          }*/
-        unsafe { e.unwrap().as_ref().unwrap().load(guard) }
+        Arc::new(FrozenMap::new(map))
     }
 
-
-    fn miss_locked<'g>(&'g self, guard: &'g Guard) {
-        let miss = self.misses.fetch_add(1, Ordering::SeqCst);
-
-        let dirty = self.dirty.load(Ordering::SeqCst, guard);
-        if dirty.is_null() {
-            return;
-        }
-        if miss < unsafe { dirty.deref() }.len() {
-            return;
-        }
-        let mut map = HashMap::new();
-
-        for (key, value) in unsafe { dirty.deref() }.deref() {
-            map.insert(key.clone(), *value);
-        }
-        let read_only_map = Shared::boxed(ReadOnly {
-            m: map,
-            amended: false,
-        }, &self.collector);
-        self.read.store(read_only_map, Ordering::SeqCst);
-        let old_map = self.dirty.load(Ordering::SeqCst, guard);
-        if !old_map.is_null() {
-            self.dirty.compare_exchange(old_map, Shared::null(), Ordering::AcqRel, Ordering::Acquire, guard);
-        }
-        self.misses.store(0, Ordering::SeqCst);
+    #[inline]
+    fn hash<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
+        self.build_hasher.hash_one(key)
     }
-}
 
-impl<K, V, S> Map<K, V, S>
-    where
-        K: Sync + Send + Clone + Hash + Ord,
-        V: Sync + Send,
-        S: BuildHasher,
-{
-    /// Inserts a key-value pair into the map.
+    /// Returns the number of entries in the map.
     ///
-    /// If the map did not have this key present, [`None`] is returned.
+    /// This is O(1): the map keeps a count of its live entries, updated whenever a value fills
+    /// an empty slot or is removed. Under concurrent modification the result is a snapshot that
+    /// may already be stale by the time it is returned.
     ///
-    /// If the map did have this key present, the value is updated, and the old
-    /// value is returned. The key is left unchanged. See the [std-collections
-    /// documentation] for more.
+    /// # Examples
     ///
-    /// [`None`]: std::option::Option::None
-    /// [std-collections documentation]: https://doc.rust-lang.org/std/collections/index.html#insert-and-complex-keys
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a",&guard);
+    /// map.insert(2, "b",&guard);
+    /// assert!(map.len() == 2);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.live_len()
+    }
+
+    /// Returns the number of live entries in the read-only map, which can be loaded without
+    /// taking the lock.
+    ///
+    /// This only walks the read-only map and does not take the lock.
     ///
     /// # Examples
     ///
     /// ```
-    ///
     /// use syncmap::map::Map;
+    ///
     /// let map = Map::new();
     /// let guard = map.guard();
-    /// map.insert(1,1,&guard)
-    pub fn insert<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) {
+    /// map.insert(1, "a", &guard);
+    /// // new keys go to the dirty map until it is promoted
+    /// assert_eq!(map.len_read(&guard), 0);
+    /// map.iter(&guard).count();
+    /// assert_eq!(map.len_read(&guard), 1);
+    /// ```
+    pub fn len_read(&self, guard: &Guard<'_>) -> usize {
         self.check_guard(guard);
-        self.put(key, value, false, guard)
-    }
-
-    fn put<'g>(
-        &'g self,
-        mut key: K,
-        value: V,
-        no_replacement: bool,
-        guard: &'g Guard<'_>,
-    ) {
-        let mut table = self.read.load(Ordering::SeqCst, guard);
-        let entry_value = Shared::boxed(value, &self.collector);
-        loop {
-            if table.is_null() {
-                table = self.init_table(guard);
-                continue;
-            }
-
-            let read = unsafe { table.deref() };
-
-
-            if let Some(v) = read.m.get(&key) {
-                if unsafe { v.as_ref().unwrap() }.try_store(entry_value, guard) {
-                    return;
-                }
-            }
-
-            let lock = self.lock.lock();
-            // TODO need to load readonlu again
-            match read.m.get(&key) {
-                Some(e) => {
-                    if unsafe { e.as_ref().unwrap() }.unexpunge_locked(guard) {
-                        // The entry was previously expunged, which implies that there is a
-                        // non-nil dirty map and this entry is not in it.
-                        let mut table = self.read.load(Ordering::SeqCst, guard);
-                        unsafe {
-                            let read = table.as_ptr();
-                            let read = read.as_mut().unwrap();
-                            read
-                        }.m.insert(key.clone(), *e);
-                    }
-                    unsafe { e.as_ref().unwrap() }.store_locked(entry_value, guard);
-                }
-                None => {
-                    let dirty = self.dirty.load(Ordering::SeqCst, guard);
-                    if dirty.is_null() {
-                        table = self.read.load(Ordering::SeqCst, guard);
-                        let m = Shared::boxed(HashMap::new(), &self.collector);
-                        self.dirty.store(m, Ordering::SeqCst);
-                        drop(lock);
-                        continue;
-                    }
-                    // TODO: check the dirty is null here
-                    let d = unsafe { dirty.deref() };
-                    if !d.is_empty() {
-                        if let Some(e) = d.get(&key) {
-                            unsafe { e.as_ref() }.unwrap().store_locked(entry_value, guard);
-                            drop(lock);
-                            break;
-                        }
-                    }
-
-                    if !read.amended {
-                        // We're adding the first new key to the dirty map.
-                        // Make sure it is allocated and mark the read-only map as incomplete.
-                        self.dirty_locked(key, entry_value, guard);
-                        let shard = self.read.load(Ordering::SeqCst, guard);
-                        let mut map = HashMap::new();
-                        for (key, value) in &unsafe { shard.deref() }.m {
-                            map.insert(key.clone(), *value);
-                        }
-                        let shard_map = Shared::boxed(ReadOnly {
-                            m: map,
-                            amended: true,
-                        }, &self.collector);
-                        self.read.store(shard_map, Ordering::SeqCst);
-                        drop(lock);
-                        break;
-                    }
-                    let dirty2 = self.dirty.load(Ordering::SeqCst, guard);
-                    if dirty != dirty2 {
-                        continue;
-                    }
-                    //save entry;
-                    let mut entry = Entry {
-                        p: Atomic::null(),
-                        expunged: Atomic::null(),
-                    };
-
-                    entry.p.store(entry_value, Ordering::SeqCst);
-                    unsafe {
-                        let dirty2 = dirty2.as_ptr();
-                        dirty2.as_mut().unwrap().insert(key.clone(), Box::into_raw(Box::new(entry)));
-                    };
-                }
-            }
-
-            drop(lock);
-            break;
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return 0;
         }
+        unsafe { read.deref() }.m.values()
+            .filter(|e| unsafe { e.as_ref().unwrap() }.load(guard).is_some())
+            .count()
     }
 
+    /// Returns the number of entries in the dirty map, or 0 if there is none.
+    ///
+    /// Like the dirty map itself, this includes the entries it shares with the read-only map and
+    /// entries whose value has been removed. A dirty map that keeps growing between promotions
+    /// points at a write-heavy workload.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.len_dirty(&guard), 1);
+    /// ```
+    pub fn len_dirty(&self, guard: &Guard<'_>) -> usize {
+        self.check_guard(guard);
+        let lock = self.lock();
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        let len = if dirty.is_null() {
+            0
+        } else {
+            unsafe { dirty.deref() }.len()
+        };
+        drop(lock);
+        len
+    }
 
-    /// Removes a key-value pair from the map, and returns the removed value (if any).
+    /// Returns a reference to the value corresponding to the key.
     ///
     /// The key may be any borrowed form of the map's key type, but
     /// [`Hash`] and [`Ord`] on the borrowed form *must* match those for
@@ -490,198 +1114,5488 @@ impl<K, V, S> Map<K, V, S>
     /// [`Ord`]: std::cmp::Ord
     /// [`Hash`]: std::hash::Hash
     ///
+    /// To obtain a `Guard`, use [`HashMap::guard`].
+    ///
     /// # Examples
     ///
     /// ```
     ///
     /// use syncmap::map::Map;
     /// let map = Map::new();
-    /// map.insert(1, "a",&map.guard());
-    /// assert_eq!(map.remove(&1,&map.guard()), Some(&"a"));
-    /// assert_eq!(map.remove(&1,&map.guard()), None);
+    /// let guard = map.guard();
+    /// map.insert(1,"a",&guard);
+    /// assert_eq!(map.get(&1,&guard), Some(&"a"));
+    /// assert_eq!(map.get(&2,&guard), None);
     /// ```
-    pub fn remove<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g V>
+    #[inline]
+    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g V>
         where
             K: Borrow<Q>,
             Q: ?Sized + Hash + Ord,
     {
-        // NOTE: _technically_, this method shouldn't require the thread-safety bounds, but a) that
-        // would require special-casing replace_node for when new_value.is_none(), and b) it's sort
-        // of useless to call remove on a collection that you know you can never insert into.
         self.check_guard(guard);
+        // safety: we just checked that the guard belongs to this map.
+        unsafe { self.get_unchecked(key, guard) }
+    }
 
-        let mut read = self.read.load(Ordering::SeqCst, guard);
-        loop {
-            if read.is_null() {
-                break None;
-            }
+    /// Returns a raw pointer to the value corresponding to the key, for handing across an FFI
+    /// boundary.
+    ///
+    /// The pointer is the address of the same value [`get`](Self::get) returns a reference to.
+    /// It is not tied to any lifetime, which is what makes it usable from foreign code, and also
+    /// why this function is `unsafe`.
+    ///
+    /// # Safety
+    ///
+    /// The pointer may only be dereferenced while `guard` is held. Once the value is replaced or
+    /// removed, it is freed as soon as no guard protects it anymore, so dereferencing the pointer
+    /// after dropping `guard` is a use-after-free. The value must not be mutated through the
+    /// pointer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, 42u32, &guard);
+    /// let ptr = unsafe { map.get_ptr(&1, &guard) }.unwrap();
+    /// // safety: `guard` is still held
+    /// assert_eq!(unsafe { *ptr }, 42);
+    /// drop(guard);
+    /// ```
+    pub unsafe fn get_ptr<Q>(&self, key: &Q, guard: &Guard<'_>) -> Option<*const V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.get(key, guard).map(|v| v as *const V)
+    }
 
+    /// Returns a reference to the value corresponding to the key, without checking that `guard`
+    /// was obtained from this map.
+    ///
+    /// This behaves exactly like [`get`](Self::get), minus the collector comparison that `get`
+    /// performs on every call. It is meant for hot read loops where the guard is known to be
+    /// right.
+    ///
+    /// # Safety
+    ///
+    /// `guard` must have been obtained from this map (through [`guard`](Self::guard) or
+    /// [`pin`](Self::pin)). Using a guard from any other collector does not protect the values
+    /// that are read, which can then be freed while still in use.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// // safety: the guard comes from `map`
+    /// assert_eq!(unsafe { map.get_unchecked(&1, &guard) }, Some(&"a"));
+    /// ```
+    #[inline]
+    pub unsafe fn get_unchecked<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        let mut e = r.m.get(key).copied();
+        // An entry in the read-only map is authoritative even while it is removed, amended or
+        // not: storing the key again revives that same entry rather than adding a new one to the
+        // dirty map, so only keys missing from the read-only map need the lock.
+        let locked = e.is_none() && r.amended;
+        if locked {
+            let lock = self.lock();
+            #[cfg(feature = "trace")]
+            self.trace(TraceEvent::LockAcquired(Operation::Get));
+            let read = self.read.load(Ordering::SeqCst, guard);
             let r = unsafe { read.deref() };
-            let mut remove_el: Option<*mut Entry<V>> = None;
-            let mut e = r.m.get(&key);
+            e = r.m.get(key).copied();
             if e.is_none() && r.amended {
-                let lock = self.lock.lock();
-
-                e = r.m.get(&key);
-                if e.is_none() && r.amended {
-                    let dirty = self.dirty.load(Ordering::SeqCst, guard);
-                    if dirty.is_null() {
-                        read = self.read.load(Ordering::SeqCst, guard);
-                        drop(lock);
-                        continue;
-                    }
-                    e = unsafe { dirty.deref() }.get(&key);
-
-                    let dirty = unsafe { dirty.as_ptr() };
-
-                    remove_el = unsafe { dirty.as_mut().unwrap().remove(&key) };
-
-                    self.miss_locked(guard);
+                let dirty = self.dirty.load(Ordering::SeqCst, guard);
+                if dirty.is_null() {
+                    #[cfg(feature = "trace")]
+                    self.trace(TraceEvent::LockReleased(Operation::Get));
+                    drop(lock);
+                    self.get_misses.fetch_add(1, Ordering::Relaxed);
+                    return None;
                 }
-                drop(lock)
-            } else {
-                if let Some(e) = e {
-                    return unsafe { e.as_mut().unwrap().remove(guard) };
+                e = unsafe { dirty.deref() }.get(key).copied();
+                // Only lookups that the dirty map could answer count as misses. Keys that are
+                // absent from both maps would otherwise trigger promotions that just copy the
+                // same dirty map again.
+                if e.is_some() {
+                    self.miss_locked(guard);
                 }
             }
-
-            if remove_el.is_some() {
-                let data = unsafe { remove_el.unwrap().as_mut().unwrap().remove(guard) };
-                break data;
-            }
-            break None;
-        }
-    }
-
-    fn dirty_locked<'g>(&'g self, key: K, entry_value: Shared<V>, guard: &Guard<'_>) {
-        let dirty = self.dirty.load(Ordering::SeqCst, guard);
-        if dirty.is_null() {
-            return;
-        }
-        let read = self.read.load(Ordering::SeqCst, guard);
-        let mut map = HashMap::with_capacity(unsafe { read.deref() }.m.len());
-        for (key, value) in &unsafe { read.deref() }.m {
-            if !unsafe { value.as_ref().unwrap() }.try_unexpunge_locked(guard) {
-                map.insert(key.clone(), *value);
-            }
+            #[cfg(feature = "trace")]
+            self.trace(TraceEvent::LockReleased(Operation::Get));
+            drop(lock)
         }
-        let entry = Entry {
-            p: Atomic::null(),
-            expunged: Atomic::null(),
+        let value = match e {
+            Some(e) => unsafe { e.as_ref().unwrap() }.load(guard),
+            None => None,
         };
+        let counter = match value {
+            None => &self.get_misses,
+            Some(_) if locked => &self.dirty_hits,
+            Some(_) => &self.read_hits,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
 
-        entry.p.store(entry_value, Ordering::SeqCst);
-
-        map.insert(key, Box::into_raw(Box::new(entry)));
-        self.dirty.store(Shared::boxed(map, &self.collector), Ordering::SeqCst)
+        /*  let v = unsafe { Box::from_raw(e.unwrap().as_mut().unwrap()) };
+          let p = v.p.load(Ordering::SeqCst, &guard);
+          if p.is_null() {
+              return None;
+          }
+          if let Some(p) = unsafe {p.as_ref()} {
+              let v = &**p;
+              return Some(v)
+          }*/
     }
-}
 
-impl<K, V, S> Map<K, V, S>
-    where
-        K: Clone + Ord,
-{
-    /// Clears the map, removing all key-value pairs.
+
+    /// Looks up the value for the key without ever blocking on the map's lock.
+    ///
+    /// Keys in the read-only map are answered as by [`get`](Self::get). A key that isn't there
+    /// may still be in the dirty map, which is only readable under the lock: the lock is taken if
+    /// it is free, and otherwise [`Poll3::WouldBlock`] is returned, so that the caller can retry
+    /// later or accept the uncertainty. This suits schedulers and executors that must never
+    /// block.
     ///
     /// # Examples
     ///
     /// ```
+    /// use syncmap::map::{Map, Poll3};
     ///
-    /// use syncmap::map::Map;
     /// let map = Map::new();
     /// let guard = map.guard();
-    /// map.insert(1, "a",&guard);
-    /// map.clear(&guard);
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.poll_get(&1, &guard), Poll3::Found(&"a"));
+    /// assert_eq!(map.poll_get(&2, &guard), Poll3::NotFound);
     /// ```
-    pub fn clear<'g>(&'g self, guard: &'g Guard<'_>) {
-        let lock = self.lock.lock();
-
-        self.dirty.store(Shared::boxed(HashMap::new(), &self.collector), Ordering::SeqCst);
+    pub fn poll_get<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Poll3<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.check_guard(guard);
         let read = self.read.load(Ordering::SeqCst, guard);
-        self.read.store(Shared::boxed(ReadOnly::new(), &self.collector), Ordering::SeqCst);
-        let sc = self.misses.load(Ordering::SeqCst);
-        self.misses.compare_exchange(sc, 0, Ordering::AcqRel, Ordering::Acquire).expect("change miess");
+        if read.is_null() {
+            return Poll3::NotFound;
+        }
+        let r = unsafe { read.deref() };
+        if let Some(e) = r.m.get(key) {
+            return unsafe { e.as_ref().unwrap() }.load(guard).map_or(Poll3::NotFound, Poll3::Found);
+        }
+        if !r.amended {
+            return Poll3::NotFound;
+        }
 
-        drop(lock);
+        let lock = match self.lock.try_lock() {
+            Ok(lock) => lock,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Poll3::WouldBlock,
+        };
+        let value = self.entry_locked(key, guard).and_then(|e| e.load(guard));
+        if value.is_some() {
+            // count the miss like `get` does, so that polling alone still promotes the dirty map
+            self.miss_locked(guard);
+        }
+        drop(lock);
+        value.map_or(Poll3::NotFound, Poll3::Found)
+    }
+
+    /// Returns the values for two keys at once.
+    ///
+    /// This is [`get_disjoint`](Self::get_disjoint) for two keys: both lookups go against the
+    /// same read-only map, and the lock is taken at most once even if both keys have to be
+    /// looked up in the dirty map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// map.insert(2, "b", &guard);
+    /// assert_eq!(map.get2(&1, &3, &guard), (Some(&"a"), None));
+    /// ```
+    pub fn get2<'g, Q>(&'g self, k1: &Q, k2: &Q, guard: &'g Guard<'_>) -> (Option<&'g V>, Option<&'g V>)
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let [v1, v2] = self.get_disjoint([k1, k2], guard);
+        (v1, v2)
+    }
+
+    /// Returns the values for `N` keys at once, in the same order as `keys`.
+    ///
+    /// All keys are first looked up in the same read-only map, without locking. Keys that
+    /// aren't there are then looked up under a single acquisition of the lock, instead of one
+    /// per key. Each key is looked up independently, so repeating a key is allowed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// map.insert(2, "b", &guard);
+    /// assert_eq!(map.get_disjoint([&2, &3, &1], &guard), [Some(&"b"), None, Some(&"a")]);
+    /// ```
+    pub fn get_disjoint<'g, Q, const N: usize>(&'g self, keys: [&Q; N], guard: &'g Guard<'_>) -> [Option<&'g V>; N]
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.check_guard(guard);
+        let mut values = [None; N];
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return values;
+        }
+        let r = unsafe { read.deref() };
+        let mut found = [false; N];
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(e) = r.m.get(*key) {
+                found[i] = true;
+                values[i] = unsafe { e.as_ref().unwrap() }.load(guard);
+            }
+        }
+        if !r.amended || found.iter().all(|&f| f) {
+            return values;
+        }
+
+        let lock = self.lock();
+        for (i, key) in keys.iter().enumerate() {
+            if found[i] {
+                continue;
+            }
+            // a miss may promote the dirty map, so reload for every key
+            let read = self.read.load(Ordering::SeqCst, guard);
+            let r = unsafe { read.deref() };
+            let mut e = r.m.get(*key).copied();
+            if e.is_none() && r.amended {
+                let dirty = self.dirty.load(Ordering::SeqCst, guard);
+                if !dirty.is_null() {
+                    e = unsafe { dirty.deref() }.get(*key).copied();
+                    if e.is_some() {
+                        self.miss_locked(guard);
+                    }
+                }
+            }
+            values[i] = e.and_then(|e| unsafe { e.as_ref().unwrap() }.load(guard));
+        }
+        drop(lock);
+        values
+    }
+
+    /// Returns a clone of the value corresponding to the key, or `V::default()` if the key is
+    /// absent.
+    ///
+    /// Unlike [`get_or_insert`](Self::get_or_insert), this never inserts anything: a missing key
+    /// reads as the default and stays missing. That suits maps of counters, where absent keys
+    /// should read as zero without filling the map with zero entries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("hits", 3, &guard);
+    /// assert_eq!(map.get_or_default(&"hits", &guard), 3);
+    /// assert_eq!(map.get_or_default(&"misses", &guard), 0);
+    /// assert!(!map.contains_key(&"misses", &guard));
+    /// ```
+    pub fn get_or_default<Q>(&self, key: &Q, guard: &Guard<'_>) -> V
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            V: Default + Clone,
+    {
+        self.get(key, guard).cloned().unwrap_or_default()
+    }
+
+    /// Returns a reference to the value corresponding to the key, or the reference returned by
+    /// `f` if the key is absent.
+    ///
+    /// Like [`get_or_default`](Self::get_or_default), and unlike
+    /// [`get_or_insert_with`](Self::get_or_insert_with), this never modifies the map. It suits
+    /// fallbacks to a shared default that lives elsewhere, such as a `static`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// static DEFAULT_LIMIT: u32 = 100;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("alice", 500, &guard);
+    /// assert_eq!(map.get_or_else(&"alice", || &DEFAULT_LIMIT, &guard), &500);
+    /// assert_eq!(map.get_or_else(&"bob", || &DEFAULT_LIMIT, &guard), &100);
+    /// assert!(!map.contains_key(&"bob", &guard));
+    /// ```
+    pub fn get_or_else<'g, Q, F>(&'g self, key: &Q, f: F, guard: &'g Guard<'_>) -> &'g V
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            F: FnOnce() -> &'g V,
+    {
+        self.get(key, guard).unwrap_or_else(f)
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// [`Hash`] and [`Ord`] on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// When the read-only map is complete (no keys are waiting in the dirty map), it answers
+    /// every lookup on its own, hits and misses alike, without taking the lock.
+    ///
+    /// [`Ord`]: std::cmp::Ord
+    /// [`Hash`]: std::hash::Hash
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// assert!(map.contains_key(&1, &guard));
+    /// assert!(!map.contains_key(&2, &guard));
+    /// ```
+    pub fn contains_key<Q>(&self, key: &Q, guard: &Guard<'_>) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.check_guard(guard);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return false;
+        }
+        let r = unsafe { read.deref() };
+        if let Some(e) = r.m.get(key) {
+            return unsafe { e.as_ref().unwrap() }.load(guard).is_some();
+        }
+        if !r.amended {
+            // the read-only map holds every key, so this is a definite miss
+            return false;
+        }
+        self.get(key, guard).is_some()
+    }
+
+    /// Returns a reference to the key as it is stored in the map, without loading its value.
+    ///
+    /// This is useful when the key type's equality ignores part of the key (for example
+    /// case-insensitive strings): looking up with any equal form returns the stored, canonical
+    /// one.
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// [`Hash`] and [`Ord`] on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// Keys are always handed out from the read-only map, which is never modified once it has
+    /// been published. If the key is only in the dirty map, the dirty map is promoted first.
+    ///
+    /// [`Ord`]: std::cmp::Ord
+    /// [`Hash`]: std::hash::Hash
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(String::from("a"), 1, &guard);
+    /// assert_eq!(map.get_key("a", &guard), Some(&String::from("a")));
+    /// assert_eq!(map.get_key("b", &guard), None);
+    /// ```
+    pub fn get_key<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g K>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.check_guard(guard);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        if let Some((k, e)) = r.m.get_key_value(key) {
+            return unsafe { e.as_ref().unwrap() }.load(guard).map(|_| k);
+        }
+        if !r.amended {
+            return None;
+        }
+
+        let lock = self.lock();
+        // the dirty map is modified in place, so references into it could dangle; promote it so
+        // the key can be returned from an immutable read-only map instead.
+        if self.entry_locked(key, guard)?.load(guard).is_some() {
+            self.promote_locked(guard);
+        }
+        drop(lock);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let (k, e) = unsafe { read.deref() }.m.get_key_value(key)?;
+        unsafe { e.as_ref().unwrap() }.load(guard).map(|_| k)
+    }
+
+    /// Returns the key as it is stored in the map together with its value, from a single lookup.
+    ///
+    /// Like [`get_key`](Self::get_key), a key that is only in the dirty map is promoted to the
+    /// read-only map first, so that the returned key can't dangle.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(String::from("a"), 1, &guard);
+    /// assert_eq!(map.get_key_value("a", &guard), Some((&String::from("a"), &1)));
+    /// assert_eq!(map.get_key_value("b", &guard), None);
+    /// ```
+    pub fn get_key_value<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<(&'g K, &'g V)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.check_guard(guard);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        if let Some((k, e)) = r.m.get_key_value(key) {
+            return unsafe { e.as_ref().unwrap() }.load(guard).map(|v| (k, v));
+        }
+        if !r.amended {
+            return None;
+        }
+
+        let lock = self.lock();
+        if self.entry_locked(key, guard)?.load(guard).is_some() {
+            self.promote_locked(guard);
+        }
+        drop(lock);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let (k, e) = unsafe { read.deref() }.m.get_key_value(key)?;
+        unsafe { e.as_ref().unwrap() }.load(guard).map(|v| (k, v))
+    }
+
+    /// Finds the entry for `key` in either the read-only or the dirty map, whether or not it
+    /// currently holds a value.
+    ///
+    /// Must be called while holding `self.lock`.
+    fn entry_locked<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g Entry<V>>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        let e = match r.m.get(key) {
+            Some(e) => *e,
+            None => {
+                let dirty = self.dirty.load(Ordering::SeqCst, guard);
+                if !r.amended || dirty.is_null() {
+                    return None;
+                }
+                *unsafe { dirty.deref() }.get(key)?
+            }
+        };
+        // safety: entries stay allocated while they are reachable from either map, and are only
+        // retired through the collector, so this reference is valid for as long as the guard.
+        Some(unsafe { &**e.as_ref().unwrap() })
+    }
+
+    fn miss_locked<'g>(&'g self, guard: &'g Guard) {
+        let miss = self.misses.fetch_add(1, Ordering::SeqCst);
+
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        if dirty.is_null() {
+            return;
+        }
+        #[cfg(feature = "metrics")]
+        metrics::recorder().register_counter(&self.metric_keys.misses).increment(1);
+        // Promoting copies the whole dirty map, and a steady stream of new keys keeps amending
+        // the freshly promoted map. Unless the dirty map has grown substantially since the last
+        // promotion, wait for proportionally more misses, so that such workloads don't copy
+        // the map over and over.
+        let len = unsafe { dirty.deref() }.len();
+        let grown = len >= self.last_promoted_len.load(Ordering::SeqCst).saturating_mul(PROMOTION_GROWTH);
+        let threshold = if grown { len } else { len.saturating_mul(PROMOTION_BACKOFF) };
+        if miss < threshold {
+            return;
+        }
+        self.promote_locked(guard);
+        #[cfg(feature = "trace")]
+        self.trace(TraceEvent::Promoted);
+    }
+
+    /// Reports the current length of the map to the `metrics` recorder.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn record_len(&self) {
+        metrics::recorder().register_gauge(&self.metric_keys.len).set(self.len() as f64);
+    }
+
+    /// Promotes the dirty map to be the new read-only map, so that every live key can be served
+    /// without taking the lock.
+    ///
+    /// This is a no-op unless the read-only map is missing keys that are only in the dirty map.
+    /// Must be called while holding `self.lock`.
+    fn promote_locked<'g>(&'g self, guard: &'g Guard<'_>) {
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() || !unsafe { read.deref() }.amended {
+            return;
+        }
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        if dirty.is_null() {
+            return;
+        }
+        // keep any room that was reserved in the read-only map
+        let capacity = unsafe { dirty.deref() }.len().max(unsafe { read.deref() }.m.capacity());
+        let mut map = HashMap::with_capacity(capacity);
+
+        let old_dirty = if self.keep_dirty_allocation {
+            // only locked code looks into the dirty map, and none of it hands out references to
+            // its keys, so they can be moved out, leaving the allocation behind for the next
+            // write phase
+            for (key, value) in unsafe { dirty.as_ptr().as_mut().unwrap() }.drain() {
+                map.insert(key, value);
+            }
+            Shared::null()
+        } else {
+            for (key, value) in unsafe { dirty.deref() }.deref() {
+                map.insert(key.clone(), *value);
+            }
+            dirty
+        };
+        self.last_promoted_len.store(map.len(), Ordering::SeqCst);
+        let read_only_map = Shared::boxed(ReadOnly {
+            m: map,
+            amended: false,
+        }, &self.collector);
+        let old_read = self.read.swap(read_only_map, Ordering::SeqCst, guard);
+        if !old_dirty.is_null() {
+            self.dirty.store(Shared::null(), Ordering::SeqCst);
+        }
+        // safety: both tables have been unlinked above, so only threads that are still
+        // holding a guard can observe them; they will be freed once those guards are gone.
+        // Expunged entries were left out of the dirty map, so the old read-only map was the last
+        // map referencing them, and they can't be revived without the lock, which we hold.
+        unsafe {
+            for e in old_read.deref().m.values() {
+                if matches!(e.as_ref().unwrap().state(guard), EntryState::Expunged) {
+                    guard.retire(*e, seize::reclaim::boxed::<Entry<V>>);
+                }
+            }
+            guard.retire_shared(old_read);
+            guard.retire_shared(old_dirty);
+        }
+        self.misses.store(0, Ordering::SeqCst);
+        // the dirty map was built without the old tombstones
+        self.tombstones.store(0, Ordering::SeqCst);
+        #[cfg(feature = "metrics")]
+        metrics::recorder().register_counter(&self.metric_keys.promotions).increment(1);
+    }
+
+    /// Reserves capacity for at least `additional` more keys in the dirty map, where new keys are
+    /// added until they are promoted.
+    ///
+    /// Use this ahead of a write-heavy phase to avoid growing the dirty map one reallocation at a
+    /// time. The reservation is kept when the dirty map is rebuilt for the next new key, and
+    /// carries over into the read-only map when the dirty map is promoted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.reserve_dirty(1000, &guard);
+    /// for i in 0..1000 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// ```
+    pub fn reserve_dirty(&self, additional: usize, guard: &Guard<'_>) {
+        self.check_guard(guard);
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        let lock = self.lock();
+        let reserved = self.try_reserve_dirty_locked(additional, guard);
+        drop(lock);
+        match reserved {
+            Ok(()) => {}
+            Err(TryReserveError::CapacityOverflow) => panic!("capacity overflow"),
+            Err(TryReserveError::AllocError { layout }) => std::alloc::handle_alloc_error(layout),
+        }
+    }
+
+    /// Tries to reserve capacity for at least `additional` more keys in the dirty map.
+    ///
+    /// This is the fallible version of [`reserve_dirty`](Self::reserve_dirty): if the capacity
+    /// overflows or the allocator reports a failure, an error is returned instead of panicking
+    /// or aborting, and the map is left as it was. Reserving ahead of time is also what makes
+    /// the following inserts safe from allocation failures in the dirty map, since the
+    /// reservation is kept when the dirty map is rebuilt.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.try_reserve(1000, &guard).expect("out of memory");
+    /// map.insert(1, "a", &guard);
+    /// assert!(map.try_reserve(usize::MAX, &guard).is_err());
+    /// assert_eq!(map.get(&1, &guard), Some(&"a"));
+    /// ```
+    pub fn try_reserve(&self, additional: usize, guard: &Guard<'_>) -> Result<(), TryReserveError> {
+        self.check_guard(guard);
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        let lock = self.lock();
+        let reserved = self.try_reserve_dirty_locked(additional, guard);
+        drop(lock);
+        reserved
+    }
+
+    /// Reserves capacity for `additional` more keys in the dirty map, creating one if needed.
+    ///
+    /// Must be called while holding `self.lock`.
+    fn try_reserve_dirty_locked(&self, additional: usize, guard: &Guard<'_>) -> Result<(), TryReserveError> {
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        if dirty.is_null() {
+            // promoted; the next dirty map starts out as a copy of the read-only map
+            let read = self.read.load(Ordering::SeqCst, guard);
+            let capacity = unsafe { read.deref() }.m.len()
+                .checked_add(additional)
+                .ok_or(TryReserveError::CapacityOverflow)?;
+            let mut map = HashMap::new();
+            map.try_reserve(capacity)?;
+            self.dirty.store(Shared::boxed(map, &self.collector), Ordering::SeqCst);
+        } else {
+            // safety: the dirty map is only ever accessed while holding the lock.
+            unsafe { dirty.as_ptr().as_mut().unwrap() }.try_reserve(additional)?;
+        }
+        Ok(())
+    }
+
+    /// Reserves capacity for at least `additional` more keys in the read-only map, which serves
+    /// lookups without taking the lock.
+    ///
+    /// Use this ahead of a read-heavy phase, so that promoting the keys that are still being
+    /// added doesn't have to grow the read-only map. The read-only map keeps its capacity across
+    /// promotions; [`shrink_to`](Self::shrink_to) releases it again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.reserve_read(1000, &guard);
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.get(&1, &guard), Some(&"a"));
+    /// ```
+    pub fn reserve_read(&self, additional: usize, guard: &Guard<'_>) {
+        self.check_guard(guard);
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        let lock = self.lock();
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let r = unsafe { read.deref() };
+        let capacity = r.m.len() + additional;
+        if r.m.capacity() < capacity {
+            // published read-only maps are never modified, so publish a larger copy instead
+            let mut map = HashMap::with_capacity(capacity);
+            for (key, value) in &r.m {
+                map.insert(key.clone(), *value);
+            }
+            let old_read = self.read.swap(Shared::boxed(ReadOnly {
+                m: map,
+                amended: r.amended,
+            }, &self.collector), Ordering::SeqCst, guard);
+            // safety: the old read-only map is unlinked, and its entries are all still referenced
+            // from the new one.
+            unsafe { guard.retire_shared(old_read) };
+        }
+        drop(lock);
+    }
+
+    /// Moves the map from a write phase to a read phase in one call: every key that is only in
+    /// the dirty map is promoted into the read-only map, and the miss counter starts over.
+    ///
+    /// Afterwards every live key is served without taking the lock, so the first wave of reads
+    /// doesn't have to pay for the lazy promotion that enough misses would otherwise trigger.
+    /// The promoted read-only map is allocated for all keys at once, keeping any larger
+    /// capacity reserved with [`reserve_read`](Self::reserve_read).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..100 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// map.prepare_for_reads(&guard);
+    /// assert_eq!(map.len_read(&guard), 100);
+    /// ```
+    pub fn prepare_for_reads(&self, guard: &Guard<'_>) {
+        self.check_guard(guard);
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            return;
+        }
+
+        let lock = self.lock();
+        self.promote_locked(guard);
+        self.misses.store(0, Ordering::SeqCst);
+        drop(lock);
+    }
+
+    /// Returns an owned copy of every live key-value pair, in arbitrary order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.snapshot(&guard), vec![(1, "a")]);
+    /// ```
+    pub fn snapshot(&self, guard: &Guard<'_>) -> Vec<(K, V)>
+        where
+            V: Clone,
+    {
+        self.iter(guard).map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Returns an owned copy of every live key, in arbitrary order.
+    ///
+    /// This is the key-only counterpart of [`snapshot`](Self::snapshot). It pins its own guard
+    /// and drops it before returning, so the keys can be held for as long as needed, for example
+    /// across a long refresh loop, without holding up reclamation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// map.insert("a", 1, &map.guard());
+    /// map.insert("b", 2, &map.guard());
+    /// let mut keys = map.keys_snapshot();
+    /// keys.sort();
+    /// assert_eq!(keys, vec!["a", "b"]);
+    /// ```
+    pub fn keys_snapshot(&self) -> Vec<K> {
+        let guard = self.guard();
+        self.keys(&guard).cloned().collect()
+    }
+
+    /// Captures the current contents of the map so that they can be put back later with
+    /// [`restore`](Self::restore).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// let checkpoint = map.checkpoint(&guard);
+    /// map.insert(2, "b", &guard);
+    /// map.restore(&checkpoint, &guard);
+    /// assert_eq!(map.get(&2, &guard), None);
+    /// assert_eq!(map.checkpoint(&guard), checkpoint);
+    /// ```
+    pub fn checkpoint(&self, guard: &Guard<'_>) -> Checkpoint<K, V>
+        where
+            V: Clone,
+    {
+        let mut entries = self.snapshot(guard);
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Checkpoint { entries }
+    }
+
+    /// Rebuilds the read-only map without any removed entries, with room for at least
+    /// `min_capacity` entries, and returns the number of entries that were dropped.
+    ///
+    /// The dirty map is promoted first, so afterwards every live key is in the read-only map.
+    /// Must be called while holding `self.lock`.
+    fn compact_locked<'g>(&'g self, min_capacity: usize, guard: &'g Guard<'_>) -> usize {
+        self.promote_locked(guard);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return 0;
+        }
+        self.tombstones.store(0, Ordering::SeqCst);
+        let r = unsafe { read.deref() };
+
+        let mut live = Vec::with_capacity(r.m.len());
+        let mut dead = Vec::new();
+        for (key, e) in &r.m {
+            // expunge empty entries first: writers can store into an empty entry without the
+            // lock, but have to take it to revive an expunged one
+            if unsafe { e.as_ref().unwrap() }.try_expunge_locked(guard) {
+                dead.push(*e);
+            } else {
+                live.push((key, *e));
+            }
+        }
+        if dead.is_empty() && r.m.capacity() <= live.len().max(min_capacity) {
+            return 0;
+        }
+
+        let mut map = HashMap::with_capacity(live.len().max(min_capacity));
+        for (key, e) in live {
+            map.insert(key.clone(), e);
+        }
+        let old_read = self.read.swap(Shared::boxed(ReadOnly {
+            m: map,
+            amended: false,
+        }, &self.collector), Ordering::SeqCst, guard);
+        // safety: after the promotion above the read-only map is the only map referencing these
+        // entries, and both it and the removed entries have now been unlinked. Expunged entries
+        // can't be written to without the lock, which we hold.
+        unsafe {
+            guard.retire_shared(old_read);
+            for e in &dead {
+                guard.retire(*e, seize::reclaim::boxed::<Entry<V>>);
+            }
+        }
+        dead.len()
+    }
+
+    /// Shrinks the capacity of the map as much as possible.
+    ///
+    /// This drops the slots of removed entries and rebuilds the read-only map to fit the live
+    /// entries exactly. Keys that are only in the dirty map are promoted as part of this.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..100 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// for i in 0..90 {
+    ///     map.remove(&i, &guard);
+    /// }
+    /// map.shrink_to_fit(&guard);
+    /// assert_eq!(map.inspect(&guard).removed, 0);
+    /// ```
+    pub fn shrink_to_fit(&self, guard: &Guard<'_>) {
+        self.shrink_to(0, guard);
+    }
+
+    /// Shrinks the capacity of the map with a lower limit.
+    ///
+    /// Like [`shrink_to_fit`](Self::shrink_to_fit), but the rebuilt read-only map keeps room for
+    /// at least `min_capacity` entries, which avoids regrowing a map that is about to be refilled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..100 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// for i in 0..90 {
+    ///     map.remove(&i, &guard);
+    /// }
+    /// map.shrink_to(64, &guard);
+    /// assert_eq!(map.iter(&guard).count(), 10);
+    /// ```
+    pub fn shrink_to(&self, min_capacity: usize, guard: &Guard<'_>) {
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.compact_locked(min_capacity, guard);
+        drop(lock);
+    }
+
+    /// Compacts the read-only map if it holds too many removed entries, and returns how many
+    /// were dropped.
+    ///
+    /// Removing a key leaves its slot behind in the read-only map. Once the ratio of these
+    /// removed entries to live ones exceeds the map's
+    /// [`compaction_threshold`](MapBuilder::compaction_threshold), the read-only map is rebuilt
+    /// without them. The check itself doesn't take the lock, so this is cheap to call
+    /// periodically. Unlike [`shrink_to_fit`](Self::shrink_to_fit), the rebuilt map keeps its
+    /// capacity.
+    ///
+    /// [`remove`](Self::remove) runs this check on its own. Calling it directly is useful after
+    /// removals that happen under the lock, such as those of
+    /// [`with_many_mut`](Self::with_many_mut).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..10 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// map.iter(&guard).count(); // promote all keys into the read-only map
+    /// let remove_all = |values: &mut [Option<i32>]| vec![None; values.len()];
+    /// map.with_many_mut(&[0], remove_all, &guard);
+    /// assert_eq!(map.maybe_compact(&guard), 0);
+    /// map.with_many_mut(&[1, 2, 3, 4, 5], remove_all, &guard);
+    /// assert_eq!(map.maybe_compact(&guard), 6);
+    /// ```
+    pub fn maybe_compact(&self, guard: &Guard<'_>) -> usize {
+        self.check_guard(guard);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return 0;
+        }
+        let r = unsafe { read.deref() };
+        let removed = r.m.values()
+            .filter(|e| unsafe { e.as_ref().unwrap() }.load(guard).is_none())
+            .count();
+        let live = r.m.len() - removed;
+        if removed == 0 || removed as f64 <= live as f64 * self.compaction_threshold {
+            return 0;
+        }
+
+        let lock = self.lock();
+        let capacity = unsafe { self.read.load(Ordering::SeqCst, guard).deref() }.m.capacity();
+        let dropped = self.compact_locked(capacity, guard);
+        drop(lock);
+        dropped
+    }
+
+    /// Compacts the read-only map right away, and returns how many entries were reclaimed.
+    ///
+    /// Unlike [`maybe_compact`](Self::maybe_compact), this doesn't wait for the
+    /// [`compaction_threshold`](MapBuilder::compaction_threshold): the dirty map is promoted, and
+    /// the read-only map is rebuilt without any removed or expunged entries, whose boxes are
+    /// retired. The rebuilt map keeps its capacity. The count includes expunged entries that the
+    /// promotion drops on its own.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..10 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// map.iter(&guard).count(); // promote all keys into the read-only map
+    /// map.remove(&0, &guard);
+    /// assert_eq!(map.compact_now(&guard), 1);
+    /// assert_eq!(map.compact_now(&guard), 0);
+    /// assert_eq!(map.len(), 9);
+    /// ```
+    pub fn compact_now(&self, guard: &Guard<'_>) -> usize {
+        self.check_guard(guard);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return 0;
+        }
+
+        let lock = self.lock();
+        let r = unsafe { self.read.load(Ordering::SeqCst, guard).deref() };
+        // the promotion in compact_locked retires these without reporting them
+        let expunged = if r.amended {
+            r.m.values()
+                .filter(|e| matches!(unsafe { e.as_ref().unwrap() }.state(guard), EntryState::Expunged))
+                .count()
+        } else {
+            0
+        };
+        let capacity = r.m.capacity();
+        let dropped = self.compact_locked(capacity, guard);
+        drop(lock);
+        expunged + dropped
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    ///
+    /// Any keys that are only present in the dirty map are promoted to the read-only map first,
+    /// so the iterator observes every key that was present when it was created. Entries removed
+    /// while iterating are skipped.
+    ///
+    /// The iterator element type is `(&'g K, &'g V)`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// map.insert(2, "b", &guard);
+    /// assert_eq!(map.iter(&guard).count(), 2);
+    /// ```
+    pub fn iter<'g>(&'g self, guard: &'g Guard<'_>) -> Iter<'g, K, V> {
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.promote_locked(guard);
+        drop(lock);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        Iter {
+            inner: unsafe { read.as_ref() }.map(|r| r.m.iter()),
+            guard,
+        }
+    }
+
+    /// An iterator visiting all live key-value pairs in ascending key order.
+    ///
+    /// The map's iteration order is otherwise arbitrary and can change with every promotion of
+    /// the dirty map. This collects the entries visited by [`iter`](Self::iter) and sorts them by
+    /// key up front, so the order is reproducible, e.g. for golden-file tests of map dumps. The
+    /// storage itself is unchanged. Entries inserted or removed while the returned iterator is
+    /// consumed are not reflected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for k in [3, 1, 2] {
+    ///     map.insert(k, k * 10, &guard);
+    /// }
+    /// let entries: Vec<_> = map.iter_sorted(&guard).collect();
+    /// assert_eq!(entries, vec![(&1, &10), (&2, &20), (&3, &30)]);
+    /// ```
+    pub fn iter_sorted<'g>(&'g self, guard: &'g Guard<'_>) -> impl Iterator<Item = (&'g K, &'g V)> {
+        let mut entries: Vec<_> = self.iter(guard).collect();
+        entries.sort_unstable_by_key(|(k, _)| *k);
+        entries.into_iter()
+    }
+
+    /// A weakly-consistent iterator visiting the key-value pairs of the read-only map in arbitrary
+    /// order.
+    ///
+    /// Unlike [`iter`](Self::iter), this never takes the lock or promotes the dirty map, so it
+    /// doesn't contend with writers and leaves the map's internal state alone. In exchange it is
+    /// only a best-effort view: keys that were inserted recently and are still only in the dirty
+    /// map are missed.
+    ///
+    /// Nothing is snapshotted up front. The iterator walks the read-only map that is current when
+    /// it is created, and loads each entry's value only when it reaches it: a key removed before
+    /// then is skipped rather than yielding a stale value, and a value replaced before then is
+    /// yielded in its new form. No key is visited twice. If the map is cleared or replaced
+    /// meanwhile, the iterator keeps walking the contents it started with.
+    ///
+    /// The iterator element type is `(&'g K, &'g V)`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// // the new key is only in the dirty map
+    /// assert_eq!(map.iter_read_only(&guard).count(), 0);
+    /// // `iter` promotes it
+    /// assert_eq!(map.iter(&guard).count(), 1);
+    /// assert_eq!(map.iter_read_only(&guard).count(), 1);
+    ///
+    /// // values are loaded as they are reached, so removing a key ahead of the iterator skips it
+    /// map.insert(2, "b", &guard);
+    /// map.iter(&guard).count();
+    /// let mut seen = 0;
+    /// for (k, _) in map.iter_read_only(&guard) {
+    ///     map.remove(&(3 - k), &guard);
+    ///     seen += 1;
+    /// }
+    /// assert_eq!(seen, 1);
+    /// ```
+    #[doc(alias = "iter_live")]
+    pub fn iter_read_only<'g>(&'g self, guard: &'g Guard<'_>) -> Iter<'g, K, V> {
+        self.check_guard(guard);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        Iter {
+            inner: unsafe { read.as_ref() }.map(|r| r.m.iter()),
+            guard,
+        }
+    }
+
+    /// An iterator visiting all keys in arbitrary order.
+    ///
+    /// The iterator element type is `&'g K`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.keys(&guard).collect::<Vec<_>>(), vec![&1]);
+    /// ```
+    pub fn keys<'g>(&'g self, guard: &'g Guard<'_>) -> Keys<'g, K, V> {
+        Keys { iter: self.iter(guard) }
+    }
+
+    /// An iterator visiting all values in arbitrary order.
+    ///
+    /// The iterator element type is `&'g V`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.values(&guard).collect::<Vec<_>>(), vec![&"a"]);
+    /// ```
+    pub fn values<'g>(&'g self, guard: &'g Guard<'_>) -> Values<'g, K, V> {
+        Values { iter: self.iter(guard) }
+    }
+
+    /// Folds every live key-value pair into an accumulator, in arbitrary order.
+    ///
+    /// This visits the same pairs as [`iter`](Self::iter), under the one guard passed in, and
+    /// without collecting them first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("a", 3, &guard);
+    /// map.insert("bb", 4, &guard);
+    /// let weighted = map.fold(0, |acc, k, v| acc + k.len() * v, &guard);
+    /// assert_eq!(weighted, 11);
+    /// ```
+    pub fn fold<A, F>(&self, init: A, mut f: F, guard: &Guard<'_>) -> A
+        where
+            F: FnMut(A, &K, &V) -> A,
+    {
+        self.iter(guard).fold(init, |acc, (k, v)| f(acc, k, v))
+    }
+
+    /// Returns the sum of all live values, or `V::default()` if the map is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.sum(&guard), 0);
+    /// for i in 1..=4u64 {
+    ///     map.insert(i, i * 10, &guard);
+    /// }
+    /// assert_eq!(map.sum(&guard), 100);
+    /// ```
+    pub fn sum(&self, guard: &Guard<'_>) -> V
+        where
+            V: Default + Add<Output = V> + Copy,
+    {
+        self.fold(V::default(), |acc, _, v| acc + *v, guard)
+    }
+
+    /// Returns an arbitrary live entry of the map, or `None` if the map is empty.
+    ///
+    /// Entries in the read-only map are tried first, without taking the lock. Only if none of them
+    /// is live and there are keys in the dirty map, the dirty map is promoted and searched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.any(&guard), None);
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.any(&guard), Some((&1, &"a")));
+    /// ```
+    pub fn any<'g>(&'g self, guard: &'g Guard<'_>) -> Option<(&'g K, &'g V)> {
+        self.check_guard(guard);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let r = unsafe { read.as_ref() }?;
+        let found = Iter {
+            inner: Some(r.m.iter()),
+            guard,
+        }.next();
+        if found.is_some() || !r.amended {
+            return found;
+        }
+        self.iter(guard).next()
+    }
+
+    /// Returns a pseudo-randomly chosen live entry of the map, or `None` if the map is empty.
+    ///
+    /// Keys that are only in the dirty map are promoted first so that every live key can be
+    /// picked. The choice is uniform over the slots of the read-only map, so entries that follow
+    /// runs of removed slots are slightly more likely to be returned. This is good enough for
+    /// random eviction, but not for statistics.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// map.insert(2, "b", &guard);
+    /// let (k, _) = map.sample(&guard).unwrap();
+    /// assert!(*k == 1 || *k == 2);
+    /// ```
+    pub fn sample<'g>(&'g self, guard: &'g Guard<'_>) -> Option<(&'g K, &'g V)> {
+        use rand::Rng;
+
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.promote_locked(guard);
+        drop(lock);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let r = unsafe { read.as_ref() }?;
+        if r.m.is_empty() {
+            return None;
+        }
+        let start = rand::thread_rng().gen_range(0..r.m.len());
+        // start at a random slot and wrap around until a live entry is found
+        r.m.iter()
+            .skip(start)
+            .chain(r.m.iter().take(start))
+            .find_map(|(k, e)| unsafe { e.as_ref().unwrap() }.load(guard).map(|v| (k, v)))
+    }
+
+    /// Returns `true` if the map contains no live entries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// assert!(map.is_empty());
+    /// map.insert(1, "a", &map.guard());
+    /// assert!(!map.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of entries in the map together with its capacity, as `(len, capacity)`.
+    ///
+    /// The capacity is the number of keys the map can hold before its tables have to grow: that
+    /// of the dirty map while it holds keys the read-only map lacks, and that of the read-only
+    /// map otherwise. Both numbers are read while holding the lock, so the capacity can't change
+    /// in between, which makes the pair suitable for load-factor computations. Inserts and
+    /// removals of keys that are already in the read-only map don't take the lock, though, so
+    /// the length can still move concurrently.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::with_capacity(100);
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// let (len, capacity) = map.size_info(&guard);
+    /// assert_eq!(len, 1);
+    /// assert!(capacity >= 100);
+    /// ```
+    pub fn size_info(&self, guard: &Guard<'_>) -> (usize, usize) {
+        self.check_guard(guard);
+        let lock = self.lock();
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let capacity = match unsafe { read.as_ref() } {
+            None => 0,
+            Some(r) => {
+                let dirty = self.dirty.load(Ordering::SeqCst, guard);
+                match unsafe { dirty.as_ref() } {
+                    Some(d) if r.amended => d.capacity(),
+                    _ => r.m.capacity(),
+                }
+            }
+        };
+        let len = self.len();
+        drop(lock);
+        (len, capacity)
+    }
+
+    /// Hashes every live key with the map's hasher, bins the hashes into `buckets` slots, and
+    /// returns how many keys landed in each slot.
+    ///
+    /// The histogram reflects the map's hasher `S` only. The read-only and dirty tables hash keys
+    /// with their own default hasher, so `S` has no effect on lookups in the map itself: it only
+    /// decides how [`ShardedMap`](crate::sharded::ShardedMap) routes keys to shards and how the table
+    /// built by [`freeze_into_arc`](Self::freeze_into_arc) is laid out. Uneven counts point at
+    /// keys that `S` doesn't spread well there. It visits every key as [`iter`](Self::iter)
+    /// does, so it is meant for debugging rather than hot paths.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buckets` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..1000 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// let histogram = map.hash_distribution(&guard, 8);
+    /// assert_eq!(histogram.len(), 8);
+    /// assert_eq!(histogram.iter().sum::<usize>(), 1000);
+    /// ```
+    pub fn hash_distribution(&self, guard: &Guard<'_>, buckets: usize) -> Vec<usize> {
+        assert!(buckets > 0, "hash_distribution needs at least one bucket");
+        let mut histogram = vec![0; buckets];
+        for (key, _) in self.iter(guard) {
+            histogram[(self.hash(key) % buckets as u64) as usize] += 1;
+        }
+        histogram
+    }
+
+    /// Returns how the lookups made with [`get`](Self::get) so far were served.
+    ///
+    /// Every `get` bumps exactly one of three counters: one for values found in the read-only
+    /// map without taking the lock, one for values found only after falling back to the lock and
+    /// the dirty map, and one for keys that had no value. A high share of dirty hits means keys
+    /// are looked up before they get promoted, and that the workload gets little out of the
+    /// lock-free read path. The counters are updated with relaxed atomics, so a snapshot taken
+    /// while other threads are reading may be slightly behind.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// map.get(&1, &guard); // only in the dirty map
+    /// map.iter(&guard).count(); // promote it
+    /// map.get(&1, &guard);
+    /// map.get(&2, &guard);
+    /// let stats = map.stats();
+    /// assert_eq!((stats.read_hits, stats.dirty_hits, stats.misses), (1, 1, 1));
+    /// ```
+    pub fn stats(&self) -> MapStats {
+        MapStats {
+            read_hits: self.read_hits.load(Ordering::Relaxed),
+            dirty_hits: self.dirty_hits.load(Ordering::Relaxed),
+            misses: self.get_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Classifies every entry of the map by where it lives and what state it is in.
+    ///
+    /// This walks both the read-only and the dirty map while holding the lock, so it is meant for
+    /// debugging and tests rather than hot paths. See [`MapInspection`] for what is counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// let inspection = map.inspect(&guard);
+    /// assert_eq!(inspection.read + inspection.dirty_only, 1);
+    /// ```
+    pub fn inspect(&self, guard: &Guard<'_>) -> MapInspection {
+        self.check_guard(guard);
+        let mut inspection = MapInspection::default();
+
+        let lock = self.lock();
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return inspection;
+        }
+        let r = unsafe { read.deref() };
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        let dirty = if r.amended && !dirty.is_null() {
+            Some(unsafe { dirty.deref() })
+        } else {
+            None
+        };
+
+        for e in r.m.values() {
+            match unsafe { e.as_ref().unwrap() }.state(guard) {
+                EntryState::Live(_) => inspection.read += 1,
+                EntryState::Empty => inspection.removed += 1,
+                EntryState::Expunged => inspection.expunged += 1,
+            }
+        }
+        if let Some(d) = dirty {
+            for (key, e) in d.iter() {
+                if r.m.contains_key(key) {
+                    continue;
+                }
+                match unsafe { e.as_ref().unwrap() }.state(guard) {
+                    EntryState::Live(_) => inspection.dirty_only += 1,
+                    // entries are only expunged while they are in the read-only map
+                    EntryState::Empty | EntryState::Expunged => inspection.removed += 1,
+                }
+            }
+        }
+        drop(lock);
+        inspection
+    }
+}
+
+impl<K, V, S> Map<K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, [`None`] is returned.
+    ///
+    /// If the map did have this key present, the value is updated, and the old
+    /// value is returned. The key is left unchanged. See the [std-collections
+    /// documentation] for more.
+    ///
+    /// [`None`]: std::option::Option::None
+    /// [std-collections documentation]: https://doc.rust-lang.org/std/collections/index.html#insert-and-complex-keys
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// use syncmap::map::Map;
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1,1,&guard)
+    pub fn insert<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) {
+        self.check_guard(guard);
+        self.put(key, Shared::boxed(value, &self.collector), false, guard);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+    }
+
+    /// Inserts a key-value pair into the map like [`insert`](Self::insert), and reports which
+    /// path the insert took.
+    ///
+    /// This is instrumentation for tests and benchmarks, for example to check that overwrites of
+    /// keys in a warmed-up map don't take the lock. See [`InsertPath`] for the possible paths.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::{InsertPath, Map};
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.insert_profiled(1, "a", &guard), InsertPath::NewInReadAmend);
+    /// assert_eq!(map.insert_profiled(2, "b", &guard), InsertPath::NewInDirty);
+    /// assert_eq!(map.insert_profiled(2, "c", &guard), InsertPath::Locked);
+    /// map.iter(&guard).count(); // promote
+    /// assert_eq!(map.insert_profiled(2, "d", &guard), InsertPath::LockFree);
+    /// ```
+    pub fn insert_profiled<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) -> InsertPath {
+        self.check_guard(guard);
+        let (_, path) = self.put_with_path(key, Shared::boxed(value, &self.collector), false, guard);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+        path
+    }
+
+    /// Inserts a key-value pair into the map, and returns a reference to the stored value.
+    ///
+    /// This saves the second lookup of an insert followed by a [`get`](Self::get). If the key
+    /// was already present, its value is replaced. The returned reference stays valid for as
+    /// long as `guard`, even if the value is replaced or removed by another thread in the
+    /// meantime.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// let v = map.insert_ref("greeting", String::from("hello"), &guard);
+    /// assert_eq!(v.len(), 5);
+    /// ```
+    pub fn insert_ref<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) -> &'g V {
+        self.check_guard(guard);
+        let value = Shared::boxed(value, &self.collector);
+        self.put(key, value, false, guard);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+        // safety: `value` was published by `put`. Should it be removed or replaced right away,
+        // it is retired through a guard, and our guard keeps it alive until `'g` ends.
+        unsafe { value.deref() }
+    }
+
+    /// Inserts a key-value pair into the map, unless the key is already present.
+    ///
+    /// Returns `true` if `value` was inserted. If the map already had a value for `key`, it is
+    /// left untouched, `value` is dropped and `false` is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert!(map.insert_if_absent(1, "a", &guard));
+    /// assert!(!map.insert_if_absent(1, "b", &guard));
+    /// assert_eq!(map.get(&1, &guard), Some(&"a"));
+    /// ```
+    pub fn insert_if_absent(&self, key: K, value: V, guard: &Guard<'_>) -> bool {
+        self.check_guard(guard);
+        let inserted = self.put(key, Shared::boxed(value, &self.collector), true, guard).is_none();
+        #[cfg(feature = "metrics")]
+        if inserted {
+            self.record_len();
+        }
+        inserted
+    }
+
+    /// Returns the value for `key`, inserting `value` first if the key is not present.
+    ///
+    /// If the key is already present, `value` is dropped. A key that was removed earlier is
+    /// refilled in place: its entry is reused rather than allocating a new one, so churning
+    /// the same keys through insert and remove only allocates the values themselves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.get_or_insert(1, "a", &guard), &"a");
+    /// assert_eq!(map.get_or_insert(1, "b", &guard), &"a");
+    /// map.remove(&1, &guard);
+    /// assert_eq!(map.get_or_insert(1, "c", &guard), &"c");
+    /// ```
+    pub fn get_or_insert<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) -> &'g V {
+        self.check_guard(guard);
+        let value = Shared::boxed(value, &self.collector);
+        match self.put(key, value, true, guard) {
+            Some(current) => current,
+            None => {
+                #[cfg(feature = "metrics")]
+                self.record_len();
+                // safety: `value` was published by `put`. Should it be removed or replaced right
+                // away, it is retired through a guard, and our guard keeps it alive until `'g`
+                // ends.
+                unsafe { value.deref() }
+            }
+        }
+    }
+
+    /// Inserts a key-value pair into the map, and returns the value it replaced (if any).
+    pub(crate) fn insert_returning_old<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) -> Option<&'g V> {
+        self.check_guard(guard);
+        let old = self.put(key, Shared::boxed(value, &self.collector), false, guard);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+        old
+    }
+
+    /// Inserts every key-value pair from `iter` into the map.
+    ///
+    /// Duplicate keys within the batch are coalesced before the map is touched: the last value
+    /// for each key wins, and every distinct key is inserted exactly once. All inserts happen
+    /// while holding the map's lock, so the batch is applied in one go.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert_batch(vec![(1, "a"), (2, "b"), (1, "c")], &guard);
+    /// assert_eq!(map.get(&1, &guard), Some(&"c"));
+    /// assert_eq!(map.get(&2, &guard), Some(&"b"));
+    /// ```
+    pub fn insert_batch<I>(&self, iter: I, guard: &Guard<'_>)
+        where
+            I: IntoIterator<Item = (K, V)>,
+    {
+        self.check_guard(guard);
+        let batch: HashMap<K, V> = iter.into_iter().collect();
+        if batch.is_empty() {
+            return;
+        }
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        let lock = self.lock();
+        for (key, value) in batch {
+            self.put_locked(key, Shared::boxed(value, &self.collector), guard);
+        }
+        drop(lock);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+    }
+
+    /// Inserts every key-value pair from `iter` like [`insert_batch`](Self::insert_batch), and
+    /// returns a view of the values the batch stored.
+    ///
+    /// The view is taken while still holding the lock the batch was applied under, so it holds
+    /// exactly the values the batch inserted, even if other threads replace or remove them right
+    /// after. That makes it suitable for load-then-verify sequences. The dirty map is also
+    /// promoted before the lock is released, so that later lookups of the batch's keys through
+    /// the map don't have to take the lock either.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// let batch = map.insert_batch_view(vec![(1, "a"), (2, "b"), (1, "c")], &guard);
+    /// map.insert(1, "d", &guard);
+    /// assert_eq!(batch.get(&1), Some(&"c"));
+    /// assert_eq!(batch.len(), 2);
+    /// assert_eq!(map.get(&1, &guard), Some(&"d"));
+    /// ```
+    pub fn insert_batch_view<'g, I>(&'g self, iter: I, guard: &'g Guard<'_>) -> BatchView<'g, K, V>
+        where
+            I: IntoIterator<Item = (K, V)>,
+    {
+        self.check_guard(guard);
+        let batch: HashMap<K, V> = iter.into_iter().collect();
+        let mut values = HashMap::with_capacity(batch.len());
+        if batch.is_empty() {
+            return BatchView { values };
+        }
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        let lock = self.lock();
+        for (key, value) in batch {
+            let value = self.insert_locked(key.clone(), value, guard);
+            values.insert(key, value);
+        }
+        self.promote_locked(guard);
+        drop(lock);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+        BatchView { values }
+    }
+
+    /// Stores `entry_value` under `key`, and returns the value it replaced.
+    ///
+    /// With `no_replacement`, a value that is already present is left alone and returned
+    /// instead, and `entry_value` is dropped.
+    fn put<'g>(
+        &'g self,
+        key: K,
+        entry_value: Shared<'g, V>,
+        no_replacement: bool,
+        guard: &'g Guard<'_>,
+    ) -> Option<&'g V> {
+        self.put_with_path(key, entry_value, no_replacement, guard).0
+    }
+
+    /// [`put`](Self::put), also returning which path the store took.
+    #[inline]
+    fn put_with_path<'g>(
+        &'g self,
+        key: K,
+        entry_value: Shared<'g, V>,
+        no_replacement: bool,
+        guard: &'g Guard<'_>,
+    ) -> (Option<&'g V>, InsertPath) {
+        let mut table = self.read.load(Ordering::SeqCst, guard);
+        if table.is_null() {
+            table = self.init_table(guard);
+        }
+
+        let read = unsafe { table.deref() };
+        if let Some(v) = read.m.get(&key) {
+            let e = unsafe { v.as_ref().unwrap() };
+            if no_replacement {
+                if let Some(current) = e.load(guard) {
+                    // safety: the new value was never published, so nobody else can be
+                    // referencing it.
+                    drop(unsafe { entry_value.into_box() });
+                    return (Some(current), InsertPath::LockFree);
+                }
+            } else if let Ok(old) = e.try_store(entry_value, guard) {
+                if old.is_none() {
+                    self.count_stored(false);
+                    self.refill_tombstone();
+                    self.stamp(e);
+                }
+                return (old, InsertPath::LockFree);
+            }
+        }
+
+        let lock = self.lock();
+        #[cfg(feature = "trace")]
+        self.trace(TraceEvent::LockAcquired(Operation::Put));
+        let old = if no_replacement {
+            (self.put_locked_if_absent(&key, K::clone, entry_value, guard), InsertPath::Locked)
+        } else {
+            self.put_locked_with_path(key, entry_value, guard)
+        };
+        #[cfg(feature = "trace")]
+        self.trace(TraceEvent::LockReleased(Operation::Put));
+        drop(lock);
+        old
+    }
+
+    /// Stores `entry_value` under `key` unless the key already has a value, and returns that
+    /// value instead if it does, dropping `entry_value`. The owned key is only created, with
+    /// `to_owned`, if the key has to be added to the dirty map.
+    ///
+    /// Must be called while holding `self.lock`. Even so, an emptied entry of the read-only map
+    /// can be refilled by a lock-free insert at any moment, so it is only filled if it is still
+    /// empty, and otherwise the concurrent value wins.
+    pub(crate) fn put_locked_if_absent<'g, Q, T>(
+        &'g self,
+        key: &Q,
+        to_owned: T,
+        entry_value: Shared<'g, V>,
+        guard: &'g Guard<'_>,
+    ) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            T: FnOnce(&Q) -> K,
+    {
+        let mut table = self.read.load(Ordering::SeqCst, guard);
+        if table.is_null() {
+            table = self.init_table(guard);
+        }
+        let read = unsafe { table.deref() };
+        if let Some(e) = read.m.get(key) {
+            let entry = unsafe { e.as_ref().unwrap() };
+            if entry.unexpunge_locked(guard) {
+                // as in `put_locked_with_path`, an expunged entry is missing from the dirty map
+                let dirty = self.dirty.load(Ordering::SeqCst, guard);
+                unsafe { dirty.as_ptr().as_mut().unwrap() }.insert(to_owned(key), *e);
+            }
+            return match entry.try_store_if_empty(entry_value, guard) {
+                Ok(()) => {
+                    self.count_stored(true);
+                    self.refill_tombstone();
+                    self.stamp(entry);
+                    None
+                }
+                Err(current) => {
+                    // safety: the new value was never published, so nobody else can be
+                    // referencing it.
+                    drop(unsafe { entry_value.into_box() });
+                    Some(current)
+                }
+            };
+        }
+        // keys that are only in the dirty map are never stored to without the lock
+        if let Some(current) = self.load_locked(key, guard) {
+            // safety: the new value was never published, so nobody else can be referencing it.
+            drop(unsafe { entry_value.into_box() });
+            return Some(current);
+        }
+        self.put_locked(to_owned(key), entry_value, guard);
+        None
+    }
+
+    /// Stores `value` for `key` and returns a reference to the stored value.
+    ///
+    /// Must be called while holding `self.lock`.
+    pub(crate) fn insert_locked<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) -> &'g V {
+        let value = Shared::boxed(value, &self.collector);
+        self.put_locked(key, value, guard);
+        // safety: the value was just published and can only be retired after our guard is dropped.
+        unsafe { value.deref() }
+    }
+
+    /// Stores `entry_value` under `key`, adding the key to the dirty map if it is not already in
+    /// the read-only map, and returns the value it replaced.
+    ///
+    /// Must be called while holding `self.lock`.
+    fn put_locked<'g>(&'g self, key: K, entry_value: Shared<'g, V>, guard: &'g Guard<'_>) -> Option<&'g V> {
+        self.put_locked_with_path(key, entry_value, guard).0
+    }
+
+    /// [`put_locked`](Self::put_locked), also returning which path the store took.
+    #[inline]
+    fn put_locked_with_path<'g>(
+        &'g self,
+        key: K,
+        entry_value: Shared<'g, V>,
+        guard: &'g Guard<'_>,
+    ) -> (Option<&'g V>, InsertPath) {
+        let mut table = self.read.load(Ordering::SeqCst, guard);
+        if table.is_null() {
+            table = self.init_table(guard);
+        }
+        let read = unsafe { table.deref() };
+        match read.m.get(&key) {
+            Some(e) => {
+                if unsafe { e.as_ref().unwrap() }.unexpunge_locked(guard) {
+                    // The entry was previously expunged, which implies that there is a
+                    // non-nil dirty map and this entry is not in it.
+                    let dirty = self.dirty.load(Ordering::SeqCst, guard);
+                    unsafe { dirty.as_ptr().as_mut().unwrap() }.insert(key, *e);
+                }
+                let old = unsafe { e.as_ref().unwrap() }.store_locked(entry_value, guard);
+                if old.is_none() {
+                    self.count_stored(true);
+                    self.refill_tombstone();
+                    self.stamp(unsafe { e.as_ref().unwrap() });
+                }
+                (old, InsertPath::Locked)
+            }
+            None => {
+                let mut dirty = self.dirty.load(Ordering::SeqCst, guard);
+                if dirty.is_null() {
+                    dirty = Shared::boxed(HashMap::new(), &self.collector);
+                    self.dirty.store(dirty, Ordering::SeqCst);
+                }
+                if let Some(e) = unsafe { dirty.deref() }.get(&key) {
+                    let old = unsafe { e.as_ref() }.unwrap().store_locked(entry_value, guard);
+                    if old.is_none() {
+                        self.count_stored(true);
+                        self.stamp(unsafe { e.as_ref() }.unwrap());
+                    }
+                    return (old, InsertPath::Locked);
+                }
+                self.count_stored(true);
+
+                if !read.amended {
+                    // We're adding the first new key to the dirty map.
+                    // Make sure it is allocated and mark the read-only map as incomplete.
+                    self.dirty_locked(key, entry_value, guard);
+                    let mut map = HashMap::with_capacity(read.m.capacity());
+                    for (key, value) in &read.m {
+                        map.insert(key.clone(), *value);
+                    }
+                    let shard_map = Shared::boxed(ReadOnly {
+                        m: map,
+                        amended: true,
+                    }, &self.collector);
+                    let old_read = self.read.swap(shard_map, Ordering::SeqCst, guard);
+                    // safety: the old read-only map is unlinked, so it is only reachable by
+                    // threads that still hold a guard.
+                    unsafe { guard.retire_shared(old_read) };
+                    return (None, InsertPath::NewInReadAmend);
+                }
+                //save entry;
+                let entry = self.collector.link_boxed(Entry::new(entry_value));
+                self.stamp(unsafe { &*entry });
+                unsafe {
+                    let dirty = dirty.as_ptr();
+                    dirty.as_mut().unwrap().insert(key, entry);
+                };
+                (None, InsertPath::NewInDirty)
+            }
+        }
+    }
+
+    /// Replaces the value of a key that is already present, and returns the old value.
+    ///
+    /// If the map does not contain `key`, nothing is inserted, `value` is dropped and [`None`] is
+    /// returned. This is the opposite of an insert-if-absent: it only ever updates existing keys.
+    /// That makes it the exchange primitive for state machines whose keys have to be registered
+    /// before they can change state. The value is swapped into the key's entry atomically,
+    /// without taking the lock unless the key is still only in the dirty map, and the old value
+    /// is retired through the collector.
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// [`Hash`] and [`Ord`] on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// [`None`]: std::option::Option::None
+    /// [`Ord`]: std::cmp::Ord
+    /// [`Hash`]: std::hash::Hash
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.replace(&1, "a", &guard), None);
+    /// assert_eq!(map.get(&1, &guard), None);
+    /// map.insert(1, "a", &guard);
+    /// assert_eq!(map.replace(&1, "b", &guard), Some(&"a"));
+    /// assert_eq!(map.get(&1, &guard), Some(&"b"));
+    /// ```
+    #[doc(alias = "swap")]
+    pub fn replace<'g, Q>(&'g self, key: &Q, value: V, guard: &'g Guard<'_>) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.check_guard(guard);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        let e = match r.m.get(key) {
+            Some(e) => *e,
+            None => {
+                if !r.amended {
+                    return None;
+                }
+                let lock = self.lock();
+                let dirty = self.dirty.load(Ordering::SeqCst, guard);
+                let e = if dirty.is_null() {
+                    None
+                } else {
+                    unsafe { dirty.deref() }.get(key).copied()
+                };
+                drop(lock);
+                e?
+            }
+        };
+
+        let entry_value = Shared::boxed(value, &self.collector);
+        let old = unsafe { e.as_ref().unwrap() }.try_replace(entry_value, guard);
+        if old.is_none() {
+            // safety: the new value was never published, so nobody else can be referencing it.
+            drop(unsafe { entry_value.into_box() });
+        }
+        old
+    }
+
+    /// Stores `new` under `key` if the key's current value is `current`, and returns the
+    /// replaced value.
+    ///
+    /// `current` is compared by address, not by value, so `V` doesn't need to implement
+    /// `PartialEq` and large values aren't compared field by field. It should be a reference
+    /// previously returned by this map for `key`, such as from [`get`](Self::get). If the key's
+    /// value has been replaced or removed since, nothing is stored and `new` is handed back as
+    /// an error. This is the building block for optimistic update loops: read the value, compute
+    /// its replacement, and retry if another writer got there first.
+    ///
+    /// # ABA
+    ///
+    /// A removed or replaced value is only freed once every guard that might still be reading
+    /// it has been dropped, and its memory can't be reused for a new value before then. `current`
+    /// is tied to `guard`, so the allocation it points to can't have been recycled: if the
+    /// addresses match, it really is the value that was read. Comparing against a value address
+    /// kept beyond its guard, for example as a raw pointer, has no such guarantee.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("config", vec![1, 2, 3], &guard);
+    ///
+    /// let current = map.get(&"config", &guard).unwrap();
+    /// let mut next = current.clone();
+    /// next.push(4);
+    /// assert!(map.compare_and_swap_ptr(&"config", current, next, &guard).is_ok());
+    ///
+    /// // `current` is no longer the stored value
+    /// assert_eq!(map.compare_and_swap_ptr(&"config", current, vec![], &guard), Err(vec![]));
+    /// assert_eq!(map.get(&"config", &guard), Some(&vec![1, 2, 3, 4]));
+    /// ```
+    pub fn compare_and_swap_ptr<'g, Q>(&'g self, key: &Q, current: &V, new: V, guard: &'g Guard<'_>) -> Result<&'g V, V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.check_guard(guard);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let e = match unsafe { read.as_ref() } {
+            Some(r) => match r.m.get(key) {
+                Some(e) => Some(unsafe { &**e.as_ref().unwrap() }),
+                None if r.amended => {
+                    let lock = self.lock();
+                    let e = self.entry_locked(key, guard);
+                    drop(lock);
+                    e
+                }
+                None => None,
+            },
+            None => None,
+        };
+        let e = match e {
+            Some(e) => e,
+            None => return Err(new),
+        };
+
+        let new = Shared::boxed(new, &self.collector);
+        e.compare_and_swap_ptr(current, new, guard).map_err(|()| {
+            // safety: the new value was never published, so nobody else can be referencing it.
+            Linked::into_inner(*unsafe { new.into_box() })
+        })
+    }
+
+    /// Returns a handle to the entry for `key`, inserting `value` first if the key is not present.
+    ///
+    /// The [`EntryHandle`] can load and update the entry's value without hashing or looking up
+    /// the key again, which makes it cheap to hammer a single hot key (for example a counter).
+    /// If the key is already present, `value` is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// let hits = map.get_or_insert_entry("hits", 0, &guard);
+    /// for _ in 0..3 {
+    ///     let current = hits.load().unwrap();
+    ///     hits.compare_and_swap(current, current + 1).unwrap();
+    /// }
+    /// assert_eq!(map.get(&"hits", &guard), Some(&3));
+    /// ```
+    pub fn get_or_insert_entry<'g>(&'g self, key: K, value: V, guard: &'g Guard<'_>) -> EntryHandle<'g, V> {
+        self.check_guard(guard);
+
+        let mut table = self.read.load(Ordering::SeqCst, guard);
+        if table.is_null() {
+            table = self.init_table(guard);
+        }
+        let read = unsafe { table.deref() };
+        if let Some(e) = read.m.get(&key) {
+            let e = unsafe { &**e.as_ref().unwrap() };
+            if e.load(guard).is_some() {
+                return EntryHandle::new(e, &self.collector, guard);
+            }
+        }
+
+        let lock = self.lock();
+        self.put_locked_if_absent(&key, K::clone, Shared::boxed(value, &self.collector), guard);
+        let e = self.entry_locked(&key, guard).expect("key was just inserted");
+        drop(lock);
+        EntryHandle::new(e, &self.collector, guard)
+    }
+
+    /// Marks `key` as being computed by the current thread, and returns `true`, unless another
+    /// thread already is. In that case, waits for it to finish and returns `false`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current thread is already computing `key`, since waiting for itself would
+    /// never return.
+    fn claim_in_flight(&self, key: &K) -> bool {
+        let current = std::thread::current().id();
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+        match in_flight.get(key) {
+            None => {
+                in_flight.insert(key.clone(), current);
+                return true;
+            }
+            Some(owner) if *owner == current => {
+                drop(in_flight);
+                panic!("re-entrant get_or_insert_with: the closure computing a key asked for that same key");
+            }
+            Some(_) => {}
+        }
+        while in_flight.contains_key(key) {
+            in_flight = self.in_flight_done.wait(in_flight).unwrap_or_else(PoisonError::into_inner);
+        }
+        false
+    }
+
+    /// Returns the value for `key`, inserting the value computed by `f` if the key is absent.
+    ///
+    /// `f` runs without holding the map's lock, so operations on other keys carry on while it
+    /// computes. Concurrent calls for the *same* key are deduplicated: only one of them runs its
+    /// closure, and the others wait for that value instead of computing their own. If the
+    /// running closure panics, one of the waiting callers takes over.
+    ///
+    /// If a plain [`insert`](Self::insert) of `key` lands while `f` runs, the inserted value is
+    /// kept and returned, and the value from `f` is dropped.
+    ///
+    /// `f` may use the map, including to look up or insert other keys. It must not ask for the
+    /// value of `key` itself through `get_or_insert_with` (or
+    /// [`get_or_try_insert_with_negative_cache`](Self::get_or_try_insert_with_negative_cache)),
+    /// since that would wait for `f` to finish: such a call panics instead of deadlocking.
+    ///
+    /// The callback set with [`MapBuilder::on_miss`], if any, is called right before `f` runs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.get_or_insert_with(1, || "a", &guard), &"a");
+    /// assert_eq!(map.get_or_insert_with(1, || "b", &guard), &"a");
+    /// ```
+    pub fn get_or_insert_with<'g, F>(&'g self, key: K, f: F, guard: &'g Guard<'_>) -> &'g V
+        where
+            F: FnOnce() -> V,
+    {
+        loop {
+            if let Some(v) = self.get(&key, guard) {
+                return v;
+            }
+            if self.claim_in_flight(&key) {
+                break;
+            }
+            // the other caller either inserted the value or panicked, so look again
+        }
+        let marker = InFlight { map: self, key: &key };
+        // the previous owner of the marker may have finished between our lookup and taking it
+        if let Some(v) = self.get(&key, guard) {
+            return v;
+        }
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        if let Some(on_miss) = &self.on_miss {
+            on_miss(&key);
+        }
+        let value = Shared::boxed(f(), &self.collector);
+        let lock = self.lock();
+        // a plain insert may have stored a value while `f` ran; that one wins
+        let current = self.put_locked_if_absent(&key, K::clone, value, guard);
+        drop(lock);
+        drop(marker);
+        // safety: if it was stored, the value can only be retired after our guard is dropped.
+        current.unwrap_or_else(|| unsafe { value.deref() })
+    }
+
+    /// Returns the value for `key`, inserting the value computed by `f` if the key is absent and
+    /// `f` succeeds, and caching the error for `ttl` if it fails.
+    ///
+    /// This is [`get_or_insert_with`](Self::get_or_insert_with) for fallible computations, such
+    /// as fetches from a flaky upstream: concurrent calls for the same key still run only one
+    /// closure, but an error is remembered for the key until `ttl` has passed. Until then, calls
+    /// for the key return a clone of the error without running their closure, which protects the
+    /// upstream from being hammered while it is failing. The first call after that retries.
+    ///
+    /// Cached errors are kept apart from the map's values: they don't count towards
+    /// [`len`](Self::len), and aren't affected by [`remove`](Self::remove) or
+    /// [`clear`](Self::clear). A cached error of a different type than `E` is ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<&str, u32> = Map::new();
+    /// let guard = map.guard();
+    /// let ttl = Duration::from_secs(60);
+    /// let failed = map.get_or_try_insert_with_negative_cache("a", ttl, || Err("down"), &guard);
+    /// assert_eq!(failed, Err("down"));
+    /// // the error is cached, so the closure doesn't run
+    /// let cached = map.get_or_try_insert_with_negative_cache("a", ttl, || Ok(1), &guard);
+    /// assert_eq!(cached, Err("down"));
+    /// assert_eq!(map.get(&"a", &guard), None);
+    /// ```
+    pub fn get_or_try_insert_with_negative_cache<'g, F, E>(
+        &'g self,
+        key: K,
+        ttl: Duration,
+        f: F,
+        guard: &'g Guard<'_>,
+    ) -> Result<&'g V, E>
+        where
+            F: FnOnce() -> Result<V, E>,
+            E: Clone + Send + Sync + 'static,
+    {
+        loop {
+            if let Some(v) = self.get(&key, guard) {
+                return Ok(v);
+            }
+            if let Some(e) = self.cached_failure(&key) {
+                return Err(e);
+            }
+            if self.claim_in_flight(&key) {
+                break;
+            }
+            // the other caller inserted a value, cached an error or panicked, so look again
+        }
+        let marker = InFlight { map: self, key: &key };
+        // the previous owner of the marker may have finished between our lookups and taking it
+        if let Some(v) = self.get(&key, guard) {
+            return Ok(v);
+        }
+        if let Some(e) = self.cached_failure(&key) {
+            return Err(e);
+        }
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        if let Some(on_miss) = &self.on_miss {
+            on_miss(&key);
+        }
+        let value = match f() {
+            Ok(value) => Shared::boxed(value, &self.collector),
+            Err(e) => {
+                // cache the error before releasing the marker, so that waiting callers see it
+                let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+                failures.insert(key.clone(), (Instant::now() + ttl, Box::new(e.clone())));
+                drop(failures);
+                drop(marker);
+                return Err(e);
+            }
+        };
+        let lock = self.lock();
+        // a plain insert may have stored a value while `f` ran; that one wins
+        let current = self.put_locked_if_absent(&key, K::clone, value, guard);
+        drop(lock);
+        drop(marker);
+        // safety: if it was stored, the value can only be retired after our guard is dropped.
+        Ok(current.unwrap_or_else(|| unsafe { value.deref() }))
+    }
+
+    /// Returns a clone of the error cached for `key`, if it hasn't expired yet and is an `E`.
+    fn cached_failure<E>(&self, key: &K) -> Option<E>
+        where
+            E: Clone + 'static,
+    {
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let (expires, e) = failures.get(key)?;
+        if *expires <= Instant::now() {
+            failures.remove(key);
+            return None;
+        }
+        e.downcast_ref::<E>().cloned()
+    }
+
+    /// Returns a clone of the value for `key`, inserting the value computed by `f` if the key is
+    /// absent.
+    ///
+    /// This is [`get_or_insert_with`](Self::get_or_insert_with) for callers that want an owned
+    /// value. It pins its own guard and drops it before returning, so the caller doesn't hold up
+    /// reclamation of values replaced or removed in the meantime while it uses the result.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// assert_eq!(map.get_or_insert_with_cloned(1, || String::from("a")), "a");
+    /// assert_eq!(map.get_or_insert_with_cloned(1, || String::from("b")), "a");
+    /// ```
+    pub fn get_or_insert_with_cloned<F>(&self, key: K, f: F) -> V
+        where
+            F: FnOnce() -> V,
+            V: Clone,
+    {
+        let guard = self.guard();
+        self.get_or_insert_with(key, f, &guard).clone()
+    }
+
+    /// Returns the value for `key`, inserting the value computed by `f` if the key is absent.
+    ///
+    /// The lookup uses the borrowed form `key`, and the owned key is only created, by calling
+    /// `to_owned`, when the value is actually inserted. With `String` keys this means a lookup by
+    /// `&str` doesn't allocate when the key is already present.
+    ///
+    /// `f` runs without holding the map's lock. If another thread inserts the key while `f`
+    /// runs, its value is kept and returned, and the value from `f` is dropped.
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// [`Hash`] and [`Ord`] on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// [`Ord`]: std::cmp::Ord
+    /// [`Hash`]: std::hash::Hash
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<String, usize> = Map::new();
+    /// let guard = map.guard();
+    /// let len = map.get_or_insert_with_cow("hello", str::to_owned, || 5, &guard);
+    /// assert_eq!(len, &5);
+    /// // the key exists now, so neither closure is called
+    /// let len = map.get_or_insert_with_cow("hello", |_| unreachable!(), || unreachable!(), &guard);
+    /// assert_eq!(len, &5);
+    /// ```
+    pub fn get_or_insert_with_cow<'g, Q, T, F>(&'g self, key: &Q, to_owned: T, f: F, guard: &'g Guard<'_>) -> &'g V
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            T: FnOnce(&Q) -> K,
+            F: FnOnce() -> V,
+    {
+        if let Some(v) = self.get(key, guard) {
+            return v;
+        }
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        let value = Shared::boxed(f(), &self.collector);
+        let lock = self.lock();
+        let current = self.put_locked_if_absent(key, to_owned, value, guard);
+        drop(lock);
+        // safety: if it was stored, the value can only be retired after our guard is dropped.
+        current.unwrap_or_else(|| unsafe { value.deref() })
+    }
+
+    /// Removes a key-value pair from the map, and returns the removed value (if any).
+    ///
+    /// The key may be any borrowed form of the map's key type, but
+    /// [`Hash`] and [`Ord`] on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// The removed value is handed to the map's collector, which frees it once no guard can
+    /// observe it anymore. The returned reference is bound to `guard`, so it can't be used after
+    /// the guard is dropped:
+    ///
+    /// ```compile_fail
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a", &guard);
+    /// let removed = map.remove(&1, &guard);
+    /// drop(guard);
+    /// assert_eq!(removed, Some(&"a"));
+    /// ```
+    ///
+    /// Removing a key that is in the read-only map leaves its emptied entry behind, so that the
+    /// key can be inserted again without the lock. Once these tombstones outweigh the live
+    /// entries by more than the map's
+    /// [`compaction_threshold`](MapBuilder::compaction_threshold), `remove` compacts the
+    /// read-only map, so that remove-heavy workloads don't fill it with dead entries.
+    ///
+    /// [`Ord`]: std::cmp::Ord
+    /// [`Hash`]: std::hash::Hash
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// use syncmap::map::Map;
+    /// let map = Map::new();
+    /// map.insert(1, "a",&map.guard());
+    /// assert_eq!(map.remove(&1,&map.guard()), Some(&"a"));
+    /// assert_eq!(map.remove(&1,&map.guard()), None);
+    /// ```
+    pub fn remove<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        // NOTE: _technically_, this method shouldn't require the thread-safety bounds, but a) that
+        // would require special-casing replace_node for when new_value.is_none(), and b) it's sort
+        // of useless to call remove on a collection that you know you can never insert into.
+        self.remove_with(key, |_| (), guard).map(|(_, value)| value)
+    }
+
+    /// [`remove`](Self::remove), also passing the key as it was stored to `with_key` if a value
+    /// was removed, and returning what it returns.
+    pub(crate) fn remove_with<'g, Q, F, R>(&'g self, key: &Q, with_key: F, guard: &'g Guard<'_>) -> Option<(R, &'g V)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            F: FnOnce(&K) -> R,
+    {
+        self.check_guard(guard);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        let removed = if let Some((k, e)) = r.m.get_key_value(key) {
+            let removed = unsafe { e.as_ref().unwrap() }.remove(guard).map(|v| (with_key(k), v));
+            if removed.is_some() {
+                self.count_removed(false);
+                let tombstones = self.tombstones.fetch_add(1, Ordering::SeqCst) + 1;
+                // While the read-only map is amended, the next dirty map leaves the tombstones
+                // out anyway. Otherwise they would stay until the next compaction, so trigger one
+                // once they outweigh the live entries.
+                if !r.amended
+                    && tombstones as f64 > self.len() as f64 * self.compaction_threshold
+                {
+                    let lock = self.lock();
+                    let capacity = unsafe { self.read.load(Ordering::SeqCst, guard).deref() }.m.capacity();
+                    self.compact_locked(capacity, guard);
+                    drop(lock);
+                }
+            }
+            removed
+        } else if r.amended {
+            let lock = self.lock();
+            #[cfg(feature = "trace")]
+            self.trace(TraceEvent::LockAcquired(Operation::Remove));
+            let removed = self.remove_locked_with(key, with_key, guard);
+            #[cfg(feature = "trace")]
+            self.trace(TraceEvent::LockReleased(Operation::Remove));
+            drop(lock);
+            removed
+        } else {
+            None
+        };
+        #[cfg(feature = "metrics")]
+        if removed.is_some() {
+            self.record_len();
+        }
+        removed
+    }
+
+    /// Removes `key` from the map, looking in the dirty map if it is not in the read-only map.
+    ///
+    /// Must be called while holding `self.lock`.
+    pub(crate) fn remove_locked<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.remove_locked_with(key, |_| (), guard).map(|(_, value)| value)
+    }
+
+    /// [`remove_locked`](Self::remove_locked), passing the stored key to `with_key` like
+    /// [`remove_with`](Self::remove_with). Must be called while holding `self.lock`.
+    fn remove_locked_with<'g, Q, F, R>(&'g self, key: &Q, with_key: F, guard: &'g Guard<'_>) -> Option<(R, &'g V)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            F: FnOnce(&K) -> R,
+    {
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        let removed = if let Some((k, e)) = r.m.get_key_value(key) {
+            let removed = unsafe { e.as_ref().unwrap() }.remove(guard).map(|v| (with_key(k), v));
+            if removed.is_some() {
+                self.tombstones.fetch_add(1, Ordering::SeqCst);
+            }
+            removed
+        } else {
+            if !r.amended {
+                return None;
+            }
+            let dirty = self.dirty.load(Ordering::SeqCst, guard);
+            if dirty.is_null() {
+                return None;
+            }
+            let e = unsafe { dirty.as_ptr().as_mut().unwrap() }.remove_entry(key);
+            self.miss_locked(guard);
+            let (k, e) = e?;
+            let removed = unsafe { e.as_ref().unwrap() }.remove(guard).map(|v| (with_key(&k), v));
+            // safety: the key was not in the read-only map and has just been taken out of the
+            // dirty map, so the entry is no longer reachable from either.
+            unsafe { guard.retire(e, seize::reclaim::boxed::<Entry<V>>) };
+            removed
+        };
+        if removed.is_some() {
+            self.count_removed(true);
+        }
+        removed
+    }
+
+    /// Loads the value for `key` from either the read-only or the dirty map.
+    ///
+    /// Unlike [`get`](Self::get), this does not count as a miss. Must be called while holding
+    /// `self.lock`.
+    pub(crate) fn load_locked<'g, Q>(&'g self, key: &Q, guard: &'g Guard<'_>) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.entry_locked(key, guard)?.load(guard)
+    }
+
+    /// Atomically computes a new value for `key` from its current one.
+    ///
+    /// While holding the map's lock, `f` is called with the current value (`None` if the key is
+    /// absent). If it returns a value, that value is stored and a reference to it is returned;
+    /// if it returns `None`, the map is left unchanged and `None` is returned.
+    ///
+    /// Updates through `update` (and the operations built on it, like
+    /// [`fetch_add_saturating`](Self::fetch_add_saturating)) never lose each other's writes. A
+    /// plain [`insert`](Self::insert) or [`remove`](Self::remove) of a key that is already in
+    /// the read-only map doesn't take the lock, though, and may land between the read and the
+    /// write of an update.
+    ///
+    /// `f` runs while holding the map's lock, so it must not use the map itself, or it
+    /// deadlocks.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.update("log", |v| Some(format!("{}a", v.map_or("", String::as_str))), &guard);
+    /// map.update("log", |v| Some(format!("{}b", v.map_or("", String::as_str))), &guard);
+    /// assert_eq!(map.get(&"log", &guard).map(String::as_str), Some("ab"));
+    /// assert_eq!(map.update("log", |_| None, &guard), None);
+    /// ```
+    pub fn update<'g, F>(&'g self, key: K, f: F, guard: &'g Guard<'_>) -> Option<&'g V>
+        where
+            F: FnOnce(Option<&V>) -> Option<V>,
+    {
+        self.check_guard(guard);
+        if self.read.load(Ordering::SeqCst, guard).is_null() {
+            self.init_table(guard);
+        }
+
+        let lock = self.lock();
+        let value = match f(self.load_locked(&key, guard)) {
+            Some(value) => Shared::boxed(value, &self.collector),
+            None => return None,
+        };
+        self.put_locked(key, value, guard);
+        drop(lock);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+        // safety: the value was just published and can only be retired after our guard is dropped.
+        Some(unsafe { value.deref() })
+    }
+
+    /// Adds `delta` to the counter stored under `key`, clamping at `V::MAX`, and returns the
+    /// previous value.
+    ///
+    /// An absent key counts as zero. This is built on [`update`](Self::update), so concurrent
+    /// counter operations on the same key never lose increments, though a racing plain
+    /// [`insert`](Self::insert) or [`remove`](Self::remove) of the key can still overwrite one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.fetch_add_saturating("hits", 200u8, &guard), 0);
+    /// assert_eq!(map.fetch_add_saturating("hits", 200u8, &guard), 200);
+    /// assert_eq!(map.get(&"hits", &guard), Some(&u8::MAX));
+    /// ```
+    pub fn fetch_add_saturating(&self, key: K, delta: V, guard: &Guard<'_>) -> V
+        where
+            V: Counter,
+    {
+        let mut previous = V::default();
+        self.update(key, |v| {
+            previous = v.copied().unwrap_or_default();
+            Some(previous.saturating_add(delta))
+        }, guard);
+        previous
+    }
+
+    /// Subtracts `delta` from the counter stored under `key`, clamping at `V::MIN`, and returns
+    /// the previous value.
+    ///
+    /// An absent key counts as zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("tokens", 3u32, &guard);
+    /// assert_eq!(map.fetch_sub_saturating("tokens", 5, &guard), 3);
+    /// assert_eq!(map.get(&"tokens", &guard), Some(&0));
+    /// ```
+    pub fn fetch_sub_saturating(&self, key: K, delta: V, guard: &Guard<'_>) -> V
+        where
+            V: Counter,
+    {
+        let mut previous = V::default();
+        self.update(key, |v| {
+            previous = v.copied().unwrap_or_default();
+            Some(previous.saturating_sub(delta))
+        }, guard);
+        previous
+    }
+
+    /// Adds `delta` to the counter stored under `key`, and returns the previous value, or `None`
+    /// if the addition would overflow.
+    ///
+    /// An absent key counts as zero. On overflow the counter is left unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// assert_eq!(map.checked_fetch_add("hits", 200u8, &guard), Some(0));
+    /// assert_eq!(map.checked_fetch_add("hits", 100u8, &guard), None);
+    /// assert_eq!(map.get(&"hits", &guard), Some(&200));
+    /// ```
+    pub fn checked_fetch_add(&self, key: K, delta: V, guard: &Guard<'_>) -> Option<V>
+        where
+            V: Counter,
+    {
+        let mut previous = None;
+        self.update(key, |v| {
+            let current = v.copied().unwrap_or_default();
+            let next = current.checked_add(delta)?;
+            previous = Some(current);
+            Some(next)
+        }, guard);
+        previous
+    }
+
+    /// Subtracts `delta` from the counter stored under `key`, and returns the previous value, or
+    /// `None` if the subtraction would overflow.
+    ///
+    /// An absent key counts as zero. On overflow the counter is left unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("tokens", 3u32, &guard);
+    /// assert_eq!(map.checked_fetch_sub("tokens", 2, &guard), Some(3));
+    /// assert_eq!(map.checked_fetch_sub("tokens", 2, &guard), None);
+    /// assert_eq!(map.get(&"tokens", &guard), Some(&1));
+    /// ```
+    pub fn checked_fetch_sub(&self, key: K, delta: V, guard: &Guard<'_>) -> Option<V>
+        where
+            V: Counter,
+    {
+        let mut previous = None;
+        self.update(key, |v| {
+            let current = v.copied().unwrap_or_default();
+            let next = current.checked_sub(delta)?;
+            previous = Some(current);
+            Some(next)
+        }, guard);
+        previous
+    }
+
+    /// Reads and rewrites a group of keys in one critical section of the map's lock.
+    ///
+    /// While holding the map's lock, the current value of every key in `keys` is cloned into a
+    /// slice that is handed to `f`. `f` returns the new value for each key, in the same order as
+    /// `keys`; a `None` removes the key. Duplicate keys are loaded once per occurrence and the
+    /// last returned value for them wins. Only the returned `Vec` is applied: edits made through
+    /// the slice are discarded.
+    ///
+    /// This does not interleave with any other operation that takes the lock. Note that
+    /// overwriting or removing a key that already lives in the read-only map does not take the
+    /// lock, so a concurrent plain [`insert`](Self::insert) or [`remove`](Self::remove) of such a
+    /// key can still land between loading its value and storing the new one.
+    ///
+    /// `f` runs while holding the map's lock, so it must not use the map itself, or it
+    /// deadlocks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` returns a different number of values than there are `keys`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("a", 10, &guard);
+    /// map.insert("b", 5, &guard);
+    /// // move 3 from "a" to "b"
+    /// map.with_many_mut(&["a", "b"], |values| {
+    ///     let a = values[0].unwrap_or(0);
+    ///     let b = values[1].unwrap_or(0);
+    ///     vec![Some(a - 3), Some(b + 3)]
+    /// }, &guard);
+    /// assert_eq!(map.get(&"a", &guard), Some(&7));
+    /// assert_eq!(map.get(&"b", &guard), Some(&8));
+    /// ```
+    pub fn with_many_mut<F>(&self, keys: &[K], f: F, guard: &Guard<'_>)
+        where
+            V: Clone,
+            F: FnOnce(&mut [Option<V>]) -> Vec<Option<V>>,
+    {
+        self.check_guard(guard);
+
+        let lock = self.lock();
+        let mut current: Vec<Option<V>> = keys
+            .iter()
+            .map(|key| self.load_locked(key, guard).cloned())
+            .collect();
+        let updated = f(&mut current);
+        assert_eq!(
+            updated.len(),
+            keys.len(),
+            "with_many_mut: the closure must return one value per key"
+        );
+        for (key, value) in keys.iter().zip(updated) {
+            match value {
+                Some(value) => {
+                    self.put_locked(key.clone(), Shared::boxed(value, &self.collector), guard);
+                }
+                None => {
+                    self.remove_locked(key, guard);
+                }
+            }
+        }
+        drop(lock);
+    }
+
+    /// Replaces the value of every live entry with the result of `f`.
+    ///
+    /// `f` is called with each key and its current value, and the value it returns is stored in
+    /// place of the current one. The whole pass runs while holding the lock, after promoting the
+    /// dirty map, so the set of keys visited is the one present when `update_all` started: keys
+    /// inserted concurrently are not visited, and keys removed concurrently are not brought back.
+    ///
+    /// `f` runs while holding the map's lock, so it must not use the map itself, or it
+    /// deadlocks.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("a", 8, &guard);
+    /// map.insert("b", 4, &guard);
+    /// // decay all counters by half
+    /// map.update_all(|_, v| v / 2, &guard);
+    /// assert_eq!(map.get(&"a", &guard), Some(&4));
+    /// assert_eq!(map.get(&"b", &guard), Some(&2));
+    /// ```
+    pub fn update_all<F>(&self, mut f: F, guard: &Guard<'_>)
+        where
+            F: FnMut(&K, &V) -> V,
+    {
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.promote_locked(guard);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if let Some(r) = unsafe { read.as_ref() } {
+            for (key, e) in &r.m {
+                let e = unsafe { e.as_ref().unwrap() };
+                if let Some(value) = e.load(guard) {
+                    let entry_value = Shared::boxed(f(key, value), &self.collector);
+                    if e.try_replace(entry_value, guard).is_none() {
+                        // the entry was removed after we loaded it
+                        drop(unsafe { entry_value.into_box() });
+                    }
+                }
+            }
+        }
+        drop(lock);
+    }
+
+    /// Replaces the contents of the map with those captured by [`checkpoint`](Self::checkpoint).
+    ///
+    /// The map is cleared and refilled while holding the lock, so no other locked operation
+    /// interleaves with the restore. Lock-free readers may observe a partially restored map.
+    pub fn restore(&self, checkpoint: &Checkpoint<K, V>, guard: &Guard<'_>)
+        where
+            V: Clone,
+    {
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.clear_locked(guard);
+        for (key, value) in &checkpoint.entries {
+            self.put_locked(key.clone(), Shared::boxed(value.clone(), &self.collector), guard);
+        }
+        drop(lock);
+    }
+
+    /// Atomically replaces the contents of the map with the pairs yielded by `items`.
+    ///
+    /// The new contents are built into a complete read-only map off to the side, which is then
+    /// published in a single store, while the dirty map is dropped under the lock. Unlike a
+    /// [`clear`](Self::clear) followed by inserts, or [`restore`](Self::restore), readers never
+    /// observe a mix of the two: every lookup sees either the entire old set or the entire new
+    /// one. This makes it suitable for hot-reloading configuration. If `items` yields a key more
+    /// than once, the last value wins.
+    ///
+    /// Removals of keys that were in the old read-only map don't take the lock, so one racing
+    /// with the replacement may find the key gone from the old contents and return `None`, even
+    /// if the new contents have it. Inserts racing with it always land in the new contents.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("timeout", 10, &guard);
+    /// map.insert("retries", 3, &guard);
+    /// map.replace_all(vec![("timeout", 30), ("workers", 8)], &guard);
+    /// assert_eq!(map.get(&"timeout", &guard), Some(&30));
+    /// assert_eq!(map.get(&"retries", &guard), None);
+    /// assert_eq!(map.len(), 2);
+    /// ```
+    pub fn replace_all<I>(&self, items: I, guard: &Guard<'_>)
+        where
+            I: IntoIterator<Item = (K, V)>,
+    {
+        self.check_guard(guard);
+        let items = items.into_iter();
+        let mut m = HashMap::with_capacity(items.size_hint().0);
+        for (key, value) in items {
+            let entry = Entry::new(Shared::boxed(value, &self.collector));
+            self.stamp(&entry);
+            if let Some(old) = m.insert(key, self.collector.link_boxed(entry)) {
+                // safety: the replaced entry was never shared
+                unsafe { guard.retire(old, seize::reclaim::boxed::<Entry<V>>) };
+            }
+        }
+        let len = m.len();
+        let read = Shared::boxed(ReadOnly { m, amended: false }, &self.collector);
+
+        let lock = self.lock();
+        let old_read = self.read.swap(read, Ordering::SeqCst, guard);
+        let old_dirty = self.dirty.swap(Shared::null(), Ordering::SeqCst, guard);
+        // safety: both tables have been unlinked above, and we hold the lock.
+        let removed = unsafe { self.retire_tables(old_read, old_dirty, guard) };
+        self.misses.store(0, Ordering::SeqCst);
+        let live = if len >= removed {
+            self.live.fetch_add(len - removed, Ordering::SeqCst).wrapping_add(len - removed)
+        } else {
+            self.live.fetch_sub(removed - len, Ordering::SeqCst).wrapping_sub(removed - len)
+        };
+        self.tombstones.store(0, Ordering::SeqCst);
+        self.last_promoted_len.store(len, Ordering::SeqCst);
+        if live == 0 {
+            self.emptied.notify_all();
+        }
+        drop(lock);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+    }
+
+    /// Retains only the elements specified by the predicate, and returns how many entries were
+    /// removed.
+    ///
+    /// In other words, remove all pairs `(k, v)` such that `f(&k,&v)` returns `false`.
+    ///
+    /// If `f` returns `false` for a given key/value pair, but the value for that pair is changed
+    /// before the removal is made, the new value will be removed regardless. Pairs that were
+    /// already removed by another thread are not counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..8 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert_eq!(map.retain(|&k, _| k % 2 == 0, &guard), 4);
+    /// assert_eq!(map.iter(&guard).count(), 4);
+    /// ```
+    pub fn retain<F>(&self, mut f: F, guard: &Guard<'_>) -> usize
+        where
+            F: FnMut(&K, &V) -> bool,
+    {
+        self.retain_with(&mut (), |_, k, v| f(k, v), guard)
+    }
+
+    /// Retains only the elements specified by the predicate, which can accumulate into `acc`
+    /// along the way, and returns how many entries were removed.
+    ///
+    /// This is [`retain`](Self::retain) for eviction passes that also gather statistics, without
+    /// having the predicate capture (and synchronize) external state. `f` is called once for
+    /// every visited pair, in order, on the calling thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..8 {
+    ///     map.insert(i, vec![0u8; i], &guard);
+    /// }
+    /// let mut evicted_bytes = 0;
+    /// let removed = map.retain_with(&mut evicted_bytes, |bytes, _, v| {
+    ///     if v.len() > 4 {
+    ///         *bytes += v.len();
+    ///         return false;
+    ///     }
+    ///     true
+    /// }, &guard);
+    /// assert_eq!(removed, 3);
+    /// assert_eq!(evicted_bytes, 5 + 6 + 7);
+    /// ```
+    pub fn retain_with<A, F>(&self, acc: &mut A, mut f: F, guard: &Guard<'_>) -> usize
+        where
+            F: FnMut(&mut A, &K, &V) -> bool,
+    {
+        self.check_guard(guard);
+        let mut removed = 0;
+        for (key, value) in self.iter(guard) {
+            if !f(acc, key, value) && self.remove(key, guard).is_some() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Removes the entries for which `f` returns `true`, and yields them, as the returned iterator
+    /// is consumed.
+    ///
+    /// This moves matching entries out in a single pass, e.g. the ready jobs of a map of pending
+    /// ones, leaving the others in place. The dirty map is promoted first, like for
+    /// [`iter`](Self::iter). Each matching value is then removed with a compare-and-swap, so a
+    /// value that another thread replaced or removed after `f` saw it is left alone and isn't
+    /// yielded. Entries the iterator hasn't reached yet are untouched, including when it is
+    /// dropped early.
+    ///
+    /// Like with [`remove`](Self::remove), the yielded values are retired rather than moved out,
+    /// so they are references that live as long as the guard.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..8 {
+    ///     map.insert(i, i * 10, &guard);
+    /// }
+    /// let mut ready: Vec<_> = map.drain_filter(|&k, _| k % 2 == 0, &guard).collect();
+    /// ready.sort();
+    /// assert_eq!(ready, vec![(&0, &0), (&2, &20), (&4, &40), (&6, &60)]);
+    /// assert_eq!(map.len(), 4);
+    /// assert_eq!(map.get(&1, &guard), Some(&10));
+    /// ```
+    pub fn drain_filter<'g, F>(&'g self, f: F, guard: &'g Guard<'_>) -> DrainFilter<'g, K, V, S, F>
+        where
+            F: FnMut(&K, &V) -> bool,
+    {
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.promote_locked(guard);
+        drop(lock);
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        DrainFilter {
+            map: self,
+            inner: unsafe { read.as_ref() }.map(|r| r.m.iter()),
+            pred: f,
+            guard,
+        }
+    }
+
+    /// Retains only the elements specified by the predicate, then compacts the map, and returns
+    /// how many entries were removed.
+    ///
+    /// This is [`retain`](Self::retain) followed by [`shrink_to_fit`](Self::shrink_to_fit), in a
+    /// single acquisition of the lock: the dirty map is promoted, the predicate is evaluated for
+    /// every live entry, and the read-only map is rebuilt with only the kept entries. The removed
+    /// entries are retired, so the map is left without any tombstones.
+    ///
+    /// `f` runs while holding the map's lock, so it must not use the map itself, or it
+    /// deadlocks.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..8 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert_eq!(map.retain_and_compact(|&k, _| k % 2 == 0, &guard), 4);
+    /// assert_eq!(map.inspect(&guard).removed, 0);
+    /// assert_eq!(map.len(), 4);
+    /// ```
+    pub fn retain_and_compact<F>(&self, mut f: F, guard: &Guard<'_>) -> usize
+        where
+            F: FnMut(&K, &V) -> bool,
+    {
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.promote_locked(guard);
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let mut removed = 0;
+        if let Some(r) = unsafe { read.as_ref() } {
+            for (key, e) in &r.m {
+                let e = unsafe { e.as_ref().unwrap() };
+                if let Some(value) = e.load(guard) {
+                    if !f(key, value) && e.remove(guard).is_some() {
+                        removed += 1;
+                        self.count_removed(true);
+                    }
+                }
+            }
+        }
+        self.compact_locked(0, guard);
+        drop(lock);
+        removed
+    }
+
+    /// Retains only the keys specified by the predicate, and returns how many entries were
+    /// removed.
+    ///
+    /// This is [`retain`](Self::retain) for predicates that only look at the key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..8 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert_eq!(map.retain_keys(|&k| k < 3, &guard), 5);
+    /// ```
+    pub fn retain_keys<F>(&self, mut f: F, guard: &Guard<'_>) -> usize
+        where
+            F: FnMut(&K) -> bool,
+    {
+        self.retain(|k, _| f(k), guard)
+    }
+
+    /// Retains only the values specified by the predicate, and returns how many entries were
+    /// removed.
+    ///
+    /// This is [`retain`](Self::retain) for predicates that only look at the value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..8 {
+    ///     map.insert(i, i * 10, &guard);
+    /// }
+    /// assert_eq!(map.retain_values(|&v| v >= 60, &guard), 6);
+    /// ```
+    pub fn retain_values<F>(&self, mut f: F, guard: &Guard<'_>) -> usize
+        where
+            F: FnMut(&V) -> bool,
+    {
+        self.retain(|_, v| f(v), guard)
+    }
+
+    /// Retains only the elements specified by the predicate, evaluating it in parallel, and
+    /// returns how many entries were removed.
+    ///
+    /// This is [`retain`](Self::retain) for expensive predicates over large maps. Any keys that
+    /// are only in the dirty map are promoted first, so every key present at the start is
+    /// visited. The predicate is then evaluated for all entries on the rayon thread pool, and
+    /// the rejected keys are removed in one go while holding the lock. Only the evaluation runs
+    /// in parallel; the removals stay serialized.
+    ///
+    /// As with `retain`, if the value for a rejected key is changed before the removals are
+    /// made, the new value is removed regardless.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for i in 0..100 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert_eq!(map.par_retain(|_, &v| v % 10 == 0, &guard), 90);
+    /// assert_eq!(map.len(), 10);
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn par_retain<F>(&self, f: F, guard: &Guard<'_>) -> usize
+        where
+            F: Fn(&K, &V) -> bool + Sync,
+    {
+        use rayon::prelude::*;
+
+        // `iter` promotes the dirty map. The references it hands out stay valid for as long as
+        // our guard is held, which covers the whole parallel evaluation below.
+        let entries: Vec<(&K, &V)> = self.iter(guard).collect();
+        let rejected: Vec<&K> = entries
+            .par_iter()
+            .filter(|(k, v)| !f(k, v))
+            .map(|&(k, _)| k)
+            .collect();
+        if rejected.is_empty() {
+            return 0;
+        }
+
+        let lock = self.lock();
+        let removed = rejected
+            .into_iter()
+            .filter(|k| self.remove_locked(*k, guard).is_some())
+            .count();
+        drop(lock);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+        removed
+    }
+
+    /// Removes every live key within `range`, and returns how many entries were removed.
+    ///
+    /// This is meant for eviction by key order, such as expiring all entries before a timestamp
+    /// key. The map is hash-based, so this scans the whole read-only and dirty map while holding
+    /// the lock rather than seeking to the range. Keys inserted within the range concurrently
+    /// may or may not be removed, depending on whether they land before the lock is taken.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for ts in 0..10u64 {
+    ///     map.insert(ts, format!("event {}", ts), &guard);
+    /// }
+    /// assert_eq!(map.remove_range(..5, &guard), 5);
+    /// assert_eq!(map.remove_range(8.., &guard), 2);
+    /// assert_eq!(map.len(), 3);
+    /// assert!(map.contains_key(&5, &guard));
+    /// ```
+    pub fn remove_range<R>(&self, range: R, guard: &Guard<'_>) -> usize
+        where
+            R: RangeBounds<K>,
+    {
+        self.check_guard(guard);
+        let lock = self.lock();
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return 0;
+        }
+        let r = unsafe { read.deref() };
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        let dirty = if r.amended && !dirty.is_null() {
+            Some(unsafe { dirty.deref() })
+        } else {
+            None
+        };
+
+        // removing from the dirty map drops its keys, and a miss can promote it, so the keys are
+        // collected up front instead of removed while walking the tables
+        let mut keys = Vec::new();
+        for (key, e) in &r.m {
+            if range.contains(key) && unsafe { e.as_ref().unwrap() }.load(guard).is_some() {
+                keys.push(key.clone());
+            }
+        }
+        if let Some(d) = dirty {
+            for (key, e) in d.iter() {
+                if range.contains(key)
+                    && !r.m.contains_key(key)
+                    && unsafe { e.as_ref().unwrap() }.load(guard).is_some()
+                {
+                    keys.push(key.clone());
+                }
+            }
+        }
+
+        let removed = keys
+            .iter()
+            .filter(|k| self.remove_locked(*k, guard).is_some())
+            .count();
+        drop(lock);
+        #[cfg(feature = "metrics")]
+        if removed > 0 {
+            self.record_len();
+        }
+        removed
+    }
+
+    /// Removes the entry that was inserted longest ago, and returns its key and a clone of its
+    /// value, or `None` if the map is empty.
+    ///
+    /// Together with [`len`](Self::len), this makes a bounded FIFO cache without a separate
+    /// queue. Insertion order counts when a key gains a value; replacing the value of a present
+    /// key doesn't move it. Finding the oldest entry scans the whole map while holding the lock.
+    ///
+    /// An insertion that races with this call may not have recorded its position yet, in which
+    /// case its entry can be taken for older than it is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map was not built with
+    /// [`track_insertion_order`](crate::MapBuilder::track_insertion_order).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::builder().track_insertion_order(true).build();
+    /// let guard = map.guard();
+    /// for k in ["a", "b", "c"] {
+    ///     map.insert(k, k.len(), &guard);
+    ///     if map.len() > 2 {
+    ///         map.evict_oldest(&guard);
+    ///     }
+    /// }
+    /// map.insert("b", 10, &guard);
+    /// assert_eq!(map.evict_oldest(&guard), Some(("b", 10)));
+    /// assert_eq!(map.evict_oldest(&guard), Some(("c", 1)));
+    /// assert_eq!(map.evict_oldest(&guard), None);
+    /// ```
+    pub fn evict_oldest(&self, guard: &Guard<'_>) -> Option<(K, V)>
+        where
+            V: Clone,
+    {
+        self.check_guard(guard);
+        assert!(
+            self.next_seq.is_some(),
+            "evict_oldest requires a map built with track_insertion_order"
+        );
+        let lock = self.lock();
+        let read = self.read.load(Ordering::SeqCst, guard);
+        if read.is_null() {
+            return None;
+        }
+        let r = unsafe { read.deref() };
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        let dirty = if r.amended && !dirty.is_null() {
+            Some(unsafe { dirty.deref() })
+        } else {
+            None
+        };
+
+        let dirty_only = dirty
+            .into_iter()
+            .flat_map(|d| d.iter())
+            .filter(|(key, _)| !r.m.contains_key(*key));
+        let oldest = r.m.iter()
+            .chain(dirty_only)
+            .map(|(key, e)| (key, unsafe { e.as_ref().unwrap() }))
+            .filter(|(_, e)| e.load(guard).is_some())
+            .min_by_key(|(_, e)| e.seq.load(Ordering::SeqCst))
+            .map(|(key, _)| key.clone())?;
+
+        let value = self.remove_locked(&oldest, guard).cloned();
+        drop(lock);
+        #[cfg(feature = "metrics")]
+        self.record_len();
+        value.map(|value| (oldest, value))
+    }
+
+    fn dirty_locked(&self, key: K, entry_value: Shared<V>, guard: &Guard<'_>) {
+        let dirty = self.dirty.load(Ordering::SeqCst, guard);
+        if dirty.is_null() {
+            return;
+        }
+        let r = unsafe { self.read.load(Ordering::SeqCst, guard).deref() };
+        let mut fresh = None;
+        let map = if self.keep_dirty_allocation {
+            // refill the map left behind by the last promotion rather than allocating a new one
+            let d = unsafe { dirty.as_ptr().as_mut().unwrap() };
+            d.clear();
+            d.reserve(r.m.len() + 1);
+            d
+        } else {
+            // keep any capacity that was set aside for the dirty map, e.g. by `with_capacity`
+            let capacity = r.m.len().max(unsafe { dirty.deref() }.capacity());
+            fresh.insert(HashMap::with_capacity(capacity))
+        };
+        for (key, value) in &r.m {
+            if !unsafe { value.as_ref().unwrap() }.try_expunge_locked(guard) {
+                map.insert(key.clone(), *value);
+            }
+        }
+        let entry = self.collector.link_boxed(Entry::new(entry_value));
+        self.stamp(unsafe { &*entry });
+        map.insert(key, entry);
+        if let Some(map) = fresh {
+            let old_dirty = self.dirty.swap(Shared::boxed(map, &self.collector), Ordering::SeqCst, guard);
+            // safety: the old dirty map is unlinked, and its entries are all still referenced from
+            // the read-only map or the new dirty map.
+            unsafe { guard.retire_shared(old_dirty) };
+        }
+    }
+}
+
+/// Helpers for caches of shared resources that hold [`Arc`]s.
+impl<K, T, S> Map<K, Arc<T>, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        T: Sync + Send,
+        S: BuildHasher,
+{
+    /// Returns a clone of the `Arc` for `key`, inserting the one created by `f` if the key is
+    /// absent.
+    ///
+    /// This is [`get_or_insert_with`](Self::get_or_insert_with) for resource caches: concurrent
+    /// calls for the same key run `f` only once, and the guard is pinned internally and dropped
+    /// before returning, so the caller gets an owned `Arc` with no guard lifetime to track.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use syncmap::map::Map;
+    ///
+    /// let connections: Map<&str, Arc<String>> = Map::new();
+    /// let a = connections.get_or_insert_arc("db", || Arc::new(String::from("conn 1")));
+    /// let b = connections.get_or_insert_arc("db", || Arc::new(String::from("conn 2")));
+    /// assert!(Arc::ptr_eq(&a, &b));
+    /// assert_eq!(*a, "conn 1");
+    /// ```
+    pub fn get_or_insert_arc<F>(&self, key: K, f: F) -> Arc<T>
+        where
+            F: FnOnce() -> Arc<T>,
+    {
+        self.get_or_insert_with_cloned(key, f)
+    }
+}
+
+/// Helpers for caches that hold [`Weak`] references, so that cached values are dropped once
+/// nothing else uses them.
+impl<K, T, S> Map<K, Weak<T>, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        T: Sync + Send,
+        S: BuildHasher,
+{
+    /// Inserts a weak reference to `value` under `key`.
+    ///
+    /// The map doesn't keep `value` alive: once every `Arc` to it is dropped,
+    /// [`get_upgraded`](Self::get_upgraded) returns `None` for `key` and removes the entry.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// let resource = Arc::new(String::from("connection"));
+    /// map.insert_weak("db", &resource, &guard);
+    /// assert_eq!(map.get_upgraded(&"db", &guard), Some(resource.clone()));
+    ///
+    /// drop(resource);
+    /// assert_eq!(map.get_upgraded(&"db", &guard), None);
+    /// assert!(!map.contains_key(&"db", &guard));
+    /// ```
+    pub fn insert_weak(&self, key: K, value: &Arc<T>, guard: &Guard<'_>) {
+        self.insert(key, Arc::downgrade(value), guard);
+    }
+
+    /// Returns the value for `key`, if it is present and still alive.
+    ///
+    /// If the key holds a weak reference whose value has been dropped, the entry is removed, so
+    /// dead entries are cleaned up lazily as they are looked up. An entry that was replaced
+    /// concurrently is left alone.
+    pub fn get_upgraded<Q>(&self, key: &Q, guard: &Guard<'_>) -> Option<Arc<T>>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let weak = self.get(key, guard)?;
+        if let Some(value) = weak.upgrade() {
+            return Some(value);
+        }
+
+        let read = self.read.load(Ordering::SeqCst, guard);
+        let r = unsafe { read.deref() };
+        let (e, read_resident) = match r.m.get(key) {
+            Some(e) => (unsafe { &**e.as_ref().unwrap() }, true),
+            None => {
+                let lock = self.lock();
+                let e = self.entry_locked(key, guard);
+                drop(lock);
+                (e?, false)
+            }
+        };
+        if e.remove_if_ptr(weak, guard) {
+            self.count_removed(false);
+            if read_resident {
+                self.tombstones.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        None
+    }
+}
+
+impl<K, V, S> Map<K, V, S>
+    where
+        K: Clone + Ord,
+{
+    /// Clears the map, removing all key-value pairs.
+    ///
+    /// This pins a guard internally; use [`clear_with`](Self::clear_with) if you already hold one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// use syncmap::map::Map;
+    /// let map = Map::new();
+    /// map.insert(1, "a", &map.guard());
+    /// map.clear();
+    /// assert_eq!(map.get(&1, &map.guard()), None);
+    /// ```
+    pub fn clear(&self) {
+        let guard = self.guard();
+        self.clear_with(&guard);
+    }
+
+    /// Clears the map using an existing guard, removing all key-value pairs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// use syncmap::map::Map;
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert(1, "a",&guard);
+    /// map.clear_with(&guard);
+    /// ```
+    pub fn clear_with<'g>(&'g self, guard: &'g Guard<'_>) {
+        self.check_guard(guard);
+        let lock = self.lock();
+        self.clear_locked(guard);
+        drop(lock);
+    }
+
+    /// Replaces both maps with empty ones. Must be called while holding `self.lock`.
+    fn clear_locked<'g>(&'g self, guard: &'g Guard<'_>) {
+        let old_dirty = self.dirty.swap(Shared::boxed(HashMap::new(), &self.collector), Ordering::SeqCst, guard);
+        let old_read = self.read.swap(Shared::boxed(ReadOnly::new(), &self.collector), Ordering::SeqCst, guard);
+        // safety: both tables have been unlinked above.
+        let removed = unsafe { self.retire_tables(old_read, old_dirty, guard) };
+        // `get` may count a miss concurrently, so reset unconditionally.
+        self.misses.store(0, Ordering::SeqCst);
+        // Only uncount the values taken out: a racing store or removal on the old tables either
+        // landed before they were expunged, and was counted, or fails and retries under the lock.
+        if self.live.fetch_sub(removed, Ordering::SeqCst) == removed {
+            self.emptied.notify_all();
+        }
+        self.tombstones.store(0, Ordering::SeqCst);
+        self.last_promoted_len.store(0, Ordering::SeqCst);
+    }
+
+    /// Retires an unlinked read-only map and dirty map, along with every entry in either, and
+    /// returns how many of those entries held a value.
+    ///
+    /// Every entry is retired first, so that threads still holding one of the old tables can
+    /// no longer store into or remove from it without the lock, and go to the new tables instead,
+    /// while those only reading the old tables still see every value they held.
+    ///
+    /// # Safety
+    ///
+    /// Both tables must have been unlinked from the map, so that only threads that are still
+    /// holding a guard can observe them or their entries. Must be called while holding
+    /// `self.lock`, so that no entry is revived in between.
+    unsafe fn retire_tables(
+        &self,
+        old_read: Shared<'_, ReadOnly<K, V>>,
+        old_dirty: Shared<'_, HashMap<K, EntryPtr<V>>>,
+        guard: &Guard<'_>,
+    ) -> usize {
+        let mut entries = HashSet::new();
+        if let Some(read) = old_read.as_ref() {
+            entries.extend(read.m.values().copied());
+        }
+        if let Some(dirty) = old_dirty.as_ref() {
+            entries.extend(dirty.values().copied());
+        }
+        let mut removed = 0;
+        for e in entries {
+            if e.as_ref().unwrap().retire_locked(guard) {
+                removed += 1;
+            }
+            guard.retire(e, seize::reclaim::boxed::<Entry<V>>);
+        }
+        guard.retire_shared(old_dirty);
+        guard.retire_shared(old_read);
+        removed
+    }
+}
+
+/// The map's lock, as held by a [`MapEntry`](crate::MapEntry). See [`Map::lock_for_entry`].
+pub(crate) struct EntryLock<'a> {
+    owner: &'a Mutex<Option<ThreadId>>,
+    held: &'a AtomicBool,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl Drop for EntryLock<'_> {
+    fn drop(&mut self) {
+        // cleared before `_lock` is released, so the next holder can't have its marker erased
+        self.held.store(false, Ordering::SeqCst);
+        *self.owner.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// Marks `key` as being computed by [`Map::get_or_insert_with`] for as long as it is alive, and
+/// wakes up any waiting callers once it is dropped (including when the computation panics).
+struct InFlight<'a, K, V, S>
+    where
+        K: Hash + Eq,
+{
+    map: &'a Map<K, V, S>,
+    key: &'a K,
+}
+
+impl<K, V, S> Drop for InFlight<'_, K, V, S>
+    where
+        K: Hash + Eq,
+{
+    fn drop(&mut self) {
+        let mut in_flight = self.map.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+        in_flight.remove(self.key);
+        drop(in_flight);
+        self.map.in_flight_done.notify_all();
+    }
+}
+
+/// The contents of a map at some point in time, as returned by [`Map::checkpoint`].
+///
+/// Entries are kept sorted by key, so two checkpoints of maps with the same contents compare
+/// equal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint<K, V> {
+    entries: Vec<(K, V)>,
+}
+
+impl<K, V> Checkpoint<K, V> {
+    /// Returns the number of entries in the checkpoint.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the checkpoint contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the captured entries, sorted by key.
+    pub fn entries(&self) -> &[(K, V)] {
+        &self.entries
+    }
+}
+
+/// The values stored by a batch of inserts, as returned by [`Map::insert_batch_view`].
+///
+/// The view is fixed when the batch is applied: later writes to the map don't show through it.
+pub struct BatchView<'g, K, V> {
+    values: HashMap<K, &'g V>,
+}
+
+impl<'g, K, V> BatchView<'g, K, V>
+    where
+        K: Hash + Eq,
+{
+    /// Returns the value the batch stored for the key, if the key was part of the batch.
+    pub fn get<Q>(&self, key: &Q) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+    {
+        self.values.get(key).copied()
+    }
+
+    /// Returns `true` if the key was part of the batch.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+    {
+        self.values.contains_key(key)
+    }
+
+    /// Returns the number of distinct keys in the batch.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns `true` if the batch was empty.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// An iterator visiting the keys of the batch and the values stored for them, in arbitrary
+    /// order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &'g V)> {
+        self.values.iter().map(|(k, v)| (k, *v))
+    }
+}
+
+/// The error returned when a `Vec` of pairs converted into a [`Map`] repeats a key.
+///
+/// Holds the first key that was found a second time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateKey<K>(pub K);
+
+impl<K: Debug> fmt::Display for DuplicateKey<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "duplicate key {:?}", self.0)
+    }
+}
+
+impl<K: Debug> std::error::Error for DuplicateKey<K> {}
+
+/// The path an insert took through the map, as returned by [`Map::insert_profiled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertPath {
+    /// The key was in the read-only map, and its value was stored without taking the lock.
+    LockFree,
+    /// The key was already known, but storing its value required the lock: it was only in the
+    /// dirty map, or its entry in the read-only map had been expunged.
+    Locked,
+    /// The key was new, and the first one since the last promotion, so the read-only map was
+    /// marked as amended and a dirty map was built from it.
+    NewInReadAmend,
+    /// The key was new, and added to the existing dirty map.
+    NewInDirty,
+}
+
+/// The outcome of a lookup that must not block, as returned by [`Map::poll_get`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Poll3<T> {
+    /// The key has a value.
+    Found(T),
+    /// The key has no value.
+    NotFound,
+    /// The key may be in the dirty map, but finding out would have meant waiting for the lock.
+    WouldBlock,
+}
+
+/// A breakdown of a map's entries, as returned by [`Map::inspect`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MapInspection {
+    /// Live entries in the read-only map, which can be loaded without taking the lock.
+    pub read: usize,
+    /// Live entries that are only in the dirty map, so loading them requires the lock until the
+    /// next promotion.
+    pub dirty_only: usize,
+    /// Entries whose value has been removed but which still occupy a slot.
+    pub removed: usize,
+    /// Removed entries in the read-only map that were left out of the dirty map.
+    pub expunged: usize,
+}
+
+/// Counts of how lookups were served, as returned by [`Map::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MapStats {
+    /// Lookups answered from the read-only map, without taking the lock.
+    pub read_hits: u64,
+    /// Lookups whose value was only found after taking the lock, in the dirty map.
+    pub dirty_hits: u64,
+    /// Lookups that found no value for their key.
+    pub misses: u64,
+}
+
+struct ReadOnly<K, V> {
+    m: HashMap<K, EntryPtr<V>>,
+    amended: bool,
+}
+
+impl<K, V> ReadOnly<K, V> {
+    fn new() -> Self <> {
+        Self {
+            m: HashMap::new(),
+            amended: false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const ITER: u64 = 32 * 1024;
+
+    #[test]
+    fn map_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Map<usize, usize>>();
+        assert_send_sync::<Map<String, Vec<u8>>>();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn remove_and_insert() {
+        let map = Arc::new(Map::<usize, usize>::new());
+        let guard = map.guard();
+        map.insert(1, 1, &guard);
+        assert_eq!(map.remove(&1, &guard), Some(&1));
+        assert_eq!(map.remove(&1, &guard), None)
+    }
+
+    #[test]
+    fn with_many_mut_inserts_and_removes() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        map.insert(1, 1, &guard);
+        map.with_many_mut(&[1, 2], |values| {
+            assert_eq!(values, &[Some(1), None]);
+            vec![None, Some(2)]
+        }, &guard);
+        assert_eq!(map.get(&1, &guard), None);
+        assert_eq!(map.get(&2, &guard), Some(&2));
+    }
+
+    #[test]
+    fn replace_skips_removed_keys() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        map.insert(1, 1, &guard);
+        map.insert(2, 2, &guard);
+        assert_eq!(map.replace(&2, 20, &guard), Some(&2));
+        assert_eq!(map.remove(&1, &guard), Some(&1));
+        assert_eq!(map.replace(&1, 10, &guard), None);
+        assert_eq!(map.get(&1, &guard), None);
+        assert_eq!(map.get(&2, &guard), Some(&20));
+    }
+
+    #[test]
+    fn inspect_tracks_entry_states() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        for i in 0..4 {
+            map.insert(i, i, &guard);
+        }
+        assert_eq!(map.inspect(&guard), MapInspection { read: 0, dirty_only: 4, removed: 0, expunged: 0 });
+
+        // iterating promotes everything into the read-only map
+        assert_eq!(map.iter(&guard).count(), 4);
+        map.remove(&0, &guard);
+        assert_eq!(map.inspect(&guard), MapInspection { read: 3, dirty_only: 0, removed: 1, expunged: 0 });
+
+        // the next new key rebuilds the dirty map without the removed entry
+        map.insert(4, 4, &guard);
+        assert_eq!(map.inspect(&guard), MapInspection { read: 3, dirty_only: 1, removed: 0, expunged: 1 });
+    }
+
+    #[test]
+    fn any_and_sample_skip_removed_entries() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        for i in 0..16 {
+            map.insert(i, i, &guard);
+        }
+        for i in 0..15 {
+            map.remove(&i, &guard);
+        }
+        assert_eq!(map.any(&guard), Some((&15, &15)));
+        for _ in 0..16 {
+            assert_eq!(map.sample(&guard), Some((&15, &15)));
+        }
+        map.remove(&15, &guard);
+        assert_eq!(map.any(&guard), None);
+        assert_eq!(map.sample(&guard), None);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn absent_keys_do_not_count_as_misses() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        map.insert(1, 1, &guard);
+        for i in 100..200 {
+            assert_eq!(map.get(&i, &guard), None);
+        }
+        assert_eq!(map.misses.load(Ordering::SeqCst), 0);
+        assert_eq!(map.inspect(&guard).dirty_only, 1);
+
+        assert_eq!(map.get(&1, &guard), Some(&1));
+        assert_eq!(map.misses.load(Ordering::SeqCst), 1);
+        assert_eq!(map.get(&1, &guard), Some(&1));
+        assert_eq!(map.inspect(&guard).read, 1);
+    }
+
+    #[test]
+    fn shrink_to_keeps_requested_capacity() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        for i in 0..90 {
+            map.remove(&i, &guard);
+        }
+        map.shrink_to(64, &guard);
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        let r = unsafe { read.deref() };
+        assert_eq!(r.m.len(), 10);
+        assert!(r.m.capacity() >= 64);
+        assert_eq!(map.inspect(&guard), MapInspection { read: 10, dirty_only: 0, removed: 0, expunged: 0 });
+
+        map.shrink_to_fit(&guard);
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        assert!(unsafe { read.deref() }.m.capacity() < 64);
+        for i in 90..100 {
+            assert_eq!(map.get(&i, &guard), Some(&i));
+        }
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let map = Map::<usize, String>::new();
+        let guard = map.guard();
+        for i in 0..32 {
+            map.insert(i, i.to_string(), &guard);
+        }
+        let checkpoint = map.checkpoint(&guard);
+        assert_eq!(checkpoint.len(), 32);
+
+        for i in 0..16 {
+            map.remove(&i, &guard);
+        }
+        for i in 32..48 {
+            map.insert(i, i.to_string(), &guard);
+        }
+        map.insert(20, String::from("changed"), &guard);
+        assert_ne!(map.checkpoint(&guard), checkpoint);
+
+        map.restore(&checkpoint, &guard);
+        assert_eq!(map.checkpoint(&guard), checkpoint);
+        assert_eq!(map.get(&20, &guard).map(String::as_str), Some("20"));
+        assert_eq!(map.get(&40, &guard), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn promoted_lookups_never_take_the_lock() {
+        let map = Arc::new(Map::<usize, usize>::new());
+        {
+            let guard = map.guard();
+            for i in 0..64 {
+                map.insert(i, i, &guard);
+            }
+            // promote everything into the read-only map
+            assert_eq!(map.iter(&guard).count(), 64);
+        }
+
+        let lock = map.lock();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let map1 = map.clone();
+        thread::spawn(move || {
+            let guard = map1.guard();
+            for i in 0..128 {
+                assert_eq!(map1.contains_key(&i, &guard), i < 64);
+                assert_eq!(map1.get(&i, &guard).is_some(), i < 64);
+            }
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(10)).is_ok(), "lookups blocked on the lock");
+        drop(lock);
+    }
+
+    #[test]
+    fn entry_handle_updates_the_stored_value() {
+        let map = Map::new();
+        let guard = map.guard();
+        let handle = map.get_or_insert_entry(1, 10, &guard);
+        assert_eq!(map.get_or_insert_entry(1, 20, &guard).load(), Some(&10));
+        assert_eq!(handle.store(11), Ok(&10));
+        let current = handle.load().unwrap();
+        assert_eq!(handle.compare_and_swap(&11, 12), Err(12));
+        assert_eq!(handle.compare_and_swap(current, 12), Ok(&11));
+        assert_eq!(map.get(&1, &guard), Some(&12));
+        map.remove(&1, &guard);
+        assert_eq!(handle.load(), None);
+        assert_eq!(handle.store(13), Err(13));
+        assert_eq!(map.get(&1, &guard), None);
+    }
+
+    #[test]
+    fn entry_handles_see_reinserts_only_into_the_same_entry() {
+        let map = Map::new();
+        let guard = map.guard();
+
+        // 1 is in the read-only map, where removal leaves its emptied entry behind; the other
+        // keys keep the tombstone below the compaction threshold
+        let read = map.get_or_insert_entry(1, 10, &guard);
+        for i in 100..104 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        map.remove(&1, &guard);
+        assert_eq!(read.load(), None);
+        map.insert(1, 11, &guard);
+        assert_eq!(read.load(), Some(&11));
+        assert_eq!(read.store(12), Ok(&11));
+        assert_eq!(map.get(&1, &guard), Some(&12));
+
+        // 2 is only in the dirty map, where removal drops its entry
+        let dirty = map.get_or_insert_entry(2, 20, &guard);
+        map.remove(&2, &guard);
+        map.insert(2, 21, &guard);
+        assert_eq!(dirty.load(), None);
+        assert_eq!(dirty.store(22), Err(22));
+        assert_eq!(map.get(&2, &guard), Some(&21));
+
+        // compaction drops the emptied entry of a read-only key as well
+        map.remove(&1, &guard);
+        map.compact_now(&guard);
+        map.insert(1, 13, &guard);
+        assert_eq!(read.load(), None);
+        assert_eq!(map.get(&1, &guard), Some(&13));
+    }
+
+    #[test]
+    fn removed_values_are_reclaimed() {
+        let value = Arc::new(());
+        let map = Map::new();
+        {
+            let guard = map.guard();
+            map.insert(1, value.clone(), &guard);
+            assert!(map.remove(&1, &guard).is_some());
+        }
+        drop(map);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn dropping_maps_drops_every_value() {
+        let value = Arc::new(());
+        for round in 0..2000 {
+            let map = Map::new();
+            let guard = map.guard();
+            for i in 0..8 {
+                map.insert(i, value.clone(), &guard);
+            }
+            if round % 2 == 0 {
+                // move everything into the read-only map
+                map.iter(&guard).count();
+            }
+            // overwritten, removed and expunged values
+            map.insert(0, value.clone(), &guard);
+            map.remove(&1, &guard);
+            map.insert(100, value.clone(), &guard);
+            if round % 3 == 0 {
+                map.clear_with(&guard);
+                map.insert(200, value.clone(), &guard);
+            }
+            if round % 5 == 0 {
+                let cloned = map.clone();
+                assert_eq!(cloned.len(), map.len());
+            }
+            drop(guard);
+            drop(map);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn get_key_returns_the_stored_key() {
+        let map = Map::new();
+        let guard = map.guard();
+        map.insert(String::from("a"), 1, &guard);
+        map.insert(String::from("b"), 2, &guard);
+        // "b" is only in the dirty map, so looking it up promotes it
+        let key = map.get_key("b", &guard).unwrap();
+        assert_eq!(key, "b");
+        assert!(!unsafe { map.read.load(Ordering::SeqCst, &guard).deref() }.amended);
+        map.insert(String::from("c"), 3, &guard);
+        assert_eq!(key, "b");
+        map.remove("a", &guard);
+        assert_eq!(map.get_key("a", &guard), None);
+    }
+
+    #[test]
+    fn with_capacity_sizes_the_dirty_map() {
+        let map = Map::with_capacity(64);
+        let guard = map.guard();
+        for i in 0..64 {
+            map.insert(i, i, &guard);
+        }
+        let dirty = map.dirty.load(Ordering::SeqCst, &guard);
+        assert!(unsafe { dirty.deref() }.capacity() >= 64);
+        // all of the inserts went to the dirty map without being promoted
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        assert!(unsafe { read.deref() }.amended);
+        assert!(unsafe { read.deref() }.m.is_empty());
+    }
+
+    #[test]
+    fn reservations_survive_rebuilds() {
+        let map = Map::new();
+        let guard = map.guard();
+        map.reserve_read(100, &guard);
+        map.reserve_dirty(200, &guard);
+        map.insert(1, 1, &guard);
+        let dirty = map.dirty.load(Ordering::SeqCst, &guard);
+        assert!(unsafe { dirty.deref() }.capacity() >= 200);
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        assert!(unsafe { read.deref() }.m.capacity() >= 100);
+
+        let lock = map.lock();
+        map.promote_locked(&guard);
+        drop(lock);
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        assert!(unsafe { read.deref() }.m.capacity() >= 100);
+        assert_eq!(map.get(&1, &guard), Some(&1));
+    }
+
+    #[test]
+    fn extend_keeps_the_last_duplicate() {
+        let map = Map::new();
+        (&map).extend((0..10).map(|i| (i % 3, i)));
+        let guard = map.guard();
+        assert_eq!(map.get(&0, &guard), Some(&9));
+        assert_eq!(map.get(&1, &guard), Some(&7));
+        assert_eq!(map.get(&2, &guard), Some(&8));
+        assert_eq!(map.iter(&guard).count(), 3);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn get_or_insert_with_runs_one_closure_per_key() {
+        let map = Arc::new(Map::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+        let first = {
+            let map = map.clone();
+            let calls = calls.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                *map.get_or_insert_with(1, || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    10
+                }, &guard)
+            })
+        };
+        started_rx.recv().unwrap();
+        let second = {
+            let map = map.clone();
+            let calls = calls.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                *map.get_or_insert_with(1, || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    20
+                }, &guard)
+            })
+        };
+
+        // other keys are not blocked by the running closure
+        let guard = map.guard();
+        map.insert(2, 2, &guard);
+        assert_eq!(map.get(&2, &guard), Some(&2));
+
+        release_tx.send(()).unwrap();
+        assert_eq!(first.join().unwrap(), 10);
+        assert_eq!(second.join().unwrap(), 10);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn get_or_insert_with_keeps_a_concurrent_insert() {
+        let map = Arc::new(Map::new());
+        let computed = Arc::new("computed");
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+        let racer = {
+            let map = map.clone();
+            let computed = computed.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                let v = map.get_or_insert_with(1, || {
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    computed
+                }, &guard);
+                **v
+            })
+        };
+        started_rx.recv().unwrap();
+        map.insert(1, Arc::new("inserted"), &map.guard());
+        release_tx.send(()).unwrap();
+
+        assert_eq!(racer.join().unwrap(), "inserted");
+        assert_eq!(map.get(&1, &map.guard()).map(|v| **v), Some("inserted"));
+        // the computed value was dropped instead of being stored
+        assert_eq!(Arc::strong_count(&computed), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn counters_saturate_without_losing_updates() {
+        let map = Arc::new(Map::new());
+        map.insert("clamped", u16::MAX - 100, &map.guard());
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    let guard = map.guard();
+                    for _ in 0..1000 {
+                        map.fetch_add_saturating("exact", 1u16, &guard);
+                        map.fetch_add_saturating("clamped", 1, &guard);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let guard = map.guard();
+        assert_eq!(map.get(&"exact", &guard), Some(&4000));
+        assert_eq!(map.get(&"clamped", &guard), Some(&u16::MAX));
+        assert_eq!(map.checked_fetch_add("exact", u16::MAX, &guard), None);
+        assert_eq!(map.get(&"exact", &guard), Some(&4000));
+    }
+
+    #[test]
+    fn len_counts_both_maps() {
+        let map = Map::new();
+        let guard = map.guard();
+        for i in 0..4 {
+            map.insert(i, i, &guard);
+        }
+        assert_eq!((map.len(), map.len_read(&guard), map.len_dirty(&guard)), (4, 0, 4));
+        let lock = map.lock();
+        map.promote_locked(&guard);
+        drop(lock);
+        assert_eq!((map.len(), map.len_read(&guard), map.len_dirty(&guard)), (4, 4, 0));
+        map.insert(4, 4, &guard);
+        map.remove(&0, &guard);
+        assert_eq!((map.len(), map.len_read(&guard), map.len_dirty(&guard)), (4, 3, 5));
+    }
+
+    #[test]
+    fn insert_if_absent_fills_removed_keys() {
+        let map = Map::new();
+        let guard = map.guard();
+        assert!(map.insert_if_absent(1, 1, &guard));
+        map.iter(&guard).count();
+        // the key is in the read-only map now
+        assert!(!map.insert_if_absent(1, 2, &guard));
+        map.remove(&1, &guard);
+        assert!(map.insert_if_absent(1, 3, &guard));
+        assert_eq!(map.get(&1, &guard), Some(&3));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn insert_if_absent_never_overwrites_a_racing_insert() {
+        use std::sync::{Arc, Barrier};
+
+        let map = Arc::new(Map::new());
+        let guard = map.guard();
+        map.insert(0, 0, &guard);
+        map.iter(&guard).count();
+        drop(guard);
+        for _ in 0..200 {
+            // leave an emptied entry in the read-only map for both threads to fill
+            map.remove(&0, &map.guard());
+            let barrier = Arc::new(Barrier::new(2));
+            let inserter = {
+                let (map, barrier) = (map.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    let guard = map.guard();
+                    barrier.wait();
+                    map.insert(0, 1, &guard);
+                })
+            };
+            let guard = map.guard();
+            barrier.wait();
+            let filled = map.insert_if_absent(0, 2, &guard);
+            inserter.join().unwrap();
+            // the plain insert always wins: either it came last, or it replaced our value
+            assert_eq!(map.get(&0, &guard), Some(&1), "insert_if_absent won: {}", filled);
+        }
+    }
+
+    #[test]
+    fn expunged_entries_are_revived_through_the_dirty_map() {
+        let map = Map::new();
+        let guard = map.guard();
+        map.insert(1, "a", &guard);
+        // a live key keeps the removal below from compacting the read-only map
+        map.insert(0, "z", &guard);
+        map.iter(&guard).count();
+        map.remove(&1, &guard);
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        let e = unsafe { &**read.deref().m[&1] };
+        assert_eq!(e.state(&guard), EntryState::Empty);
+
+        // adding a new key builds the dirty map, which leaves out the removed entry
+        map.insert(2, "b", &guard);
+        assert_eq!(e.state(&guard), EntryState::Expunged);
+        map.insert(1, "c", &guard);
+        assert_eq!(e.state(&guard), EntryState::Live(&"c"));
+
+        // the revived key must survive the next promotion
+        let lock = map.lock();
+        map.promote_locked(&guard);
+        drop(lock);
+        assert_eq!(map.get(&1, &guard), Some(&"c"));
+        assert_eq!(map.get(&2, &guard), Some(&"b"));
+    }
+
+    #[test]
+    fn reinserting_a_removed_key_reuses_its_entry() {
+        let map = Map::new();
+        let guard = map.guard();
+        map.insert(1, 0, &guard);
+        // keep the tombstone of key 1 from triggering a compaction
+        for i in 2..12 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        let entry = unsafe { read.deref() }.m[&1];
+
+        for i in 0..1000 {
+            assert_eq!(map.remove(&1, &guard), Some(&i));
+            assert_eq!(map.get_or_insert(1, i + 1, &guard), &(i + 1));
+            // every so often, add a new key while 1 is removed, which expunges its entry
+            if i % 100 == 0 {
+                map.remove(&1, &guard);
+                map.insert(-i, 0, &guard);
+                map.insert(1, i + 1, &guard);
+                map.iter(&guard).count();
+            }
+        }
+
+        let read = map.read.load(Ordering::SeqCst, &guard);
+        assert_eq!(unsafe { read.deref() }.m[&1], entry);
+        assert_eq!(map.len(), 21);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn wait_empty_returns_once_workers_drain_the_map() {
+        let map = Arc::new(Map::new());
+        {
+            let guard = map.guard();
+            for i in 0..1000 {
+                map.insert(i, i, &guard);
+            }
+            // overwrites and removals of missing keys must not throw off the count
+            map.insert(0, 0, &guard);
+            map.remove(&1000, &guard);
+        }
+        assert!(!map.wait_empty(Some(Duration::from_millis(1))));
+
+        let workers: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    let guard = map.guard();
+                    for i in (t..1000).step_by(4) {
+                        map.remove(&i, &guard);
+                    }
+                })
+            })
+            .collect();
+        assert!(map.wait_empty(Some(Duration::from_secs(10))));
+        assert!(map.is_empty());
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn clear_and_replace_all_keep_the_count_exact_under_racing_writers() {
+        let map = Arc::new(Map::<usize, usize>::new());
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    let guard = map.guard();
+                    for i in 0..ITER as usize / 4 {
+                        let key = (i + t) % 32;
+                        match i % 4 {
+                            0 => {
+                                map.remove(&key, &guard);
+                            }
+                            3 => {
+                                map.iter(&guard).count();
+                            }
+                            _ => {
+                                map.insert(key, i, &guard);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for round in 0..200 {
+            let guard = map.guard();
+            if round % 2 == 0 {
+                map.clear_with(&guard);
+            } else {
+                map.replace_all((0..16).map(|k| (k, round)), &guard);
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let guard = map.guard();
+        assert_eq!(map.len(), map.iter(&guard).count());
+        map.clear_with(&guard);
+        assert!(map.wait_empty(Some(Duration::from_secs(10))));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn compare_and_swap_ptr_loses_no_updates() {
+        let map = Arc::new(Map::new());
+        map.insert("n", 0, &map.guard());
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let guard = map.guard();
+                        loop {
+                            let current = map.get(&"n", &guard).unwrap();
+                            if map.compare_and_swap_ptr(&"n", current, current + 1, &guard).is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(map.get(&"n", &map.guard()), Some(&2000));
+    }
+
+    #[test]
+    fn remove_compacts_tombstones() {
+        let map = Map::new();
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        for i in 0..90 {
+            map.remove(&i, &guard);
+        }
+        let inspection = map.inspect(&guard);
+        assert_eq!(inspection.read, 10);
+        assert!(inspection.removed <= 10, "{} tombstones left", inspection.removed);
+        for i in 90..100 {
+            assert_eq!(map.get(&i, &guard), Some(&i));
+        }
+    }
+
+    #[test]
+    fn debug_output_is_truncated() {
+        let map = Map::new();
+        let guard = map.guard();
+        assert_eq!(format!("{:?}", map), "{}");
+        map.insert(1, "a", &guard);
+        assert_eq!(format!("{:?}", map), r#"{1: "a"}"#);
+
+        for i in 2..=DEBUG_ENTRY_LIMIT + 10 {
+            map.insert(i, "a", &guard);
+        }
+        let printed = format!("{:?}", map);
+        assert!(printed.ends_with(", ... (10 more)}"), "{}", printed);
+        assert_eq!(printed.matches(": ").count(), DEBUG_ENTRY_LIMIT);
+
+        // the lock is held, so keys that are only in the dirty map can't be printed
+        map.iter(&guard).count();
+        map.insert(0, "b", &guard);
+        let lock = map.lock();
+        assert!(format!("{:?}", map).ends_with(", ... (11 more)}"));
+        drop(lock);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn concurrent_insert() {
+        let map = Arc::new(Map::<usize, usize>::new());
+
+        let map1 = map.clone();
+        let t1 = std::thread::spawn(move || {
+            for i in 0..5000 {
+                map1.insert(i, 0, &map1.guard());
+            }
+        });
+        let map2 = map.clone();
+        let t2 = std::thread::spawn(move || {
+            for i in 0..5000 {
+                map2.insert(i, 1, &map2.guard());
+            }
+        });
+
+        t1.join().unwrap();
+        t2.join().unwrap();
+
+        thread::sleep(Duration::from_micros(1000));
+        let mut missed = 0;
+        let guard = map.guard();
+        for i in 0..5000 {
+            let v = map.get(&i, &guard);
+            if v.is_some() {
+                assert!(v == Some(&0) || v == Some(&1));
+            } else {
+                missed += 1;
+            }
+
+            // let kv = map.get_key_value(&i, &guard).unwrap();
+            // assert!(kv == (&i, &0) || kv == (&i, &1));
+        }
+
+        println!("missed {}", missed)
+    }
+
+    #[test]
+    fn len_matches_an_exact_walk() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let map = Map::<u8, u32>::new();
+        let guard = map.guard();
+        for step in 0..20_000 {
+            let key = rng.gen_range(0..64);
+            match rng.gen_range(0..100) {
+                0..=29 => {
+                    map.insert(key, step, &guard);
+                }
+                30..=54 => {
+                    map.remove(&key, &guard);
+                }
+                55..=64 => {
+                    map.insert_if_absent(key, step, &guard);
+                }
+                65..=69 => {
+                    map.get_or_insert(key, step, &guard);
+                }
+                70..=79 => {
+                    map.update(key, |v| match v {
+                        Some(_) if step % 2 == 0 => None,
+                        _ => Some(step),
+                    }, &guard);
+                }
+                80..=89 => {
+                    // misses eventually promote the dirty map
+                    map.get(&key, &guard);
+                }
+                90..=94 => {
+                    map.iter(&guard).count();
+                }
+                95..=97 => {
+                    map.maybe_compact(&guard);
+                }
+                98 => {
+                    map.retain(|k, _| k % 3 != 0, &guard);
+                }
+                _ => map.clear(),
+            }
+            let inspection = map.inspect(&guard);
+            assert_eq!(map.len(), inspection.read + inspection.dirty_only, "step {}", step);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn len_matches_an_exact_walk_after_concurrent_updates() {
+        let map = Arc::new(Map::<usize, usize>::new());
+        let handles: Vec<_> = (0..4).map(|t| {
+            let map = map.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                for i in 0..ITER as usize {
+                    let key = (i * 7 + t) % 512;
+                    if i % 3 == 0 {
+                        map.remove(&key, &guard);
+                    } else {
+                        map.insert(key, i, &guard);
+                    }
+                }
+            })
+        }).collect();
+        // clearing and replacing the contents retires the tables the writers may still be using
+        for round in 0..300 {
+            let guard = map.guard();
+            match round % 3 {
+                0 => map.clear(),
+                1 => map.clear_with(&guard),
+                _ => map.replace_all((0..64).map(|k| (k, round)), &guard),
+            }
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let guard = map.guard();
+        let inspection = map.inspect(&guard);
+        assert_eq!(map.len(), inspection.read + inspection.dirty_only);
+        assert_eq!(map.len(), map.iter(&guard).count());
+    }
+
+    #[test]
+    fn iter_read_only_reloads_entries_at_yield_time() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+
+        let mut yielded = 0;
+        for (i, (&k, &v)) in map.iter_read_only(&guard).enumerate() {
+            if i == 0 {
+                // clobber every key: odd ones are removed, even ones replaced
+                for j in 0..100 {
+                    if j == k {
+                        continue;
+                    }
+                    if j % 2 == 1 {
+                        map.remove(&j, &guard);
+                    } else {
+                        map.insert(j, j + 1000, &guard);
+                    }
+                }
+                assert_eq!(v, k);
+            } else {
+                assert_eq!(k % 2, 0);
+                assert_eq!(v, k + 1000);
+            }
+            // keys inserted from here on only reach the dirty map
+            map.insert(1000 + i, 0, &guard);
+            yielded += 1;
+        }
+        assert!((50..=51).contains(&yielded));
+    }
+
+    #[test]
+    fn remove_range_covers_read_and_dirty_keys() {
+        use std::ops::Bound;
+
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        for i in 0..50 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        // these only reach the dirty map
+        for i in 50..100 {
+            map.insert(i, i, &guard);
+        }
+        map.remove(&10, &guard);
+
+        assert_eq!(map.remove_range(0..20, &guard), 19);
+        assert_eq!(map.remove_range(40..=60, &guard), 21);
+        assert_eq!(map.remove_range((Bound::Excluded(98), Bound::Unbounded), &guard), 1);
+        assert_eq!(map.remove_range(0..20, &guard), 0);
+        assert_eq!(map.len(), 100 - 1 - 19 - 21 - 1);
+        for i in 0..100 {
+            let kept = (20..40).contains(&i) || (61..=98).contains(&i);
+            assert_eq!(map.contains_key(&i, &guard), kept, "key {}", i);
+        }
     }
-}
 
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn entry_accumulates_without_losing_updates() {
+        let map = Arc::new(Map::<usize, Vec<usize>>::new());
+        let handles: Vec<_> = (0..4).map(|t| {
+            let map = map.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                for i in 0..1000 {
+                    map.entry(i % 8, &guard)
+                        .and_modify(|v| {
+                            let mut v = v.clone();
+                            v.push(t);
+                            v
+                        })
+                        .or_insert_with(|| vec![t]);
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
 
-struct ReadOnly<K, V> {
-    m: HashMap<K, *mut Entry<V>>,
-    amended: bool,
-}
+        let guard = map.guard();
+        assert_eq!(map.len(), 8);
+        for k in 0..8 {
+            assert_eq!(map.get(&k, &guard).unwrap().len(), 500);
+        }
+        assert!(map.entry(8, &guard).or_default().is_empty());
+        assert!(matches!(map.entry(8, &guard), crate::MapEntry::Occupied(_)));
+        assert!(matches!(map.entry(9, &guard), crate::MapEntry::Vacant(_)));
+        assert_eq!(map.len(), 9);
+    }
 
-impl<K, V> ReadOnly<K, V> {
-    fn new() -> Self <> {
-        Self {
-            m: HashMap::new(),
-            amended: false,
+    #[test]
+    fn promotion_backs_off_until_the_dirty_map_grows() {
+        let map = Map::<usize, usize>::new();
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        map.insert(100, 100, &guard);
+        let amended = || unsafe { map.read.load(Ordering::SeqCst, &guard).deref() }.amended;
+
+        // the dirty map holds 101 keys, barely more than were promoted last time, so the 102nd
+        // miss doesn't promote it yet
+        for _ in 0..=101 {
+            map.get(&100, &guard);
+        }
+        assert!(amended());
+        for _ in 102..=4 * 101 {
+            map.get(&100, &guard);
         }
+        assert!(!amended());
+
+        // once it has doubled, the usual number of misses is enough again
+        for i in 101..202 {
+            map.insert(i, i, &guard);
+        }
+        for _ in 0..=202 {
+            map.get(&201, &guard);
+        }
+        assert!(!amended());
     }
-}
 
+    #[test]
+    fn refs_outlive_the_removal_of_their_value() {
+        let map = Map::<u32, String>::new();
+        let value = {
+            let pinned = map.pin();
+            pinned.insert(1, String::from("a"));
+            pinned.get_ref(&1).unwrap()
+        };
+        assert_eq!(map.pin().remove(&1).map(String::as_str), Some("a"));
+        map.pin().insert(1, String::from("b"));
+        assert_eq!(*value, "a");
+        assert_eq!(format!("{:?}", value), "\"a\"");
+        assert!(map.pin().get_ref(&2).is_none());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Arc;
-    use std::thread;
-    use std::time::Duration;
-    use rayon;
-    use rayon::prelude::*;
+    #[test]
+    fn evict_oldest_follows_insertion_order() {
+        let map = Map::<u32, u32>::builder().track_insertion_order(true).build();
+        let guard = map.guard();
+        for i in 0..10 {
+            map.insert(i, i, &guard);
+        }
+        // promote, so that the next keys and refills take the other code paths
+        map.iter(&guard).count();
+        map.remove(&0, &guard);
+        for i in 10..15 {
+            map.insert(i, i, &guard);
+        }
+        // refilled after removal, so it moves to the back; overwrites don't move keys
+        map.insert(0, 100, &guard);
+        map.insert(1, 101, &guard);
 
-    use crate::reclaim::Shared;
-    use super::*;
+        let mut order = Vec::new();
+        while let Some((k, v)) = map.evict_oldest(&guard) {
+            order.push(k);
+            assert_eq!(v, if k < 2 { k + 100 } else { k });
+        }
+        let mut expected: Vec<_> = (1..15).collect();
+        expected.push(0);
+        assert_eq!(order, expected);
+        assert!(map.is_empty());
+    }
 
-    const ITER: u64 = 32 * 1024;
+    #[test]
+    #[should_panic(expected = "track_insertion_order")]
+    fn evict_oldest_requires_tracking() {
+        let map = Map::<u32, u32>::new();
+        map.evict_oldest(&map.guard());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "map.guard()")]
+    fn unprotected_guards_are_rejected() {
+        let map = Map::<u32, u32>::new();
+        // safety: the map panics before the guard is used for anything
+        let guard = unsafe { Guard::unprotected() };
+        map.insert(1, 1, &guard);
+    }
+
+    #[test]
+    fn size_info_capacity_covers_len() {
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        assert_eq!(map.size_info(&guard), (0, 0));
+        for i in 0..1000 {
+            map.insert(i, i, &guard);
+            if i % 97 == 0 {
+                map.iter(&guard).count();
+            }
+            let (len, capacity) = map.size_info(&guard);
+            assert_eq!(len, i as usize + 1);
+            assert!(capacity >= len, "{} < {}", capacity, len);
+        }
+    }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn remove_and_insert() {
-        let map = Arc::new(Map::<usize, usize>::new());
+    fn on_miss_fires_once_per_fill() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Barrier;
+
+        let fills = Arc::new(AtomicUsize::new(0));
+        let counter = fills.clone();
+        let map = Arc::new(Map::<u64, u64>::builder()
+            .on_miss(move |&key| {
+                assert!(key < 4);
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .build());
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8).map(|_| {
+            let map = map.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                barrier.wait();
+                for key in 0..4 {
+                    map.get_or_insert_with(key, || key * 10, &guard);
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(fills.load(Ordering::SeqCst), 4);
+        // plain gets never count, hits or misses
+        let guard = map.guard();
+        map.get(&0, &guard);
+        map.get(&100, &guard);
+        assert_eq!(fills.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn concurrent_get_and_clear_do_not_panic() {
+        let map = Arc::new(Map::<u64, u64>::new());
+        let readers: Vec<_> = (0..4).map(|_| {
+            let map = map.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                for i in 0..ITER {
+                    // keys that are only in the dirty map make `get` count misses
+                    map.get(&(i % 64), &guard);
+                }
+            })
+        }).collect();
+        let clearer = {
+            let map = map.clone();
+            thread::spawn(move || {
+                for round in 0..200 {
+                    let guard = map.guard();
+                    for i in 0..64 {
+                        map.insert(i, round, &guard);
+                    }
+                    drop(guard);
+                    map.clear();
+                }
+            })
+        };
+        for h in readers {
+            h.join().unwrap();
+        }
+        clearer.join().unwrap();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn get_or_insert_arc_shares_one_value() {
+        use std::sync::atomic::AtomicUsize;
+
+        let map = Arc::new(Map::<u32, Arc<Vec<u8>>>::new());
+        let created = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..8).map(|_| {
+            let map = map.clone();
+            let created = created.clone();
+            thread::spawn(move || {
+                map.get_or_insert_arc(7, || {
+                    created.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    Arc::new(vec![7; 1024])
+                })
+            })
+        }).collect();
+        let arcs: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+        assert!(arcs.iter().all(|a| Arc::ptr_eq(a, &arcs[0])));
+        // one reference per caller plus the map's own
+        assert_eq!(Arc::strong_count(&arcs[0]), 9);
+    }
+
+    #[test]
+    fn compact_now_reclaims_removed_and_expunged_entries() {
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        for i in 0..50 {
+            map.remove(&i, &guard);
+        }
+        // a new key builds the dirty map, which expunges the removed entries
+        map.insert(100, 100, &guard);
+        assert_eq!(map.inspect(&guard).expunged, 50);
+        assert_eq!(map.compact_now(&guard), 50);
+        let read = unsafe { map.read.load(Ordering::SeqCst, &guard).deref() };
+        assert!(!read.amended);
+        assert_eq!(read.m.len(), 51);
+        assert_eq!(map.len(), 51);
+        assert_eq!(map.compact_now(&guard), 0);
+        for i in 50..=100 {
+            assert_eq!(map.get(&i, &guard), Some(&i));
+        }
+    }
+
+    #[test]
+    fn hash_distribution_exposes_a_degenerate_hasher() {
+        use std::hash::{BuildHasherDefault, Hasher};
+
+        #[derive(Default)]
+        struct ConstantHasher;
+
+        impl Hasher for ConstantHasher {
+            fn finish(&self) -> u64 {
+                42
+            }
+
+            fn write(&mut self, _: &[u8]) {}
+        }
+
+        let good = Map::<u32, u32>::new();
+        let bad = Map::<u32, u32, _>::with_hasher(BuildHasherDefault::<ConstantHasher>::default());
+        let guard = good.guard();
+        let bad_guard = bad.guard();
+        for i in 0..4096 {
+            good.insert(i, i, &guard);
+            bad.insert(i, i, &bad_guard);
+        }
+
+        let histogram = good.hash_distribution(&guard, 16);
+        assert_eq!(histogram.iter().sum::<usize>(), 4096);
+        assert!(histogram.iter().all(|&n| n > 128 && n < 384));
+
+        let histogram = bad.hash_distribution(&bad_guard, 16);
+        assert_eq!(histogram[42 % 16], 4096);
+        assert_eq!(histogram.iter().filter(|&&n| n > 0).count(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn replace_all_is_never_observed_half_done() {
+        use std::sync::atomic::AtomicBool;
+
+        let map = Arc::new(Map::<u32, u32>::new());
+        map.replace_all((0..64).map(|k| (k, 0)), &map.guard());
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let map = map.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let guard = map.guard();
+                    // an iterator walks a single read-only map, so it sees exactly one generation
+                    let values: Vec<_> = map.iter(&guard).map(|(_, v)| *v).collect();
+                    assert_eq!(values.len(), 64);
+                    assert!(values.iter().all(|v| *v == values[0]));
+                }
+            })
+        }).collect();
+        for generation in 1..200 {
+            map.replace_all((0..64).map(|k| (k, generation)), &map.guard());
+        }
+        done.store(true, Ordering::SeqCst);
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(map.len(), 64);
+    }
+
+    #[test]
+    fn stats_split_gets_by_where_they_were_served() {
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        assert_eq!(map.stats(), MapStats::default());
+        for i in 0..10 {
+            map.insert(i, i, &guard);
+        }
+        for i in 0..10 {
+            assert_eq!(map.get(&i, &guard), Some(&i));
+        }
+        // the keys are only in the dirty map until it is promoted
+        assert_eq!(map.stats(), MapStats { read_hits: 0, dirty_hits: 10, misses: 0 });
+
+        map.iter(&guard).count();
+        for i in 0..10 {
+            map.get(&i, &guard);
+        }
+        map.remove(&0, &guard);
+        map.get(&0, &guard);
+        map.get(&100, &guard);
+        assert_eq!(map.stats(), MapStats { read_hits: 10, dirty_hits: 10, misses: 2 });
+    }
+
+    #[test]
+    fn batch_views_are_fixed_at_insertion() {
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        map.insert(0, 0, &guard);
+        let batch = map.insert_batch_view((0..100).map(|i| (i, i + 1)), &guard);
+        assert_eq!(map.inspect(&guard).dirty_only, 0);
+        for i in 0..50 {
+            map.insert(i, 0, &guard);
+            map.remove(&(i + 50), &guard);
+        }
+        assert_eq!(batch.len(), 100);
+        for i in 0..100 {
+            assert_eq!(batch.get(&i), Some(&(i + 1)));
+        }
+        assert!(!batch.contains_key(&100));
+        assert_eq!(batch.iter().map(|(_, v)| *v).sum::<u32>(), (1..=100).sum::<u32>());
+        assert!(map.insert_batch_view(Vec::new(), &guard).is_empty());
+    }
+
+    #[test]
+    fn dirty_allocation_is_kept_across_promotions() {
+        let map: Map<u32, u32> = Map::builder().keep_dirty_allocation(true).build();
+        let guard = map.guard();
+        map.insert(0, 0, &guard);
+        let dirty: *const _ = unsafe { map.dirty.load(Ordering::SeqCst, &guard).deref() };
+        for cycle in 1..10 {
+            for i in 0..100 {
+                map.insert(cycle * 100 + i, i, &guard);
+            }
+            map.remove(&((cycle - 1) * 100), &guard);
+            assert_eq!(map.iter(&guard).count(), (1 + cycle * 99) as usize);
+            // the promoted dirty map stays behind, emptied but still allocated
+            let d = unsafe { map.dirty.load(Ordering::SeqCst, &guard).deref() };
+            assert!(std::ptr::eq(d, dirty));
+            assert!(d.is_empty());
+            assert!(d.capacity() >= (1 + cycle * 99) as usize);
+        }
+        assert_eq!(map.len(), 892);
+        assert_eq!(map.get(&950, &guard), Some(&50));
+        assert_eq!(map.get(&800, &guard), None);
+    }
+
+    #[test]
+    fn poll_get_never_waits_for_the_lock() {
+        let map = Map::<u32, u32>::new();
         let guard = map.guard();
         map.insert(1, 1, &guard);
-        assert_eq!(map.remove(&1, &guard), Some(&1));
-        assert_eq!(map.remove(&1, &guard), None)
+        map.iter(&guard).count();
+        map.insert(2, 2, &guard);
+
+        let lock = map.lock();
+        assert_eq!(map.poll_get(&1, &guard), Poll3::Found(&1));
+        assert_eq!(map.poll_get(&2, &guard), Poll3::WouldBlock);
+        assert_eq!(map.poll_get(&3, &guard), Poll3::WouldBlock);
+        drop(lock);
+
+        assert_eq!(map.poll_get(&2, &guard), Poll3::Found(&2));
+        assert_eq!(map.poll_get(&3, &guard), Poll3::NotFound);
+        map.remove(&1, &guard);
+        assert_eq!(map.poll_get(&1, &guard), Poll3::NotFound);
+    }
+
+    #[test]
+    fn clone_from_reuses_the_read_table() {
+        let primary = Map::<u32, String>::new();
+        let guard = primary.guard();
+        for i in 0..100 {
+            primary.insert(i, i.to_string(), &guard);
+        }
+        primary.remove(&0, &guard);
+
+        let mut standby = Map::<u32, String>::new();
+        let standby_guard = standby.guard();
+        for i in 1000..1200 {
+            standby.insert(i, i.to_string(), &standby_guard);
+        }
+        standby.iter(&standby_guard).count();
+        drop(standby_guard);
+        let table: *const _ = unsafe { standby.read.load(Ordering::SeqCst, &standby.guard()).deref() };
+
+        standby.clone_from(&primary);
+        let standby_guard = standby.guard();
+        let read = unsafe { standby.read.load(Ordering::SeqCst, &standby_guard).deref() };
+        assert!(std::ptr::eq(read, table));
+        assert!(read.m.capacity() >= 200);
+        assert_eq!(standby.len(), 99);
+        assert_eq!(standby.len_read(&standby_guard), 99);
+        assert_eq!(standby.get(&1000, &standby_guard), None);
+        assert_eq!(standby.get(&1, &standby_guard), Some(&"1".to_string()));
+        assert_eq!(standby.checkpoint(&standby_guard), primary.checkpoint(&guard));
+
+        // the copy is independent of the source
+        standby.insert(1000, "x".to_string(), &standby_guard);
+        primary.insert(2, "y".to_string(), &guard);
+        assert_eq!(standby.get(&2, &standby_guard), Some(&"2".to_string()));
+        assert_eq!(primary.get(&1000, &guard), None);
     }
 
     #[test]
     #[cfg_attr(miri, ignore)]
-    fn concurrent_insert() {
-        let map = Arc::new(Map::<usize, usize>::new());
+    fn failures_are_cached_until_their_ttl_passes() {
+        use std::sync::atomic::AtomicUsize;
 
-        let map1 = map.clone();
-        let t1 = std::thread::spawn(move || {
-            for i in 0..5000 {
-                map1.insert(i, 0, &map1.guard());
+        let map = Arc::new(Map::<u32, u32>::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let ttl = Duration::from_millis(200);
+        let handles: Vec<_> = (0..8).map(|_| {
+            let map = map.clone();
+            let calls = calls.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                map.get_or_try_insert_with_negative_cache(1, ttl, || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    Err(String::from("upstream down"))
+                }, &guard).copied()
+            })
+        }).collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), Err(String::from("upstream down")));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(map.is_empty());
+
+        let guard = map.guard();
+        thread::sleep(ttl);
+        let value = map.get_or_try_insert_with_negative_cache(1, ttl, || Ok::<_, String>(7), &guard);
+        assert_eq!(value, Ok(&7));
+        let value = map.get_or_try_insert_with_negative_cache(1, ttl, || Err(String::new()), &guard);
+        assert_eq!(value, Ok(&7));
+    }
+
+    #[test]
+    fn retain_and_compact_leaves_no_tombstones() {
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        // some of the keys are only in the dirty map
+        for i in 100..150 {
+            map.insert(i, i, &guard);
+        }
+        map.remove(&0, &guard);
+
+        assert_eq!(map.retain_and_compact(|_, &v| v % 3 == 0, &guard), 100);
+        assert_eq!(map.inspect(&guard), MapInspection { read: 49, dirty_only: 0, removed: 0, expunged: 0 });
+        assert_eq!(map.len(), 49);
+        assert!(map.iter(&guard).all(|(_, v)| v % 3 == 0));
+        assert_eq!(map.retain_and_compact(|_, _| true, &guard), 0);
+    }
+
+    #[test]
+    fn try_from_rejects_repeated_keys() {
+        let pairs: Vec<_> = (0..100).map(|i| (i, i * 2)).collect();
+        let map = Map::<u32, u32>::try_from(pairs.clone()).unwrap();
+        let guard = map.guard();
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.len_read(&guard), 100);
+        assert_eq!(map.get(&99, &guard), Some(&198));
+
+        let mut repeated = pairs;
+        repeated.push((7, 0));
+        repeated.push((3, 0));
+        let err = Map::<u32, u32>::try_from(repeated).unwrap_err();
+        assert_eq!(err, DuplicateKey(7));
+        assert_eq!(err.to_string(), "duplicate key 7");
+    }
+
+    #[test]
+    fn ref_mut_commits_only_on_a_clean_drop() {
+        let map = Map::<&str, Vec<u32>>::new();
+        let pinned = map.pin();
+        pinned.insert("a", vec![1]);
+
+        pinned.get_mut_scoped("a").unwrap().push(2);
+        assert_eq!(pinned.get(&"a"), Some(&vec![1, 2]));
+
+        let mut copy = pinned.get_mut_scoped("a").unwrap();
+        copy.push(3);
+        copy.discard();
+        assert_eq!(pinned.get(&"a"), Some(&vec![1, 2]));
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut copy = pinned.get_mut_scoped("a").unwrap();
+            copy.push(4);
+            panic!("mutation failed halfway");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(pinned.get(&"a"), Some(&vec![1, 2]));
+    }
+
+    #[test]
+    fn sum_and_fold_skip_removed_values() {
+        let map = Map::<u32, f64>::new();
+        let guard = map.guard();
+        for i in 0..10 {
+            map.insert(i, i as f64 / 2.0, &guard);
+        }
+        map.iter(&guard).count();
+        map.insert(10, 5.0, &guard);
+        map.remove(&9, &guard);
+        assert_eq!(map.sum(&guard), 23.0);
+        let (count, max) = map.fold((0, f64::MIN), |(n, max), _, &v| (n + 1, max.max(v)), &guard);
+        assert_eq!((count, max), (10, 5.0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn gets_of_removed_keys_in_the_read_map_never_lock() {
+        use std::sync::mpsc;
+
+        let map = Arc::new(Map::<u32, u32>::new());
+        let guard = map.guard();
+        for i in 0..4 {
+            map.insert(i, i, &guard);
+        }
+        map.iter(&guard).count();
+        map.remove(&0, &guard);
+        map.remove(&1, &guard);
+        // a new key amends the read-only map, and building the dirty map expunges 0 and 1
+        map.insert(4, 4, &guard);
+        map.remove(&2, &guard);
+        assert_eq!(map.inspect(&guard), MapInspection { read: 1, dirty_only: 1, removed: 1, expunged: 2 });
+        drop(guard);
+
+        let lock = map.lock();
+        let (tx, rx) = mpsc::channel();
+        let reader = {
+            let map = map.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                for key in [0, 1, 2, 3] {
+                    tx.send((key, map.get(&key, &guard).copied())).unwrap();
+                }
+            })
+        };
+        let answers: Vec<_> = (0..4)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).expect("get waited for the lock"))
+            .collect();
+        assert_eq!(answers, vec![(0, None), (1, None), (2, None), (3, Some(3))]);
+        drop(lock);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn insert_profiled_reports_each_branch_of_put() {
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        assert_eq!(map.insert_profiled(0, 0, &guard), InsertPath::NewInReadAmend);
+        assert_eq!(map.insert_profiled(1, 1, &guard), InsertPath::NewInDirty);
+        // keys that are only in the dirty map are stored under the lock
+        assert_eq!(map.insert_profiled(1, 10, &guard), InsertPath::Locked);
+        map.iter(&guard).count();
+        assert_eq!(map.insert_profiled(0, 20, &guard), InsertPath::LockFree);
+        assert_eq!(map.insert_profiled(1, 21, &guard), InsertPath::LockFree);
+
+        // an expunged entry has to be brought back into the dirty map under the lock
+        map.remove(&0, &guard);
+        assert_eq!(map.insert_profiled(2, 2, &guard), InsertPath::NewInReadAmend);
+        assert_eq!(map.insert_profiled(0, 30, &guard), InsertPath::Locked);
+        assert_eq!(map.get(&0, &guard), Some(&30));
+        assert_eq!(map.get(&1, &guard), Some(&21));
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn freeze_into_arc_moves_each_live_value_once() {
+        let map = Map::<u32, Arc<u32>>::new();
+        let values: Vec<_> = (0..6).map(Arc::new).collect();
+        let guard = map.guard();
+        for i in 0..4 {
+            map.insert(i, values[i as usize].clone(), &guard);
+        }
+        map.iter(&guard).count();
+        // 0 is expunged when the dirty map is built, 1 is emptied in both maps, 4 is dirty-only
+        // and 5 is emptied in the dirty map
+        map.remove(&0, &guard);
+        map.insert(4, values[4].clone(), &guard);
+        map.insert(5, values[5].clone(), &guard);
+        map.insert(6, Arc::new(6), &guard);
+        map.remove(&1, &guard);
+        map.remove(&5, &guard);
+        assert_eq!(map.inspect(&guard), MapInspection { read: 2, dirty_only: 2, removed: 1, expunged: 1 });
+        drop(guard);
+
+        let frozen = map.freeze_into_arc();
+        let mut keys: Vec<_> = frozen.keys().copied().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![2, 3, 4, 6]);
+        for (i, v) in values.iter().enumerate() {
+            let live = [2, 3, 4].contains(&i);
+            assert_eq!(Arc::strong_count(v), if live { 2 } else { 1 }, "value {}", i);
+            if live {
+                assert!(Arc::ptr_eq(&frozen[&(i as u32)], v));
             }
-        });
-        let map2 = map.clone();
-        let t2 = std::thread::spawn(move || {
-            for i in 0..5000 {
-                map2.insert(i, 1, &map2.guard());
+        }
+        drop(frozen);
+        assert!(values.iter().all(|v| Arc::strong_count(v) == 1));
+    }
+
+    #[test]
+    fn entries_can_back_out_or_remove_without_a_second_lookup() {
+        use crate::MapEntry;
+
+        let map = Map::<String, u32>::new();
+        let guard = map.guard();
+        map.insert(String::from("read"), 1, &guard);
+        map.iter(&guard).count();
+        map.insert(String::from("dirty"), 2, &guard);
+
+        for (key, value) in [("read", 1), ("dirty", 2)] {
+            match map.entry(key.to_owned(), &guard) {
+                MapEntry::Occupied(e) => {
+                    assert_eq!(e.key(), key);
+                    assert_eq!(e.get(), &value);
+                    assert_eq!(e.remove(), Some(&value));
+                }
+                MapEntry::Vacant(_) => panic!("{} is vacant", key),
             }
+        }
+        assert!(map.is_empty());
+        assert_eq!(map.get("dirty", &guard), None);
+
+        let key = match map.entry(String::from("read"), &guard) {
+            MapEntry::Vacant(e) => {
+                assert_eq!(e.key(), "read");
+                e.into_key()
+            }
+            MapEntry::Occupied(_) => panic!("read is occupied"),
+        };
+        // backing out released the lock
+        map.insert(key, 3, &guard);
+        assert_eq!(map.get("read", &guard), Some(&3));
+    }
+
+    #[test]
+    fn entry_closures_panic_instead_of_deadlocking_on_the_map() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use crate::MapEntry;
+
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        map.insert(1, 1, &guard);
+        map.iter(&guard).count();
+
+        // reads served from the read-only map don't take the lock
+        map.entry(1, &guard).and_modify(|v| v + map.get(&1, &guard).unwrap());
+        assert_eq!(map.get(&1, &guard), Some(&2));
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            map.entry(2, &guard).or_insert_with(|| *map.entry(3, &guard).or_insert(3));
+        }));
+        let message = result.unwrap_err();
+        assert!(message.downcast_ref::<&str>().unwrap().contains("re-entrant"));
+        // the entry let go of the lock while unwinding, and other threads never trip the check
+        let held = map.entry(2, &guard);
+        thread::scope(|s| {
+            s.spawn(|| {
+                map.entry(4, &map.guard()).or_insert(4);
+            });
+            drop(held);
         });
+        assert_eq!(map.get(&4, &guard), Some(&4));
 
-        t1.join().unwrap();
-        t2.join().unwrap();
+        // a value removed without the lock meanwhile (key 4 keeps the read-only map amended, so
+        // the removal doesn't compact under the lock either) is not reported as removed again
+        if let MapEntry::Occupied(e) = map.entry(1, &guard) {
+            assert_eq!(map.remove(&1, &guard), Some(&2));
+            assert_eq!(e.remove(), None);
+        }
+        assert_eq!(map.len(), 1);
+    }
 
-        thread::sleep(Duration::from_micros(1000));
-        let mut missed = 0;
+    #[test]
+    fn prepare_for_reads_serves_every_key_from_the_read_map() {
+        let map = Map::<u32, u32>::new();
         let guard = map.guard();
-        for i in 0..5000 {
-            let v = map.get(&i, &guard);
-            if v.is_some() {
-                assert!(v == Some(&0) || v == Some(&1));
-            } else {
-                missed += 1;
-            }
+        for i in 0..200 {
+            map.insert(i, i, &guard);
+        }
+        // a few misses, not enough to promote on their own
+        for i in 0..3 {
+            map.get(&i, &guard);
+        }
+        map.prepare_for_reads(&guard);
+        assert_eq!(map.misses.load(Ordering::SeqCst), 0);
+        assert_eq!(map.inspect(&guard), MapInspection { read: 200, dirty_only: 0, removed: 0, expunged: 0 });
 
-            // let kv = map.get_key_value(&i, &guard).unwrap();
-            // assert!(kv == (&i, &0) || kv == (&i, &1));
+        let before = map.stats();
+        for i in 0..200 {
+            assert_eq!(map.get(&i, &guard), Some(&i));
         }
+        let after = map.stats();
+        assert_eq!(after.read_hits - before.read_hits, 200);
+        assert_eq!(after.dirty_hits, before.dirty_hits);
 
-        println!("missed {}", missed)
+        // nothing to promote is fine too
+        map.prepare_for_reads(&guard);
+        assert_eq!(map.len_read(&guard), 200);
+    }
+
+    #[test]
+    fn get_or_insert_with_panics_instead_of_waiting_for_itself() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        // other keys can be computed from inside the closure
+        let v = map.get_or_insert_with(1, || map.get_or_insert_with(2, || 20, &guard) + 1, &guard);
+        assert_eq!(*v, 21);
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            map.get_or_insert_with(3, || *map.get_or_insert_with(3, || 30, &guard), &guard);
+        }));
+        let message = result.unwrap_err();
+        assert!(message.downcast_ref::<&str>().unwrap().contains("re-entrant"));
+        assert_eq!(map.get(&3, &guard), None);
+
+        // the key was released while unwinding, so it can still be computed, also on another thread
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(map.get_or_insert_with(3, || 31, &map.guard()), &31));
+        });
+        assert_eq!(map.get(&3, &guard), Some(&31));
+    }
+
+    #[test]
+    fn drain_filter_removes_only_what_it_yields() {
+        let map = Map::<u32, u32>::new();
+        let guard = map.guard();
+        for i in 0..10 {
+            map.insert(i, i, &guard);
+        }
+
+        // a value replaced after the predicate saw it is neither removed nor yielded
+        let drained: Vec<_> = map
+            .drain_filter(|&k, _| {
+                if k == 4 {
+                    map.insert(4, 40, &guard);
+                }
+                k % 2 == 0
+            }, &guard)
+            .map(|(&k, &v)| (k, v))
+            .collect();
+        let mut keys: Vec<_> = drained.iter().map(|&(k, _)| k).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![0, 2, 6, 8]);
+        assert!(drained.iter().all(|&(k, v)| k == v));
+        assert_eq!(map.get(&4, &guard), Some(&40));
+        assert_eq!(map.len(), 6);
+
+        // dropping the iterator early leaves the rest in place
+        let first = {
+            let mut odd = map.drain_filter(|&k, _| k % 2 == 1, &guard);
+            *odd.next().unwrap().0
+        };
+        assert_eq!(map.len(), 5);
+        assert_eq!(map.get(&first, &guard), None);
+        assert_eq!(map.inspect(&guard).removed, 5);
     }
 }
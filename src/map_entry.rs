@@ -0,0 +1,258 @@
+use std::hash::{BuildHasher, Hash};
+use seize::Guard;
+use crate::map::{EntryLock, Map};
+
+/// A view into a single key of a [`Map`], which is either occupied or vacant.
+///
+/// Constructed with [`Map::entry`]. The map's lock is held for as long as the entry is alive, so
+/// whether the key is occupied can't change under the caller before it acts on the entry. The
+/// exceptions are a plain [`insert`](Map::insert) or [`remove`](Map::remove) of a key that is
+/// already present in the read-only map, which don't take the lock.
+///
+/// Because of the lock, using the same map in a way that takes the lock from the thread that
+/// holds an entry panics rather than deadlocking. That includes the closures passed to the
+/// entry's methods, which run under the lock.
+pub enum MapEntry<'g, K, V, S = crate::DefaultHashBuilder> {
+    /// The key has a value.
+    Occupied(OccupiedEntry<'g, K, V, S>),
+    /// The key has no value.
+    Vacant(VacantEntry<'g, K, V, S>),
+}
+
+/// An entry for a key that has a value. See [`MapEntry`].
+pub struct OccupiedEntry<'g, K, V, S = crate::DefaultHashBuilder> {
+    map: &'g Map<K, V, S>,
+    key: K,
+    value: &'g V,
+    guard: &'g Guard<'g>,
+    _lock: EntryLock<'g>,
+}
+
+/// An entry for a key that has no value. See [`MapEntry`].
+pub struct VacantEntry<'g, K, V, S = crate::DefaultHashBuilder> {
+    map: &'g Map<K, V, S>,
+    key: K,
+    guard: &'g Guard<'g>,
+    _lock: EntryLock<'g>,
+}
+
+impl<K, V, S> Map<K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Gets the entry for `key`, for in-place manipulation.
+    ///
+    /// This takes the map's lock and keeps it until the returned entry is dropped. See
+    /// [`MapEntry`] for what that implies.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// for word in ["a", "b", "a"] {
+    ///     map.entry(word, &guard).and_modify(|n| n + 1).or_insert(1);
+    /// }
+    /// assert_eq!(map.get(&"a", &guard), Some(&2));
+    /// assert_eq!(map.get(&"b", &guard), Some(&1));
+    /// ```
+    pub fn entry<'g>(&'g self, key: K, guard: &'g Guard<'_>) -> MapEntry<'g, K, V, S> {
+        self.check_guard(guard);
+        let lock = self.lock_for_entry();
+        match self.load_locked(&key, guard) {
+            Some(value) => MapEntry::Occupied(OccupiedEntry {
+                map: self,
+                key,
+                value,
+                guard,
+                _lock: lock,
+            }),
+            None => MapEntry::Vacant(VacantEntry {
+                map: self,
+                key,
+                guard,
+                _lock: lock,
+            }),
+        }
+    }
+}
+
+impl<'g, K, V, S> MapEntry<'g, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        match self {
+            MapEntry::Occupied(e) => &e.key,
+            MapEntry::Vacant(e) => &e.key,
+        }
+    }
+
+    /// Returns the value of the entry, inserting `value` first if it is vacant.
+    pub fn or_insert(self, value: V) -> &'g V {
+        self.or_insert_with(|| value)
+    }
+
+    /// Returns the value of the entry, inserting the result of `f` first if it is vacant.
+    ///
+    /// `f` runs under the map's lock, see [`MapEntry`].
+    pub fn or_insert_with<F>(self, f: F) -> &'g V
+        where
+            F: FnOnce() -> V,
+    {
+        match self {
+            MapEntry::Occupied(e) => e.value,
+            MapEntry::Vacant(e) => e.insert(f()),
+        }
+    }
+
+    /// Returns the value of the entry, inserting `V::default()` first if it is vacant.
+    ///
+    /// Values can't be mutated in place, so to accumulate into an entry, combine this with
+    /// [`and_modify`](Self::and_modify), which stores a modified copy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map: Map<&str, Vec<u32>> = Map::new();
+    /// let guard = map.guard();
+    /// assert!(map.entry("a", &guard).or_default().is_empty());
+    /// for i in 0..3 {
+    ///     map.entry("a", &guard)
+    ///         .and_modify(|v| {
+    ///             let mut v = v.clone();
+    ///             v.push(i);
+    ///             v
+    ///         })
+    ///         .or_default();
+    /// }
+    /// assert_eq!(map.get(&"a", &guard), Some(&vec![0, 1, 2]));
+    /// ```
+    pub fn or_default(self) -> &'g V
+        where
+            V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// If the entry is occupied, replaces its value with the value `f` computes from it.
+    ///
+    /// The replacement is stored before this returns, so it is what the `or_*` methods return
+    /// when chained. Vacant entries are returned unchanged. `f` runs under the map's lock, see
+    /// [`MapEntry`].
+    pub fn and_modify<F>(self, f: F) -> Self
+        where
+            F: FnOnce(&V) -> V,
+    {
+        match self {
+            MapEntry::Occupied(mut e) => {
+                let value = f(e.value);
+                e.value = e.map.insert_locked(e.key.clone(), value, e.guard);
+                MapEntry::Occupied(e)
+            }
+            vacant => vacant,
+        }
+    }
+}
+
+impl<'g, K, V, S> VacantEntry<'g, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Inserts `value` for the entry's key and returns a reference to it.
+    pub fn insert(self, value: V) -> &'g V {
+        let value = self.map.insert_locked(self.key, value, self.guard);
+        drop(self._lock);
+        #[cfg(feature = "metrics")]
+        self.map.record_len();
+        value
+    }
+}
+
+impl<'g, K, V, S> VacantEntry<'g, K, V, S> {
+    /// Returns the key that would be used when inserting through this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Takes back the key without inserting anything, releasing the map's lock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    /// use syncmap::MapEntry;
+    ///
+    /// let map: Map<String, u32> = Map::new();
+    /// let guard = map.guard();
+    /// let key = match map.entry(String::from("a"), &guard) {
+    ///     MapEntry::Vacant(e) => e.into_key(),
+    ///     MapEntry::Occupied(_) => unreachable!(),
+    /// };
+    /// assert_eq!(key, "a");
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn into_key(self) -> K {
+        self.key
+    }
+}
+
+impl<'g, K, V, S> OccupiedEntry<'g, K, V, S> {
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the value of this entry.
+    pub fn get(&self) -> &V {
+        self.value
+    }
+}
+
+impl<'g, K, V, S> OccupiedEntry<'g, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Removes the entry's key from the map and returns the value it removed.
+    ///
+    /// As with [`Map::remove`], the value is retired rather than moved out, since other threads
+    /// may still be reading it, so what's returned is a reference that lives as long as the
+    /// guard. If the value was replaced without the lock since the entry was created, the
+    /// replacement is what's removed and returned; if it was removed, this returns `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    /// use syncmap::MapEntry;
+    ///
+    /// let map = Map::new();
+    /// let guard = map.guard();
+    /// map.insert("a", 1, &guard);
+    /// if let MapEntry::Occupied(e) = map.entry("a", &guard) {
+    ///     assert_eq!(e.get(), &1);
+    ///     assert_eq!(e.remove(), Some(&1));
+    /// }
+    /// assert_eq!(map.get(&"a", &guard), None);
+    /// ```
+    pub fn remove(self) -> Option<&'g V> {
+        let removed = self.map.remove_locked(&self.key, self.guard);
+        drop(self._lock);
+        #[cfg(feature = "metrics")]
+        self.map.record_len();
+        removed
+    }
+}
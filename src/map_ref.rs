@@ -0,0 +1,394 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::hash::{BuildHasher, Hash};
+use crate::iter::{Iter, Keys, Values};
+use crate::map::Map;
+use crate::reclaim::{Guard, GuardRef};
+
+/// A reference to a [`Map`], constructed with [`Map::pin`] or [`Map::with_guard`].
+///
+/// The current thread will be pinned for the duration of this reference.
+/// Keep in mind that this prevents the collection of garbage generated by the map.
+pub struct HashMapRef<'map, K, V, S = crate::DefaultHashBuilder> {
+    guard: GuardRef<'map>,
+    map: &'map Map<K, V, S>,
+}
+
+/// A value of a [`Map`] together with the guard that keeps it alive, as returned by
+/// [`HashMapRef::get_ref`].
+///
+/// `Ref` dereferences to the value. The value stays valid for as long as the `Ref` is held, even
+/// if it is replaced in or removed from the map in the meantime, in which case the `Ref` keeps
+/// showing the old value. Every `Ref` pins the current thread on its own, and holds back the
+/// collection of the map's garbage until it is dropped, so holding many of them, or holding one
+/// for long, lets garbage pile up.
+pub struct Ref<'map, V> {
+    _guard: Guard<'map>,
+    value: *const V,
+}
+
+impl<V> Deref for Ref<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // safety: the value was loaded under `_guard`, which we hold, so it can't have been freed.
+        unsafe { &*self.value }
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for Ref<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A copy of a value of a [`Map`] that is written back when dropped, as returned by
+/// [`HashMapRef::get_mut_scoped`].
+///
+/// `RefMut` dereferences mutably to its copy, and stores the copy back under its key when it is
+/// dropped. If it is dropped while the thread is panicking, for example because the mutation
+/// itself panicked, the copy is discarded instead, so a half-mutated value never reaches the
+/// map. [`discard`](Self::discard) drops it without writing back.
+///
+/// The write back is a plain [`insert`](Map::insert): writes to the key by other threads while
+/// the `RefMut` is alive are overwritten, and a key removed in the meantime is inserted again.
+/// Use [`Map::update`] when concurrent read-modify-writes of the same key must not be lost.
+pub struct RefMut<'a, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    map: &'a HashMapRef<'a, K, V, S>,
+    key: Option<K>,
+    value: Option<V>,
+}
+
+impl<K, V, S> RefMut<'_, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Drops the copy without writing it back to the map.
+    pub fn discard(mut self) {
+        self.key = None;
+    }
+}
+
+impl<K, V, S> Deref for RefMut<'_, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value.as_ref().expect("the value is only taken on drop")
+    }
+}
+
+impl<K, V, S> DerefMut for RefMut<'_, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        self.value.as_mut().expect("the value is only taken on drop")
+    }
+}
+
+impl<K, V, S> Drop for RefMut<'_, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        if let (Some(key), Some(value)) = (self.key.take(), self.value.take()) {
+            self.map.insert(key, value);
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for RefMut<'_, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<K, V, S> Map<K, V, S> {
+    /// Get a reference to this map with the current thread pinned.
+    ///
+    /// Keep in mind that for as long as you hold onto this, you are preventing the collection of
+    /// garbage generated by the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let pinned = map.pin();
+    /// pinned.insert(1, "a");
+    /// assert_eq!(pinned.get(&1), Some(&"a"));
+    /// ```
+    pub fn pin(&self) -> HashMapRef<'_, K, V, S> {
+        HashMapRef {
+            guard: GuardRef::Owned(self.guard()),
+            map: self,
+        }
+    }
+
+    /// Get a reference to this map with the given guard.
+    pub fn with_guard<'g>(&'g self, guard: &'g Guard<'_>) -> HashMapRef<'g, K, V, S> {
+        HashMapRef {
+            guard: GuardRef::Ref(guard),
+            map: self,
+        }
+    }
+
+    /// Runs `f` with a reference to this map pinned by a single guard, and returns its result.
+    ///
+    /// The guard is dropped as soon as `f` returns, and neither it nor any reference obtained
+    /// through it can escape the closure, so garbage collection can't be held back by accident.
+    /// Use [`pin`](Self::pin) to keep a pinned reference around instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let doubled = map.scope(|m| {
+    ///     m.insert(1, 21);
+    ///     m.get(&1).map(|v| v * 2)
+    /// });
+    /// assert_eq!(doubled, Some(42));
+    /// ```
+    pub fn scope<R, F>(&self, f: F) -> R
+        where
+            F: FnOnce(&HashMapRef<'_, K, V, S>) -> R,
+    {
+        f(&self.pin())
+    }
+}
+
+impl<K, V, S> Clone for HashMapRef<'_, K, V, S> {
+    /// Returns a new reference to the same map, pinned with a fresh guard.
+    ///
+    /// Guards can't be cloned, so every clone pins the current thread on its own and holds back
+    /// the collection of the map's garbage until it, too, is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let pinned = map.pin();
+    /// pinned.insert(1, "a");
+    /// let cloned = pinned.clone();
+    /// drop(pinned);
+    /// assert_eq!(cloned.get(&1), Some(&"a"));
+    /// ```
+    fn clone(&self) -> Self {
+        self.map.pin()
+    }
+}
+
+impl<'map, K, V, S> HashMapRef<'map, K, V, S>
+    where
+        K: Clone + Hash + Ord,
+        S: BuildHasher,
+{
+    /// Returns the number of entries in the map.
+    ///
+    /// See also [`Map::len`].
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the map contains no entries.
+    ///
+    /// See also [`Map::is_empty`].
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// See also [`Map::get`].
+    #[inline]
+    pub fn get<'g, Q>(&'g self, key: &Q) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.map.get(key, &self.guard)
+    }
+
+    /// Returns the value corresponding to the key, bundled with a guard of its own.
+    ///
+    /// Unlike the reference returned by [`get`](Self::get), the returned [`Ref`] doesn't borrow
+    /// this `HashMapRef`: it pins its own guard, so the value can't outlive the guard it depends
+    /// on. See [`Ref`] for the cost of holding onto it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let pinned = map.pin();
+    /// pinned.insert(1, String::from("a"));
+    /// let value = pinned.get_ref(&1).unwrap();
+    /// drop(pinned);
+    /// assert_eq!(value.as_str(), "a");
+    /// ```
+    pub fn get_ref<Q>(&self, key: &Q) -> Option<Ref<'map, V>>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let guard = self.map.guard();
+        let value: *const V = self.map.get(key, &guard)?;
+        Some(Ref { _guard: guard, value })
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    ///
+    /// See also [`Map::contains_key`].
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.map.contains_key(key, &self.guard)
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    ///
+    /// The iterator element type is `(&'g K, &'g V)`.
+    ///
+    /// See also [`Map::iter`].
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.map.iter(&self.guard)
+    }
+
+    /// An iterator visiting all keys in arbitrary order.
+    ///
+    /// The iterator element type is `&'g K`.
+    ///
+    /// See also [`Map::keys`].
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        self.map.keys(&self.guard)
+    }
+
+    /// An iterator visiting all values in arbitrary order.
+    ///
+    /// The iterator element type is `&'g V`.
+    ///
+    /// See also [`Map::values`].
+    pub fn values(&self) -> Values<'_, K, V> {
+        self.map.values(&self.guard)
+    }
+
+    /// Calls `f` on every key-value pair of the map, in arbitrary order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let pinned = map.pin();
+    /// pinned.insert(1, 10);
+    /// pinned.insert(2, 20);
+    /// let mut sum = 0;
+    /// pinned.for_each(|_, v| sum += v);
+    /// assert_eq!(sum, 30);
+    /// ```
+    pub fn for_each<F>(&self, mut f: F)
+        where
+            F: FnMut(&K, &V),
+    {
+        self.iter().for_each(|(k, v)| f(k, v))
+    }
+}
+
+impl<K, V, S> HashMapRef<'_, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Inserts a key-value pair into the map.
+    ///
+    /// See also [`Map::insert`].
+    pub fn insert(&self, key: K, value: V) {
+        self.map.insert(key, value, &self.guard)
+    }
+
+    /// Removes a key-value pair from the map, and returns the removed value (if any).
+    ///
+    /// See also [`Map::remove`].
+    pub fn remove<'g, Q>(&'g self, key: &Q) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.map.remove(key, &self.guard)
+    }
+
+    /// Retains only the elements specified by the predicate, and returns how many entries were
+    /// removed.
+    ///
+    /// See also [`Map::retain`].
+    pub fn retain<F>(&self, f: F) -> usize
+        where
+            F: FnMut(&K, &V) -> bool,
+    {
+        self.map.retain(f, &self.guard)
+    }
+
+    /// Returns a copy of the value for `key` that is stored back into the map when dropped.
+    ///
+    /// This gives single-key read-modify-write the ergonomics of a mutable reference. See
+    /// [`RefMut`] for when the copy is written back, and what that means for concurrent writes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::map::Map;
+    ///
+    /// let map = Map::new();
+    /// let pinned = map.pin();
+    /// pinned.insert("hits", 1);
+    /// *pinned.get_mut_scoped("hits").unwrap() += 1;
+    /// assert_eq!(pinned.get(&"hits"), Some(&2));
+    /// assert!(pinned.get_mut_scoped("misses").is_none());
+    /// ```
+    pub fn get_mut_scoped(&self, key: K) -> Option<RefMut<'_, K, V, S>>
+        where
+            V: Clone,
+    {
+        let value = self.get(&key)?.clone();
+        Some(RefMut {
+            map: self,
+            key: Some(key),
+            value: Some(value),
+        })
+    }
+}
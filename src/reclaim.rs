@@ -1,4 +1,43 @@
-pub(crate) use seize::{Collector, Guard, Linked};
+//! Atomic pointers with memory reclamation, as used by the map itself.
+//!
+//! [`Atomic`] and [`Shared`] are thin wrappers over [`seize`] that tie every pointer loaded from
+//! shared memory to the [`Guard`] it was loaded under. They are exposed so that other lock-free
+//! structures can use the same reclamation scheme, and share a [`Collector`] with a map.
+//!
+//! # Safety contract
+//!
+//! * Values are allocated through a collector, with [`Shared::boxed`] or
+//!   [`Atomic::new`], and must only be protected by guards of that same collector.
+//! * A [`Shared`] loaded under a guard stays valid for as long as that guard is held, even if
+//!   another thread unlinks it in the meantime. That is what the `'g` lifetime tracks.
+//! * A pointer may only be retired (see [`RetireShared`]) once it has been unlinked, so that no
+//!   new references to it can be created. It is freed once every guard that was active at that
+//!   point has been dropped.
+//! * A pointer that was never published (stored somewhere other threads can load it from) can
+//!   be taken back with [`Shared::into_box`] instead.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::atomic::Ordering;
+//! use syncmap::reclaim::{Atomic, Collector, RetireShared, Shared};
+//!
+//! let collector = Collector::new();
+//! let slot = Atomic::new(1, &collector);
+//!
+//! let guard = collector.enter();
+//! let old = slot.swap(Shared::boxed(2, &collector), Ordering::SeqCst, &guard);
+//! assert_eq!(unsafe { old.as_ref() }.map(|v| **v), Some(1));
+//! // safety: `old` was unlinked by the swap above
+//! unsafe { guard.retire_shared(old) };
+//!
+//! let current = slot.load(Ordering::SeqCst, &guard);
+//! assert_eq!(**unsafe { current.deref() }, 2);
+//! # drop(guard);
+//! # unsafe { drop(slot.into_box()) };
+//! ```
+
+pub use seize::{Collector, Guard, Linked};
 
 use std::marker::PhantomData;
 use std::ops::Deref;
@@ -6,26 +45,46 @@ use std::sync::atomic::Ordering;
 use std::{fmt, ptr};
 
 
-pub(crate) struct Atomic<T>(seize::AtomicPtr<T>);
+/// An atomic pointer to a value allocated through a [`Collector`].
+///
+/// Dropping an `Atomic` does not drop the value it points to.
+pub struct Atomic<T>(seize::AtomicPtr<T>);
 
 impl<T> Atomic<T> {
-    pub(crate) fn null() -> Self {
+    /// Allocates `value` through `collector` and returns an `Atomic` pointing to it.
+    pub fn new(value: T, collector: &Collector) -> Self {
+        Self::from(Shared::boxed(value, collector))
+    }
+
+    /// Returns a null `Atomic`.
+    pub fn null() -> Self {
         Self(seize::AtomicPtr::default())
     }
 
-    pub(crate) fn load<'g>(&self, ordering: Ordering, guard: &'g Guard<'_>) -> Shared<'g, T> {
+    /// Loads the pointer, protecting the value from reclamation for as long as `guard` is held.
+    pub fn load<'g>(&self, ordering: Ordering, guard: &'g Guard<'_>) -> Shared<'g, T> {
         guard.protect(&self.0, ordering).into()
     }
 
-    pub(crate) fn store(&self, new: Shared<'_, T>, ordering: Ordering) {
+    /// Stores `new`. The previous pointer is overwritten without being retired.
+    pub fn store(&self, new: Shared<'_, T>, ordering: Ordering) {
         self.0.store(new.ptr, ordering);
     }
 
-    pub(crate) unsafe fn into_box(self) -> Box<Linked<T>> {
+    /// Takes ownership of the pointed-to value.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must not be null, and no other thread may still be able to access the value.
+    pub unsafe fn into_box(self) -> Box<Linked<T>> {
         Box::from_raw(self.0.into_inner())
     }
 
-    pub(crate) fn swap<'g>(
+    /// Stores `new` and returns the previous pointer.
+    ///
+    /// The returned pointer is still valid for as long as `guard` is held. It has been unlinked
+    /// by this call, so it may be retired through the guard.
+    pub fn swap<'g>(
         &self,
         new: Shared<'_, T>,
         ord: Ordering,
@@ -34,7 +93,11 @@ impl<T> Atomic<T> {
         self.0.swap(new.ptr, ord).into()
     }
 
-    pub(crate) fn compare_exchange<'g>(
+    /// Stores `new` if the current pointer is `current`.
+    ///
+    /// On success, returns the previous pointer, which may then be retired. On failure, returns
+    /// the pointer that was found instead, along with `new`, which was not published.
+    pub fn compare_exchange<'g>(
         &self,
         current: Shared<'_, T>,
         new: Shared<'g, T>,
@@ -79,42 +142,73 @@ impl<T> fmt::Debug for Atomic<T> {
     }
 }
 
-pub(crate) struct CompareExchangeError<'g, T> {
-    pub(crate) current: Shared<'g, T>,
-    pub(crate) new: Shared<'g, T>,
+/// The error returned by a failed [`Atomic::compare_exchange`].
+pub struct CompareExchangeError<'g, T> {
+    /// The pointer that was found instead of the expected one.
+    pub current: Shared<'g, T>,
+    /// The pointer that was to be stored. It was not published.
+    pub new: Shared<'g, T>,
 }
 
-pub(crate) struct Shared<'g, T> {
+/// A pointer to a value allocated through a [`Collector`], valid for as long as the guard `'g`
+/// it was loaded under.
+pub struct Shared<'g, T> {
     ptr: *mut Linked<T>,
     _g: PhantomData<&'g ()>,
 }
 
 impl<'g, T> Shared<'g, T> {
-    pub(crate) fn null() -> Self {
+    /// Returns a null pointer.
+    pub fn null() -> Self {
         Shared::from(ptr::null_mut())
     }
 
-    pub(crate) fn boxed(value: T, collector: &Collector) -> Self {
+    /// Allocates `value` through `collector`. The allocation is not published anywhere yet.
+    pub fn boxed(value: T, collector: &Collector) -> Self {
         Shared::from(collector.link_boxed(value))
     }
 
-    pub(crate) unsafe fn into_box(self) -> Box<Linked<T>> {
+    /// Takes ownership of the pointed-to value.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must not be null, and must either never have been published, or have been
+    /// unlinked with no other thread still able to access it.
+    pub unsafe fn into_box(self) -> Box<Linked<T>> {
         Box::from_raw(self.ptr)
     }
 
-    pub(crate) unsafe fn as_ptr(&self) -> *mut Linked<T> {
+    /// Returns the raw pointer.
+    ///
+    /// # Safety
+    ///
+    /// The raw pointer is not tied to the guard. Dereferencing it after the guard has been
+    /// dropped may access freed memory.
+    pub unsafe fn as_ptr(&self) -> *mut Linked<T> {
         self.ptr
     }
 
-    pub(crate) unsafe fn as_ref(&self) -> Option<&'g Linked<T>> {
+    /// Returns a reference to the value, or `None` if the pointer is null.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must have been loaded under a guard of the collector that allocated the
+    /// value, or not have been published yet.
+    pub unsafe fn as_ref(&self) -> Option<&'g Linked<T>> {
         self.ptr.as_ref()
     }
 
-    pub(crate) unsafe fn deref(&self) -> &'g Linked<T> {
+    /// Returns a reference to the value.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`as_ref`](Self::as_ref), and the pointer must not be null.
+    pub unsafe fn deref(&self) -> &'g Linked<T> {
         &*self.ptr
     }
 
-    pub(crate) fn is_null(&self) -> bool {
+    /// Returns `true` if the pointer is null.
+    pub fn is_null(&self) -> bool {
         self.ptr.is_null()
     }
 }
@@ -129,7 +223,7 @@ impl<T> Eq for Shared<'_, T> {}
 
 impl<T> Clone for Shared<'_, T> {
     fn clone(&self) -> Self {
-        Shared::from(self.ptr)
+        *self
     }
 }
 
@@ -144,12 +238,23 @@ impl<T> From<*mut Linked<T>> for Shared<'_, T> {
     }
 }
 
-pub(crate) trait RetireShared {
+/// Retiring [`Shared`] pointers through a [`Guard`].
+pub trait RetireShared {
+    /// Retires `shared`, so that it is freed once no guard can be accessing it anymore. Null
+    /// pointers are ignored.
+    ///
+    /// # Safety
+    ///
+    /// `shared` must have been allocated through this guard's collector, and must have been
+    /// unlinked so that no new references to it can be created. It must not be retired twice.
     unsafe fn retire_shared<T>(&self, shared: Shared<'_, T>);
 }
 
 impl RetireShared for Guard<'_> {
     unsafe fn retire_shared<T>(&self, shared: Shared<'_, T>) {
+        if shared.is_null() {
+            return;
+        }
         self.retire(shared.ptr, seize::reclaim::boxed::<T>);
     }
 }
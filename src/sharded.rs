@@ -0,0 +1,333 @@
+//! A map split into independently locked shards.
+//!
+//! Every write to a [`Map`] that misses the read-only map, and every lookup that has to fall back
+//! to the dirty map, goes through the map's single lock. [`ShardedMap`] spreads keys over several
+//! inner maps by hash, so that operations on keys in different shards never contend.
+
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use seize::Guard;
+use crate::map::Map;
+
+/// The number of shards used by [`ShardedMap::new`] and [`ShardedMap::with_hasher`]: four per
+/// CPU, rounded up to a power of two.
+pub fn default_shards() -> usize {
+    (4 * num_cpus::get()).next_power_of_two()
+}
+
+/// A concurrent map made of several [`Map`]s, each guarding its own share of the keys.
+///
+/// Each key is routed to one shard by its hash, and operations on it only touch that shard.
+/// Writes and dirty-map lookups for keys in different shards therefore take different locks.
+/// The price is that whole-map operations, [`len`](Self::len) and [`iter`](Self::iter), have to
+/// visit every shard.
+///
+/// Every shard reclaims memory through its own collector, so a [`ShardedGuard`] holds one guard
+/// per shard. Pinning one is proportionally more expensive than pinning a guard for a single
+/// [`Map`], and it is best reused across many operations.
+///
+/// The number of shards is the main tuning knob, see [`with_shards`](Self::with_shards). More
+/// shards mean fewer keys per lock and so less contention, but every shard brings its own
+/// tables, lock and collector, so they also cost memory, and make `len`, `iter` and pinning a
+/// guard proportionally more expensive.
+///
+/// # Examples
+///
+/// ```
+/// use syncmap::sharded::ShardedMap;
+///
+/// let map = ShardedMap::new();
+/// let guard = map.guard();
+/// map.insert(1, "a", &guard);
+/// map.insert(2, "b", &guard);
+/// assert_eq!(map.get(&1, &guard), Some(&"a"));
+/// assert_eq!(map.remove(&2, &guard), Some(&"b"));
+/// assert_eq!(map.len(), 1);
+/// ```
+pub struct ShardedMap<K, V, S = crate::DefaultHashBuilder> {
+    shards: Vec<Map<K, V, S>>,
+    build_hasher: S,
+}
+
+/// A set of guards, one for each shard of a [`ShardedMap`].
+///
+/// See [`ShardedMap::guard`].
+pub struct ShardedGuard<'m> {
+    /// The address of the map the guard was pinned for, which can't move while it is borrowed.
+    map: usize,
+    guards: Vec<Guard<'m>>,
+}
+
+impl<K, V> ShardedMap<K, V, crate::DefaultHashBuilder> {
+    /// Creates an empty map with [`default_shards`] shards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::sharded::ShardedMap;
+    ///
+    /// let map: ShardedMap<&str, i32> = ShardedMap::new();
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn new() -> Self {
+        Self::with_hasher(crate::DefaultHashBuilder::default())
+    }
+
+    /// Creates an empty map with `shards` shards, rounded up to a power of two (and to at least
+    /// one), so that keys can be routed with a mask rather than a division.
+    ///
+    /// See [`ShardedMap`] for how to choose the number of shards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::sharded::ShardedMap;
+    ///
+    /// let map: ShardedMap<u64, u64> = ShardedMap::with_shards(12);
+    /// assert_eq!(map.shards(), 16);
+    /// ```
+    pub fn with_shards(shards: usize) -> Self {
+        Self::with_shards_and_hasher(shards, crate::DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for ShardedMap<K, V, crate::DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> ShardedMap<K, V, S>
+    where
+        S: Clone,
+{
+    /// Creates an empty map with [`default_shards`] shards, which will use `hash_builder` both
+    /// to route keys to shards and within every shard.
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_shards_and_hasher(default_shards(), hash_builder)
+    }
+
+    /// Creates an empty map with `shards` shards, rounded up to a power of two, which will use
+    /// `hash_builder` both to route keys to shards and within every shard.
+    pub fn with_shards_and_hasher(shards: usize, hash_builder: S) -> Self {
+        let shards = (0..shards.max(1).next_power_of_two())
+            .map(|_| Map::with_hasher(hash_builder.clone()))
+            .collect();
+        Self {
+            shards,
+            build_hasher: hash_builder,
+        }
+    }
+}
+
+impl<K, V, S> ShardedMap<K, V, S> {
+    /// Returns the number of shards. This is always a power of two.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Pins a guard for every shard of the map.
+    ///
+    /// As with [`Map::guard`], holding onto the returned guard prevents the collection of garbage
+    /// generated by the map, here in every shard.
+    pub fn guard(&self) -> ShardedGuard<'_> {
+        ShardedGuard {
+            map: self.id(),
+            guards: self.shards.iter().map(Map::guard).collect(),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Panics unless `guard` was pinned for this map.
+    fn check_guard(&self, guard: &ShardedGuard<'_>) {
+        assert_eq!(guard.map, self.id(), "guard was pinned for a different map");
+    }
+}
+
+impl<K, V, S> ShardedMap<K, V, S>
+    where
+        K: Clone + Hash + Ord,
+        S: BuildHasher,
+{
+    /// Returns the number of entries in the map, summed over all shards.
+    ///
+    /// Each shard's count is read separately, so under concurrent modification the sum does not
+    /// correspond to the map's size at any single point in time.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Map::len).sum()
+    }
+
+    /// Returns `true` if no shard contains any live entries.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(Map::is_empty)
+    }
+
+    fn shard_index<Q: ?Sized + Hash>(&self, key: &Q) -> usize {
+        // the shards' own tables hash keys with hashbrown's default hasher, independently of
+        // `S`, so the routing doesn't skew how a shard spreads its keys over its buckets
+        self.build_hasher.hash_one(key) as usize & (self.shards.len() - 1)
+    }
+
+    fn shard<'g, Q: ?Sized + Hash>(
+        &'g self,
+        key: &Q,
+        guard: &'g ShardedGuard<'_>,
+    ) -> (&'g Map<K, V, S>, &'g Guard<'g>) {
+        self.check_guard(guard);
+        let i = self.shard_index(key);
+        (&self.shards[i], &guard.guards[i])
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// See [`Map::get`].
+    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g ShardedGuard<'_>) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let (shard, guard) = self.shard(key, guard);
+        shard.get(key, guard)
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    ///
+    /// See [`Map::contains_key`].
+    pub fn contains_key<Q>(&self, key: &Q, guard: &ShardedGuard<'_>) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let (shard, guard) = self.shard(key, guard);
+        shard.contains_key(key, guard)
+    }
+
+    /// An iterator visiting all key-value pairs, one shard after the other.
+    ///
+    /// Each shard is iterated as with [`Map::iter`], which promotes its dirty map when the
+    /// iterator reaches it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syncmap::sharded::ShardedMap;
+    ///
+    /// let map = ShardedMap::new();
+    /// let guard = map.guard();
+    /// for i in 0..100 {
+    ///     map.insert(i, i, &guard);
+    /// }
+    /// assert_eq!(map.iter(&guard).map(|(_, v)| v).sum::<i32>(), 4950);
+    /// ```
+    pub fn iter<'g>(&'g self, guard: &'g ShardedGuard<'_>) -> impl Iterator<Item = (&'g K, &'g V)> {
+        self.check_guard(guard);
+        self.shards
+            .iter()
+            .zip(&guard.guards)
+            .flat_map(|(shard, guard)| shard.iter(guard))
+    }
+}
+
+impl<K, V, S> ShardedMap<K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Inserts a key-value pair into the map.
+    ///
+    /// See [`Map::insert`].
+    pub fn insert<'g>(&'g self, key: K, value: V, guard: &'g ShardedGuard<'_>) {
+        let (shard, guard) = self.shard(&key, guard);
+        shard.insert(key, value, guard)
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key was previously in
+    /// the map.
+    ///
+    /// See [`Map::remove`].
+    pub fn remove<'g, Q>(&'g self, key: &Q, guard: &'g ShardedGuard<'_>) -> Option<&'g V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        let (shard, guard) = self.shard(key, guard);
+        shard.remove(key, guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn keys_are_spread_over_all_shards() {
+        let map = ShardedMap::<u64, u64>::with_shards(16);
+        let guard = map.guard();
+        for i in 0..1024 {
+            map.insert(i, i, &guard);
+        }
+        assert_eq!(map.len(), 1024);
+        assert!(map.shards.iter().all(|shard| !shard.is_empty()));
+        for i in 0..1024 {
+            assert_eq!(map.get(&i, &guard), Some(&i));
+        }
+        let mut keys: Vec<_> = map.iter(&guard).map(|(k, _)| *k).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..1024).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn concurrent_inserts_and_removes() {
+        let map = Arc::new(ShardedMap::<u64, u64>::new());
+        let handles: Vec<_> = (0..4).map(|t| {
+            let map = map.clone();
+            thread::spawn(move || {
+                let guard = map.guard();
+                for i in 0..4096 {
+                    map.insert(t * 4096 + i, i, &guard);
+                }
+                for i in (0..4096).step_by(2) {
+                    assert_eq!(map.remove(&(t * 4096 + i), &guard), Some(&i));
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(map.len(), 4 * 2048);
+        let guard = map.guard();
+        assert!(!map.contains_key(&0, &guard));
+        assert!(map.contains_key(&1, &guard));
+    }
+
+    #[test]
+    fn shard_counts_are_powers_of_two() {
+        assert_eq!(ShardedMap::<u64, u64>::with_shards(0).shards(), 1);
+        assert_eq!(ShardedMap::<u64, u64>::with_shards(1).shards(), 1);
+        assert_eq!(ShardedMap::<u64, u64>::with_shards(17).shards(), 32);
+        assert!(ShardedMap::<u64, u64>::new().shards().is_power_of_two());
+
+        let map = ShardedMap::<u64, u64>::with_shards(1);
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        assert_eq!(map.len(), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "guard was pinned for a different map")]
+    fn guards_of_other_maps_are_rejected() {
+        let map = ShardedMap::<u64, u64>::new();
+        let other = ShardedMap::<u64, u64>::new();
+        let guard = other.guard();
+        map.insert(1, 1, &guard);
+    }
+}
@@ -0,0 +1,104 @@
+//! A guard-free view of a [`Map`] for single-threaded and casual use.
+//!
+//! Every method of [`Simple`] pins its own guard and drops it before returning, so values are
+//! handed out as clones rather than references. Pinning a guard per call is noticeably slower
+//! than pinning one and reusing it, so hot loops should still use [`Map`]'s guard-explicit
+//! methods, or a [`HashMapRef`](crate::HashMapRef) from [`Map::pin`].
+//!
+//! # Examples
+//!
+//! ```
+//! use syncmap::map::Map;
+//!
+//! let map = Map::new();
+//! let simple = map.simple();
+//! simple.insert("a", 1);
+//! assert_eq!(simple.get_cloned(&"a"), Some(1));
+//! assert_eq!(simple.remove_cloned(&"a"), Some(1));
+//! assert_eq!(simple.len(), 0);
+//! ```
+
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use crate::map::Map;
+
+/// A view of a [`Map`] whose methods pin their own guard. See the [module docs](self).
+pub struct Simple<'map, K, V, S = crate::DefaultHashBuilder> {
+    map: &'map Map<K, V, S>,
+}
+
+impl<K, V, S> Map<K, V, S> {
+    /// Returns a view of this map whose methods don't take a guard.
+    ///
+    /// See the [`simple`](crate::simple) module.
+    pub fn simple(&self) -> Simple<'_, K, V, S> {
+        Simple { map: self }
+    }
+}
+
+impl<K, V, S> Simple<'_, K, V, S>
+    where
+        K: Clone + Hash + Ord,
+        S: BuildHasher,
+{
+    /// Returns a clone of the value corresponding to the key.
+    pub fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            V: Clone,
+    {
+        self.map.get(key, &self.map.guard()).cloned()
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+    {
+        self.map.contains_key(key, &self.map.guard())
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the map contains no live entries.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl<K, V, S> Simple<'_, K, V, S>
+    where
+        K: Sync + Send + Clone + Hash + Ord,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Inserts a key-value pair into the map.
+    pub fn insert(&self, key: K, value: V) {
+        self.map.insert(key, value, &self.map.guard())
+    }
+
+    /// Removes a key from the map, returning a clone of its value if the key was in the map.
+    pub fn remove_cloned<Q>(&self, key: &Q) -> Option<V>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Ord,
+            V: Clone,
+    {
+        self.map.remove(key, &self.map.guard()).cloned()
+    }
+}
+
+impl<K, V, S> Simple<'_, K, V, S>
+    where
+        K: Clone + Ord,
+{
+    /// Clears the map, removing all key-value pairs.
+    pub fn clear(&self) {
+        self.map.clear()
+    }
+}
@@ -0,0 +1,34 @@
+//! Hooks for observing how often a map falls back to its lock.
+//!
+//! A hook is registered with [`MapBuilder::trace_hook`](crate::MapBuilder::trace_hook) and
+//! called with a [`TraceEvent`] whenever `get`, `put` (every insert) or `remove` enters or
+//! leaves its locked slow path, and whenever a miss promotes the dirty map. Hooks can feed
+//! `tracing` spans, counters or histograms without the crate depending on any of them.
+//!
+//! Hooks run on the thread performing the operation, mostly while the map's lock is held, so
+//! they should be quick and must not call back into the map.
+
+/// The operations whose locked slow paths are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// A lookup of a key that the read-only map couldn't answer.
+    Get,
+    /// An insert that couldn't be stored into an existing entry without the lock.
+    Put,
+    /// A removal of a key that is only in the dirty map.
+    Remove,
+}
+
+/// An event reported to a trace hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TraceEvent {
+    /// The operation acquired the map's lock.
+    LockAcquired(Operation),
+    /// The operation released the map's lock.
+    LockReleased(Operation),
+    /// Enough lookups missed the read-only map that the dirty map was promoted.
+    Promoted,
+}
+
+/// A callback receiving [`TraceEvent`]s.
+pub(crate) type TraceHook = Box<dyn Fn(TraceEvent) + Send + Sync>;
@@ -1,12 +1,10 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use std::collections::HashMap;
     use std::sync::Arc;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::thread;
     use std::time::Duration;
-    use rayon;
-    use rayon::prelude::*;
     use syncmap::map::Map;
 
 
@@ -82,7 +80,7 @@ mod tests {
         let t1 = std::thread::spawn(move || {
             let guard = map1.guard();
             for i in 0..ITERREMOVE {
-                if let Some(v) = map1.remove(&i, &guard) {
+                if map1.remove(&i, &guard).is_some() {
                     missr.fetch_add(1,Ordering::SeqCst);
                 }
             }
@@ -92,7 +90,7 @@ mod tests {
         let t2 = std::thread::spawn(move || {
             let guard = map2.guard();
             for i in 0..ITERREMOVE {
-                if let Some(v) = map2.remove(&i, &guard) {
+                if map2.remove(&i, &guard).is_some() {
                     missr2.fetch_add(1,Ordering::SeqCst);
                 }
             }
@@ -115,4 +113,107 @@ mod tests {
 
 
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn racing_removers_each_remove_a_key_at_most_once() {
+        use std::sync::Barrier;
+
+        // This is a stress test, not a model check: it only samples the interleavings the OS
+        // scheduler happens to produce, so it does not settle whether removal can double-count.
+        // The deterministic `shuttle` reproduction is still open; it needs the map's atomics and
+        // lock routed through shuttle's primitives first.
+        //
+        // half of the keys are promoted to the read-only map, where removal is a lock-free
+        // compare-and-swap, and half are only in the dirty map, where it happens under the lock
+        for round in 0..200u64 {
+            let map = Arc::new(Map::<u64, u64>::new());
+            let guard = map.guard();
+            for i in 0..64 {
+                map.insert(i, i, &guard);
+            }
+            map.iter(&guard).count();
+            for i in 64..128 {
+                map.insert(i, i, &guard);
+            }
+            drop(guard);
+
+            let barrier = Arc::new(Barrier::new(2));
+            let removers: Vec<_> = (0..2u64).map(|t| {
+                let map = map.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let guard = map.guard();
+                    barrier.wait();
+                    // walk the keys in opposite directions, starting at different offsets each
+                    // round, so that the removers meet at different keys
+                    (0..128)
+                        .map(|i| if t == 0 { (i + round) % 128 } else { (127 - i + round) % 128 })
+                        .filter(|k| map.remove(k, &guard).is_some())
+                        .collect::<Vec<_>>()
+                })
+            }).collect();
+            let mut removed: Vec<u64> = removers
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect();
+            removed.sort_unstable();
+            assert_eq!(removed, (0..128).collect::<Vec<_>>(), "round {}", round);
+            assert_eq!(map.len(), 0);
+        }
+    }
+
+    #[test]
+    fn borrowed_slice_and_str_lookups() {
+        let bytes = Map::<Vec<u8>, u32>::new();
+        let guard = bytes.guard();
+        bytes.insert(b"GET".to_vec(), 1, &guard);
+        bytes.insert(b"PUT".to_vec(), 2, &guard);
+        // "PUT" is still only in the dirty map, "GET" is found in either
+        let key: &[u8] = b"PUT";
+        assert_eq!(bytes.get(key, &guard), Some(&2));
+        assert!(bytes.contains_key(&b"GET"[..], &guard));
+        assert!(!bytes.contains_key(&b"HEAD"[..], &guard));
+        assert_eq!(bytes.get_key(key, &guard).map(Vec::as_slice), Some(key));
+        assert_eq!(bytes.replace(key, 3, &guard), Some(&2));
+        assert_eq!(bytes.get_or_default(&b"HEAD"[..], &guard), 0);
+        assert_eq!(bytes.remove(&b"GET"[..], &guard), Some(&1));
+        assert_eq!(bytes.get(&b"GET"[..], &guard), None);
+
+        let strings = Map::<String, u32>::new();
+        let guard = strings.guard();
+        strings.insert(String::from("a"), 1, &guard);
+        assert_eq!(strings.get("a", &guard), Some(&1));
+        assert!(strings.contains_key("a", &guard));
+        assert_eq!(strings.get_or_insert_with_cow("b", str::to_owned, || 2, &guard), &2);
+        assert_eq!(strings.remove("a", &guard), Some(&1));
+        assert!(!strings.contains_key("a", &guard));
+    }
+
+    #[test]
+    fn compares_against_std_hashmap() {
+        let map = Map::<String, u32>::new();
+        let guard = map.guard();
+        let mut expected = HashMap::new();
+        assert_eq!(map, expected);
+
+        for i in 0..20u32 {
+            map.insert(i.to_string(), i, &guard);
+            expected.insert(i.to_string(), i);
+        }
+        map.remove("3", &guard);
+        expected.remove("3");
+        assert_eq!(map, expected);
+        assert_eq!(expected, map);
+
+        // a differing value, a missing key and an extra key all compare unequal
+        expected.insert(String::from("4"), 40);
+        assert_ne!(map, expected);
+        expected.insert(String::from("4"), 4);
+        expected.remove("5");
+        assert_ne!(map, expected);
+        expected.insert(String::from("5"), 5);
+        expected.insert(String::from("extra"), 0);
+        assert_ne!(expected, map);
+    }
 }